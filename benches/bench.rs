@@ -4,19 +4,22 @@ use std::net::SocketAddr;
 use bytes::BytesMut;
 use tokio::net::UdpSocket;
 
-use stun_server::{config::CONFIG, error::Error, message::Message, server::server};
+use stun_server::{error::Error, message::Message, server::Server};
 
-async fn setup_server() {
+async fn setup_server() -> Server {
     dotenv::dotenv().ok();
     pretty_env_logger::init();
 
-    // start the server in a separate thread
-    tokio::spawn(async { server().await });
+    // start the server on an ephemeral port in the background
+    Server::builder()
+        .address("127.0.0.1:0".parse().unwrap())
+        .start()
+        .await
+        .unwrap()
 }
 
 async fn setup_client() -> (UdpSocket, BytesMut) {
-    let client_addr: SocketAddr = (*CONFIG).client.parse().unwrap();
-    let socket = UdpSocket::bind(client_addr)
+    let socket = UdpSocket::bind("127.0.0.1:0")
         .await
         .map_err(|e| Error::Startup(e.to_string()))
         .unwrap();
@@ -25,14 +28,13 @@ async fn setup_client() -> (UdpSocket, BytesMut) {
 
     // encode the binding request
     let mut bytes_mut = BytesMut::new();
-    message.encode(&mut bytes_mut);
+    message.encode(&mut bytes_mut).unwrap();
 
     (socket, bytes_mut)
 }
 
 // send the encoded binding request to the server
-async fn send_binding_request(socket: &UdpSocket, bytes: &BytesMut) {
-    let server_addr: SocketAddr = (*CONFIG).server.parse().unwrap();
+async fn send_binding_request(socket: &UdpSocket, bytes: &BytesMut, server_addr: SocketAddr) {
     socket
         .send_to(bytes.as_ref(), server_addr)
         .await
@@ -42,14 +44,13 @@ async fn send_binding_request(socket: &UdpSocket, bytes: &BytesMut) {
 
 fn bench(c: &mut Criterion) {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    rt.block_on(async move {
-        setup_server().await;
-    });
+    let server = rt.block_on(async move { setup_server().await });
+    let server_addr = server.local_addr();
     let (socket, bytes) = rt.block_on(async move { setup_client().await });
 
     c.bench_function("send-and-receive-binding-request-and-response", move |b| {
         b.to_async(&rt)
-            .iter(|| async { send_binding_request(&socket, &bytes) })
+            .iter(|| send_binding_request(&socket, &bytes, server_addr))
     });
 }
 
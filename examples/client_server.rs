@@ -3,15 +3,21 @@ mod client;
 
 use client::client;
 
-use stun_server::{error::Result, server::server};
+use stun_server::{config::Config, error::Result, server::Server};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
     pretty_env_logger::init();
 
-    // start the server in a separate thread
-    tokio::spawn(async { server().await });
+    let config = Config::from_dotenv()?;
 
-    client().await
+    // start the server in the background
+    let server = Server::builder().config(config.clone()).start().await?;
+
+    client(&config).await?;
+
+    // stop the server once the client is done
+    server.shutdown_handle().shutdown();
+    server.join().await
 }
@@ -1,4 +1,4 @@
-use stun_server::{error::Result, server::server};
+use stun_server::{config::Config, error::Result, server::server};
 
 #[allow(dead_code)]
 #[tokio::main]
@@ -6,5 +6,5 @@ async fn main() -> Result<()> {
     dotenv::dotenv().ok();
     pretty_env_logger::init();
 
-    server().await
+    server(Config::from_dotenv()?).await
 }
@@ -0,0 +1,144 @@
+//! A blocking STUN client for synchronous callers, built on
+//! `std::net::UdpSocket` so no async runtime is needed.  It runs one
+//! transaction at a time, dropping datagrams that don't match it.
+
+use crate::{
+    client::{binding_result, transaction_id, RetransmissionPolicy},
+    error::{Error, Result},
+    message::Message,
+    utils::MAX_DATAGRAM_SIZE,
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+#[derive(Debug)]
+pub struct StunClient {
+    socket: UdpSocket,
+    policy: RetransmissionPolicy,
+    buf: Vec<u8>,
+}
+
+impl StunClient {
+    pub fn bind(address: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(address).map_err(|e| Error::Startup(e.to_string()))?;
+
+        Ok(Self::new(socket, RetransmissionPolicy::default()))
+    }
+
+    pub fn new(socket: UdpSocket, policy: RetransmissionPolicy) -> Self {
+        Self {
+            socket,
+            policy,
+            buf: vec![0u8; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket
+            .local_addr()
+            .map_err(|e| Error::Startup(e.to_string()))
+    }
+
+    /// Send a Binding Request to `server` and return the mapped address.
+    pub fn binding(&mut self, server: SocketAddr) -> Result<SocketAddr> {
+        let request = Message::binding_request(vec![]);
+        let (mut bytes, _) = self.transaction(server, &request)?;
+
+        binding_result(&Message::decode(&mut bytes)?)
+    }
+
+    /// Send `request` to `destination`, retransmitting per the policy, and
+    /// return the raw response along with the address it came from.
+    pub fn transaction(
+        &mut self,
+        destination: SocketAddr,
+        request: &Message<'_>,
+    ) -> Result<(Bytes, SocketAddr)> {
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf)?;
+
+        for interval in self.policy.clone().intervals() {
+            self.socket
+                .send_to(buf.as_ref(), destination)
+                .map_err(|e| Error::BindingRequest(e.to_string()))?;
+
+            let deadline = Instant::now() + interval;
+
+            while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                if remaining.is_zero() {
+                    break;
+                }
+
+                self.socket
+                    .set_read_timeout(Some(remaining))
+                    .map_err(|e| Error::Receive(e.to_string()))?;
+
+                let (bytes_received, address) = match self.socket.recv_from(&mut self.buf) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        break
+                    }
+                    Err(e) => {
+                        log::debug!("error receiving: {}", e);
+                        continue;
+                    }
+                };
+                let datagram = &self.buf[..bytes_received];
+
+                if transaction_id(datagram) == Some(request.transaction_id) {
+                    return Ok((Bytes::copy_from_slice(datagram), address));
+                }
+
+                log::debug!("dropping {} bytes from {}", bytes_received, address);
+            }
+
+            log::debug!("no response from {} after {:?}", destination, interval);
+        }
+
+        Err(Error::Timeout(destination.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{policy, responder};
+
+    fn client() -> StunClient {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        StunClient::new(socket, policy())
+    }
+
+    #[test]
+    fn it_gets_the_mapped_address() {
+        let server = responder(0);
+        let mut client = client();
+
+        let mapped_address = client.binding(server).unwrap();
+
+        assert_eq!(mapped_address, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn it_retransmits_lost_requests() {
+        let server = responder(2);
+        let mut client = client();
+
+        let mapped_address = client.binding(server).unwrap();
+
+        assert_eq!(mapped_address, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn it_times_out_after_rc_requests() {
+        let server = responder(3);
+        let mut client = client();
+
+        assert!(matches!(client.binding(server), Err(Error::Timeout(_))));
+    }
+}
@@ -0,0 +1,203 @@
+//! STUN clients that share the `Message` codec and retransmission policy.
+//!
+//! Over UDP, a client SHOULD retransmit a STUN request message starting with
+//! an interval of RTO ("Retransmission TimeOut"), doubling after each
+//! retransmission.  Retransmissions continue until a response is received,
+//! or until a total of Rc requests have been sent.  If, after the last
+//! request, a duration equal to Rm times the RTO has passed without a
+//! response, the client SHOULD consider the transaction to have failed.
+//! The default values (RFC 8489 section 6.2.1) are an RTO of 500ms, an Rc of
+//! 7 and an Rm of 16, giving up after 39.5 seconds.
+//!
+//! Responses are matched to their request by transaction ID, so the source
+//! address of a response isn't checked (RFC 5780 servers may answer from an
+//! alternate address).
+//!
+//! `StunClient` runs on tokio (the `async` feature).  The `blocking` feature
+//! adds `blocking::StunClient`, built on `std::net::UdpSocket`, which doesn't
+//! need a runtime.
+
+use crate::{
+    error::{Error, Result},
+    message::{attribute::Attribute, class::Class, transaction_id::TransactionId, Message},
+};
+use std::{net::SocketAddr, time::Duration};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "async")]
+mod stun_client;
+
+#[cfg(feature = "async")]
+pub use stun_client::StunClient;
+
+/// RFC 8489 retransmission parameters for unreliable transports.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetransmissionPolicy {
+    /// The initial retransmission timeout, doubled after every send.
+    pub rto: Duration,
+    /// The total number of requests sent.
+    pub rc: u32,
+    /// After the last request, wait `rm` times the initial RTO.
+    pub rm: u32,
+}
+
+impl Default for RetransmissionPolicy {
+    fn default() -> Self {
+        Self {
+            rto: Duration::from_millis(500),
+            rc: 7,
+            rm: 16,
+        }
+    }
+}
+
+impl RetransmissionPolicy {
+    /// The default schedule, cut short so a transaction gives up after
+    /// `timeout` (to the nearest RTO): requests are sent while the doubling
+    /// RTO leaves time for a last wait, which takes the rest.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        let default = Self::default();
+        let rto = default.rto.min(timeout).max(Duration::from_millis(1));
+        let units = (timeout.as_millis() / rto.as_millis()).max(1) as u32;
+        let mut rc = 1;
+
+        // the first rc - 1 requests wait 2^(rc - 1) - 1 RTOs in all
+        while rc < default.rc && (1 << rc) - 1 < units {
+            rc += 1;
+        }
+
+        Self {
+            rto,
+            rc,
+            rm: units - ((1 << (rc - 1)) - 1),
+        }
+    }
+
+    /// How long to wait for a response after each of the `rc` sends.
+    pub fn intervals(&self) -> impl Iterator<Item = Duration> + '_ {
+        (0..self.rc).map(move |attempt| match attempt + 1 == self.rc {
+            true => self.rto * self.rm,
+            false => self.rto * 2u32.pow(attempt),
+        })
+    }
+}
+
+/// The mapped address of a Binding response, or its error.
+pub(crate) fn binding_result(response: &Message) -> Result<SocketAddr> {
+    match response.class {
+        Class::SuccessResponse => response.mapped_address().ok_or_else(|| {
+            Error::Decode("the response did not include XOR-MAPPED-ADDRESS".to_string())
+        }),
+        _ => Err(error_response(response)),
+    }
+}
+
+/// Describe the ERROR-CODE of an error response.
+pub(crate) fn error_response(response: &Message) -> Error {
+    let error = response
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::ErrorCode { code, reason } => Some(format!("{} {}", code, reason)),
+            _ => None,
+        });
+
+    Error::ErrorResponse(error.unwrap_or_else(|| format!("{:?}", response.class)))
+}
+
+/// Read the transaction ID out of a STUN header without decoding the message.
+pub(crate) fn transaction_id(datagram: &[u8]) -> Option<TransactionId> {
+    let mut transaction_id = [0u8; 12];
+    transaction_id.copy_from_slice(datagram.get(8..20)?);
+
+    Some(TransactionId(transaction_id))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::utils::Address;
+    use bytes::BytesMut;
+    use std::{net::UdpSocket, thread};
+
+    pub(crate) fn policy() -> RetransmissionPolicy {
+        RetransmissionPolicy {
+            rto: Duration::from_millis(20),
+            rc: 3,
+            rm: 2,
+        }
+    }
+
+    /// A runtime-free server that answers binding requests after dropping
+    /// the first `drop`.
+    pub(crate) fn responder(drop: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+
+            for received in 0.. {
+                let (size, client) = socket.recv_from(&mut buf).unwrap();
+
+                if received >= drop {
+                    let response = Message::binding_response(
+                        transaction_id(&buf[..size]).unwrap(),
+                        vec![Attribute::XorMappedAddress(Address::parse_address(client))],
+                    );
+                    let mut buf = BytesMut::new();
+                    response.encode(&mut buf).unwrap();
+                    socket.send_to(buf.as_ref(), client).unwrap();
+                }
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn it_doubles_the_rto_and_waits_rm_after_the_last_request() {
+        let intervals: Vec<u64> = RetransmissionPolicy::default()
+            .intervals()
+            .map(|interval| interval.as_millis() as u64)
+            .collect();
+
+        assert_eq!(intervals, vec![500, 1000, 2000, 4000, 8000, 16000, 8000]);
+        assert_eq!(intervals.iter().sum::<u64>(), 39500);
+    }
+
+    #[test]
+    fn it_fits_the_schedule_in_a_timeout() {
+        let total = |policy: RetransmissionPolicy| policy.intervals().sum::<Duration>();
+
+        assert_eq!(
+            RetransmissionPolicy::with_timeout(Duration::from_millis(39500)),
+            RetransmissionPolicy::default()
+        );
+
+        let policy = RetransmissionPolicy::with_timeout(Duration::from_secs(3));
+        assert_eq!((policy.rc, policy.rm), (3, 3));
+        assert_eq!(total(policy), Duration::from_secs(3));
+
+        let policy = RetransmissionPolicy::with_timeout(Duration::from_millis(100));
+        assert_eq!((policy.rc, policy.rm), (1, 1));
+        assert_eq!(total(policy), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn it_describes_an_error_response() {
+        let response = Message::binding_error_response(
+            TransactionId::new(),
+            vec![Attribute::ErrorCode {
+                code: 400,
+                reason: "Bad Request",
+            }],
+        );
+
+        assert!(matches!(
+            binding_result(&response),
+            Err(Error::ErrorResponse(error)) if error == "400 Bad Request"
+        ));
+    }
+}
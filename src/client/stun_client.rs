@@ -0,0 +1,301 @@
+//! An async STUN client that runs many transactions concurrently over one
+//! tokio socket.  A background task receives every datagram and hands it to
+//! the pending transaction with the same ID, and anything else to a sink.
+
+use crate::{
+    client::{binding_result, transaction_id, RetransmissionPolicy},
+    error::{Error, Result},
+    message::{transaction_id::TransactionId, Message},
+    protocol::demux::{Datagram, Kind, Sink},
+    utils::MAX_DATAGRAM_SIZE,
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    task::JoinHandle,
+    time::{self, timeout},
+};
+
+/// Pending transactions, waiting for a response with their ID.
+type Transactions = Arc<Mutex<HashMap<TransactionId, oneshot::Sender<(Bytes, SocketAddr)>>>>;
+
+#[derive(Debug)]
+pub struct StunClient {
+    socket: Arc<UdpSocket>,
+    transactions: Transactions,
+    policy: RetransmissionPolicy,
+    receiver: JoinHandle<()>,
+}
+
+impl StunClient {
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(address)
+            .await
+            .map_err(|e| Error::Startup(e.to_string()))?;
+
+        Ok(Self::new(socket, RetransmissionPolicy::default()))
+    }
+
+    /// Take ownership of `socket`.  Every datagram it receives is matched
+    /// against the pending transactions, anything else is dropped.
+    pub fn new(socket: UdpSocket, policy: RetransmissionPolicy) -> Self {
+        let drop = |datagram: Datagram| {
+            log::debug!(
+                "dropping {} bytes from {}",
+                datagram.contents.len(),
+                datagram.source
+            )
+        };
+
+        Self::with_sink(socket, policy, drop)
+    }
+
+    /// Like `new`, but datagrams that don't answer a pending transaction,
+    /// e.g. a peer's requests or media, are handed to `sink`.
+    pub fn with_sink(
+        socket: UdpSocket,
+        policy: RetransmissionPolicy,
+        sink: impl Sink + 'static,
+    ) -> Self {
+        let socket = Arc::new(socket);
+        let transactions = Transactions::default();
+        let receiver = tokio::spawn(receive(
+            socket.clone(),
+            transactions.clone(),
+            Box::new(sink),
+        ));
+
+        Self {
+            socket,
+            transactions,
+            policy,
+            receiver,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket
+            .local_addr()
+            .map_err(|e| Error::Startup(e.to_string()))
+    }
+
+    /// Send a Binding Request to `server` and return the mapped address.
+    pub async fn binding(&self, server: SocketAddr) -> Result<SocketAddr> {
+        let request = Message::binding_request(vec![]);
+        let (mut bytes, _) = self.transaction(server, &request).await?;
+
+        binding_result(&Message::decode(&mut bytes)?)
+    }
+
+    /// Send `request` to `destination`, retransmitting per the policy, and
+    /// return the raw response along with the address it came from.
+    pub async fn transaction(
+        &self,
+        destination: SocketAddr,
+        request: &Message<'_>,
+    ) -> Result<(Bytes, SocketAddr)> {
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf)?;
+
+        self.transaction_encoded(destination, request.transaction_id, buf.as_ref())
+            .await
+    }
+
+    /// Like `transaction`, for a request that's already encoded, e.g. to add
+    /// MESSAGE-INTEGRITY or FINGERPRINT.
+    pub async fn transaction_encoded(
+        &self,
+        destination: SocketAddr,
+        transaction_id: TransactionId,
+        request: &[u8],
+    ) -> Result<(Bytes, SocketAddr)> {
+        self.transaction_with_policy(destination, transaction_id, request, &self.policy)
+            .await
+    }
+
+    /// Like `transaction_encoded`, retransmitting per `policy` instead of the
+    /// client's, e.g. to send a request only once.
+    pub async fn transaction_with_policy(
+        &self,
+        destination: SocketAddr,
+        transaction_id: TransactionId,
+        request: &[u8],
+        policy: &RetransmissionPolicy,
+    ) -> Result<(Bytes, SocketAddr)> {
+        let (sender, mut response) = oneshot::channel();
+        self.transactions
+            .lock()
+            .unwrap()
+            .insert(transaction_id, sender);
+
+        let result = self
+            .retransmit(destination, request, policy, &mut response)
+            .await;
+        self.transactions.lock().unwrap().remove(&transaction_id);
+
+        result
+    }
+
+    /// Send a datagram outside of any transaction, e.g. a response.
+    pub async fn send_to(&self, contents: &[u8], destination: SocketAddr) -> Result<()> {
+        self.socket
+            .send_to(contents, destination)
+            .await
+            .map(|_| ())
+            .map_err(|e| Error::Send(e.to_string()))
+    }
+
+    async fn retransmit(
+        &self,
+        destination: SocketAddr,
+        request: &[u8],
+        policy: &RetransmissionPolicy,
+        response: &mut oneshot::Receiver<(Bytes, SocketAddr)>,
+    ) -> Result<(Bytes, SocketAddr)> {
+        for interval in policy.intervals() {
+            self.socket
+                .send_to(request, destination)
+                .await
+                .map_err(|e| Error::BindingRequest(e.to_string()))?;
+
+            if let Ok(received) = timeout(interval, &mut *response).await {
+                return received.map_err(|e| Error::Receive(e.to_string()));
+            }
+
+            log::debug!("no response from {} after {:?}", destination, interval);
+        }
+
+        Err(Error::Timeout(destination.to_string()))
+    }
+}
+
+impl Drop for StunClient {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, transactions: Transactions, mut sink: Box<dyn Sink>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (bytes_received, address) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                // e.g. ICMP port unreachable surfacing on some platforms
+                log::debug!("error receiving: {}", error);
+                time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+        };
+
+        let sender = match transaction_id(&buf[..bytes_received]) {
+            Some(transaction_id) => transactions.lock().unwrap().remove(&transaction_id),
+            None => None,
+        };
+
+        let bytes = Bytes::copy_from_slice(&buf[..bytes_received]);
+
+        match sender {
+            Some(sender) => {
+                let _ = sender.send((bytes, address));
+            }
+            None => sink.receive(Datagram {
+                kind: Kind::of(&bytes),
+                listener: 0,
+                source: address,
+                contents: bytes,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{policy, responder};
+    use crate::server::tests::{config, start};
+
+    async fn client() -> StunClient {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        StunClient::new(socket, policy())
+    }
+
+    #[tokio::test]
+    async fn it_gets_the_mapped_address() {
+        let addresses = start(config(None)).await;
+        let client = client().await;
+
+        let mapped_address = client.binding(addresses[0]).await.unwrap();
+
+        assert_eq!(mapped_address, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_runs_concurrent_transactions_on_one_socket() {
+        let addresses = start(config(None)).await;
+        let client = Arc::new(client().await);
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for _ in 0..20 {
+            let client = client.clone();
+            let server = addresses[0];
+            tasks.spawn(async move { client.binding(server).await });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            assert_eq!(result.unwrap().unwrap(), client.local_addr().unwrap());
+        }
+
+        assert!(client.transactions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_retransmits_lost_requests() {
+        let server = responder(2);
+        let client = client().await;
+
+        let mapped_address = client.binding(server).await.unwrap();
+
+        assert_eq!(mapped_address, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_times_out_after_rc_requests() {
+        let server = responder(3);
+        let client = client().await;
+
+        let result = client.binding(server).await;
+
+        assert!(matches!(result, Err(Error::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn it_sends_once_with_a_single_shot_policy() {
+        let server = responder(1);
+        let client = client().await;
+        let request = Message::binding_request(vec![]);
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf).unwrap();
+        let once = RetransmissionPolicy {
+            rto: Duration::from_millis(100),
+            rc: 1,
+            rm: 1,
+        };
+
+        let result = client
+            .transaction_with_policy(server, request.transaction_id, &buf, &once)
+            .await;
+
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert!(client.transactions.lock().unwrap().is_empty());
+    }
+}
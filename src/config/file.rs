@@ -0,0 +1,990 @@
+//! A structured configuration file describing any number of listeners.
+//!
+//! The format is picked from the extension: `.toml`, or `.yaml`/`.yml`.
+//!
+//! ```toml
+//! client = "0.0.0.0:0"
+//!
+//! [[listeners]]
+//! address = "10.0.0.5:3478"
+//! alternate = "10.0.0.6:3479"
+//! external_address = "203.0.113.7"
+//! alternate_external_address = "203.0.113.8"
+//!
+//! [listeners.tls]
+//! address = "10.0.0.5:443"
+//! certificate = "/etc/stun-server/cert.pem"
+//! private_key = "/etc/stun-server/key.pem"
+//!
+//! [[listeners]]
+//! address = "[::]:3478"
+//!
+//! [logging]
+//! level = "info"
+//! ```
+//!
+//! Unknown keys are rejected rather than ignored, so a typo can't silently
+//! fall back to a default.  That includes realm, auth, quota and metrics
+//! settings: the server has no long-term credentials, rate limits or metrics
+//! to configure yet, and accepting them would suggest it enforces them.
+//!
+//! The file is the lowest layer: `Overrides` from the environment and the
+//! command line are applied on top, and `validate` is run once everything is
+//! layered.
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+};
+use serde_derive::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The local address the client subcommands bind.
+    pub client: SocketAddr,
+    pub listeners: Vec<ListenerConfig>,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+
+    #[serde(default)]
+    pub transport: Transport,
+
+    /// A second `ip:port` for RFC 5780 NAT behavior discovery.
+    pub alternate: Option<SocketAddr>,
+
+    /// The public IP that maps 1:1 onto `address`.
+    pub external_address: Option<IpAddr>,
+
+    /// The public IP that maps 1:1 onto `alternate`.
+    pub alternate_external_address: Option<IpAddr>,
+
+    /// STUN over TLS-over-TCP alongside the UDP `address`.
+    pub tls: Option<TlsConfig>,
+
+    /// STUN over DTLS alongside the UDP `address`.
+    pub dtls: Option<TlsConfig>,
+}
+
+/// A TLS or DTLS listener.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub address: SocketAddr,
+    /// A PEM certificate chain.
+    pub certificate: PathBuf,
+    /// A PEM private key.
+    pub private_key: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Udp,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// A `RUST_LOG` style filter, e.g. `info` or `stun_server=debug`.
+    pub level: String,
+}
+
+/// Values that replace those from the file.  `client` always applies, the
+/// listener values need exactly one listener to apply to.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Overrides {
+    pub client: Option<SocketAddr>,
+    pub server: Option<SocketAddr>,
+    pub alternate_server: Option<SocketAddr>,
+    pub external_address: Option<IpAddr>,
+    pub alternate_external_address: Option<IpAddr>,
+    pub transport: Option<Transport>,
+    pub tls_server: Option<SocketAddr>,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_private_key: Option<PathBuf>,
+    pub dtls_server: Option<SocketAddr>,
+    pub dtls_certificate: Option<PathBuf>,
+    pub dtls_private_key: Option<PathBuf>,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            client: SocketAddr::from(([0, 0, 0, 0], 0)),
+            listeners: vec![],
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            transport: Transport::default(),
+            alternate: None,
+            external_address: None,
+            alternate_external_address: None,
+            tls: None,
+            dtls: None,
+        }
+    }
+}
+
+impl Overrides {
+    /// Read overrides from the same env vars as `Config::from_env`, leaving
+    /// unset vars as `None`.
+    pub fn from_env() -> Result<Self> {
+        Ok(envy::from_env::<Overrides>()?)
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = Error;
+
+    fn from_str(transport: &str) -> Result<Self> {
+        match transport {
+            "udp" => Ok(Transport::Udp),
+            _ => Err(Error::Config(format!(
+                "unknown transport {:?}, expected udp",
+                transport
+            ))),
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Read and parse the file at `path`, without validating it.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        let extension = path.extension().and_then(|extension| extension.to_str());
+
+        match extension {
+            Some("toml") => Self::from_toml(&contents),
+            Some("yaml" | "yml") => Self::from_yaml(&contents),
+            _ => Err(Error::Config(format!(
+                "{}: expected a .toml, .yaml or .yml extension",
+                path.display()
+            ))),
+        }
+        .map_err(|e| match e {
+            Error::Config(error) => Error::Config(format!("{}: {}", path.display(), error)),
+            error => error,
+        })
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        toml::from_str(contents).map_err(|e| {
+            let error = match e.span() {
+                Some(span) => {
+                    let line = contents[..span.start].matches('\n').count() + 1;
+                    format!("line {}: {}", line, e.message())
+                }
+                None => e.message().to_string(),
+            };
+
+            Error::Config(error)
+        })
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Self> {
+        serde_yaml::from_str(contents).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// Apply `overrides` on top of the file.  Listener overrides create the
+    /// listener when the file has none.
+    pub fn apply(&mut self, overrides: &Overrides) -> Result<()> {
+        if let Some(client) = overrides.client {
+            self.client = client;
+        }
+
+        let listener_overrides = overrides.server.is_some()
+            || overrides.alternate_server.is_some()
+            || overrides.external_address.is_some()
+            || overrides.alternate_external_address.is_some()
+            || overrides.transport.is_some()
+            || overrides.tls_server.is_some()
+            || overrides.tls_certificate.is_some()
+            || overrides.tls_private_key.is_some()
+            || overrides.dtls_server.is_some()
+            || overrides.dtls_certificate.is_some()
+            || overrides.dtls_private_key.is_some();
+
+        if !listener_overrides {
+            return Ok(());
+        }
+
+        if self.listeners.is_empty() {
+            let address = overrides.server.ok_or_else(|| {
+                Error::Config("server must be set when no listeners are configured".into())
+            })?;
+            self.listeners.push(ListenerConfig::new(address));
+        }
+
+        let listener = match self.listeners.as_mut_slice() {
+            [listener] => listener,
+            listeners => {
+                return Err(Error::Config(format!(
+                    "overrides apply to exactly one listener, but {} are configured",
+                    listeners.len()
+                )))
+            }
+        };
+
+        if let Some(server) = overrides.server {
+            listener.address = server;
+        }
+
+        if let Some(alternate_server) = overrides.alternate_server {
+            listener.alternate = Some(alternate_server);
+        }
+
+        if let Some(external_address) = overrides.external_address {
+            listener.external_address = Some(external_address);
+        }
+
+        if let Some(alternate_external_address) = overrides.alternate_external_address {
+            listener.alternate_external_address = Some(alternate_external_address);
+        }
+
+        if let Some(transport) = overrides.transport {
+            listener.transport = transport;
+        }
+
+        listener.tls = merge(
+            "tls",
+            listener.tls.take(),
+            overrides.tls_server,
+            &overrides.tls_certificate,
+            &overrides.tls_private_key,
+        )?;
+        listener.dtls = merge(
+            "dtls",
+            listener.dtls.take(),
+            overrides.dtls_server,
+            &overrides.dtls_certificate,
+            &overrides.dtls_private_key,
+        )?;
+
+        Ok(())
+    }
+
+    /// Check the layered configuration, naming the offending key in errors.
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(Error::Config("listeners: at least one is required".into()));
+        }
+
+        let mut bound: HashMap<SocketAddr, String> = HashMap::new();
+        let mut bound_tcp: HashMap<SocketAddr, String> = HashMap::new();
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let key = format!("listeners[{}]", index);
+            listener.validate(&key)?;
+
+            for (name, address) in listener.addresses() {
+                claim(&mut bound, format!("{}.{}", key, name), address)?;
+            }
+
+            if let Some(dtls) = &listener.dtls {
+                claim(&mut bound, format!("{}.dtls.address", key), dtls.address)?;
+            }
+
+            // TCP ports don't collide with UDP ones
+            if let Some(tls) = &listener.tls {
+                claim(&mut bound_tcp, format!("{}.tls.address", key), tls.address)?;
+            }
+        }
+
+        validate_log_level(&self.logging.level)
+    }
+
+    /// Describe each key that differs in `other`, as `key: old -> new`.
+    pub fn diff(&self, other: &ConfigFile) -> Vec<String> {
+        let mut changes = vec![];
+        change(&mut changes, "client", self.client, other.client);
+
+        for index in 0..self.listeners.len().max(other.listeners.len()) {
+            let key = format!("listeners[{}]", index);
+
+            match (self.listeners.get(index), other.listeners.get(index)) {
+                (Some(old), Some(new)) => old.diff(&key, new, &mut changes),
+                (old, new) => change(
+                    &mut changes,
+                    &key,
+                    option(old.map(|listener| listener.address)),
+                    option(new.map(|listener| listener.address)),
+                ),
+            }
+        }
+
+        change(
+            &mut changes,
+            "logging.level",
+            &self.logging.level,
+            &other.logging.level,
+        );
+
+        changes
+    }
+
+    /// The `Config` for each listener, to start a server with.
+    pub fn configs(&self) -> Vec<Config> {
+        self.listeners
+            .iter()
+            .map(|listener| Config {
+                client: self.client.to_string(),
+                server: listener.address.to_string(),
+                external_address: listener.external_address.map(|ip| ip.to_string()),
+                alternate_server: listener.alternate.map(|address| address.to_string()),
+                alternate_external_address: listener
+                    .alternate_external_address
+                    .map(|ip| ip.to_string()),
+                tls_server: listener.tls.as_ref().map(|tls| tls.address.to_string()),
+                tls_certificate: listener
+                    .tls
+                    .as_ref()
+                    .map(|tls| tls.certificate.display().to_string()),
+                tls_private_key: listener
+                    .tls
+                    .as_ref()
+                    .map(|tls| tls.private_key.display().to_string()),
+                dtls_server: listener.dtls.as_ref().map(|dtls| dtls.address.to_string()),
+                dtls_certificate: listener
+                    .dtls
+                    .as_ref()
+                    .map(|dtls| dtls.certificate.display().to_string()),
+                dtls_private_key: listener
+                    .dtls
+                    .as_ref()
+                    .map(|dtls| dtls.private_key.display().to_string()),
+            })
+            .collect()
+    }
+}
+
+impl ListenerConfig {
+    fn validate(&self, key: &str) -> Result<()> {
+        check_family(key, "external_address", self.address, self.external_address)?;
+
+        for (kind, tls) in [("tls", &self.tls), ("dtls", &self.dtls)] {
+            let tls = match tls {
+                Some(tls) => tls,
+                None => continue,
+            };
+
+            for (name, path) in [
+                ("certificate", &tls.certificate),
+                ("private_key", &tls.private_key),
+            ] {
+                if !path.is_file() {
+                    return Err(Error::Config(format!(
+                        "{}.{}.{}: {} is not a file",
+                        key,
+                        kind,
+                        name,
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        let alternate = match self.alternate {
+            Some(alternate) => alternate,
+            None if self.alternate_external_address.is_some() => {
+                return Err(Error::Config(format!(
+                    "{}.alternate_external_address: requires alternate to be set",
+                    key
+                )))
+            }
+            None => return Ok(()),
+        };
+
+        if alternate.ip() == self.address.ip() || alternate.port() == self.address.port() {
+            return Err(Error::Config(format!(
+                "{}.alternate: {} must differ from address {} in both IP and port",
+                key, alternate, self.address
+            )));
+        }
+
+        if alternate.is_ipv4() != self.address.is_ipv4() {
+            return Err(Error::Config(format!(
+                "{}.alternate: {} must be the same IP family as address {}",
+                key, alternate, self.address
+            )));
+        }
+
+        check_family(
+            key,
+            "alternate_external_address",
+            alternate,
+            self.alternate_external_address,
+        )
+    }
+
+    fn diff(&self, key_prefix: &str, other: &ListenerConfig, changes: &mut Vec<String>) {
+        let key = |name| format!("{}.{}", key_prefix, name);
+
+        change(changes, &key("address"), self.address, other.address);
+        change(changes, &key("transport"), self.transport, other.transport);
+        change(
+            changes,
+            &key("alternate"),
+            option(self.alternate),
+            option(other.alternate),
+        );
+        change(
+            changes,
+            &key("external_address"),
+            option(self.external_address),
+            option(other.external_address),
+        );
+        change(
+            changes,
+            &key("alternate_external_address"),
+            option(self.alternate_external_address),
+            option(other.alternate_external_address),
+        );
+
+        for (kind, old, new) in [
+            ("tls", &self.tls, &other.tls),
+            ("dtls", &self.dtls, &other.dtls),
+        ] {
+            let key = |name| format!("{}.{}.{}", key_prefix, kind, name);
+            let certificate = |tls: &TlsConfig| tls.certificate.display().to_string();
+            let private_key = |tls: &TlsConfig| tls.private_key.display().to_string();
+
+            change(
+                changes,
+                &key("address"),
+                option(old.as_ref().map(|tls| tls.address)),
+                option(new.as_ref().map(|tls| tls.address)),
+            );
+            change(
+                changes,
+                &key("certificate"),
+                option(old.as_ref().map(certificate)),
+                option(new.as_ref().map(certificate)),
+            );
+            change(
+                changes,
+                &key("private_key"),
+                option(old.as_ref().map(private_key)),
+                option(new.as_ref().map(private_key)),
+            );
+        }
+    }
+
+    /// Every address bound for this listener, named by the keys they come
+    /// from.
+    fn addresses(&self) -> Vec<(&'static str, SocketAddr)> {
+        match self.alternate {
+            Some(alternate) => vec![
+                ("address", self.address),
+                ("alternate", alternate),
+                (
+                    "address with the alternate port",
+                    SocketAddr::new(self.address.ip(), alternate.port()),
+                ),
+                (
+                    "alternate with the address port",
+                    SocketAddr::new(alternate.ip(), self.address.port()),
+                ),
+            ],
+            None => vec![("address", self.address)],
+        }
+    }
+}
+
+impl TryFrom<&Config> for ConfigFile {
+    type Error = Error;
+
+    /// Describe the single listener of an env or dotenv `Config`.
+    fn try_from(config: &Config) -> Result<Self> {
+        let parse_ip = |ip: &Option<String>| -> Result<Option<IpAddr>> {
+            ip.as_deref()
+                .map(|ip| {
+                    ip.parse()
+                        .map_err(|e| Error::Parse(format!("{}: {}", ip, e)))
+                })
+                .transpose()
+        };
+
+        Ok(Self {
+            client: config.client.parse()?,
+            listeners: vec![ListenerConfig {
+                address: config.server.parse()?,
+                transport: Transport::default(),
+                alternate: config
+                    .alternate_server
+                    .as_deref()
+                    .map(str::parse)
+                    .transpose()?,
+                external_address: parse_ip(&config.external_address)?,
+                alternate_external_address: parse_ip(&config.alternate_external_address)?,
+                tls: tls_config(
+                    "tls",
+                    &config.tls_server,
+                    &config.tls_certificate,
+                    &config.tls_private_key,
+                )?,
+                dtls: tls_config(
+                    "dtls",
+                    &config.dtls_server,
+                    &config.dtls_certificate,
+                    &config.dtls_private_key,
+                )?,
+            }],
+            logging: LoggingConfig::default(),
+        })
+    }
+}
+
+/// Apply the `kind` (tls or dtls) overrides to `current`.  Any of them can
+/// be overridden alone, as long as all three end up set.
+fn merge(
+    kind: &str,
+    current: Option<TlsConfig>,
+    address: Option<SocketAddr>,
+    certificate: &Option<PathBuf>,
+    private_key: &Option<PathBuf>,
+) -> Result<Option<TlsConfig>> {
+    if address.is_none() && certificate.is_none() && private_key.is_none() {
+        return Ok(current);
+    }
+
+    match (
+        address.or(current.as_ref().map(|tls| tls.address)),
+        certificate
+            .clone()
+            .or_else(|| current.as_ref().map(|tls| tls.certificate.clone())),
+        private_key
+            .clone()
+            .or_else(|| current.as_ref().map(|tls| tls.private_key.clone())),
+    ) {
+        (Some(address), Some(certificate), Some(private_key)) => Ok(Some(TlsConfig {
+            address,
+            certificate,
+            private_key,
+        })),
+        _ => Err(Error::Config(format!(
+            "{0}_server, {0}_certificate and {0}_private_key must be set together",
+            kind
+        ))),
+    }
+}
+
+/// The `kind` (tls or dtls) listener of an env or dotenv `Config`.
+fn tls_config(
+    kind: &str,
+    server: &Option<String>,
+    certificate: &Option<String>,
+    private_key: &Option<String>,
+) -> Result<Option<TlsConfig>> {
+    match (server, certificate, private_key) {
+        (Some(server), Some(certificate), Some(private_key)) => Ok(Some(TlsConfig {
+            address: server.parse()?,
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+        })),
+        (None, _, _) => Ok(None),
+        _ => Err(Error::Config(format!(
+            "{0}_server needs both {0}_certificate and {0}_private_key",
+            kind
+        ))),
+    }
+}
+
+/// Record that `name` binds `address`, unless something else already does.
+fn claim(bound: &mut HashMap<SocketAddr, String>, name: String, address: SocketAddr) -> Result<()> {
+    // port 0 is resolved by the OS, so it never collides
+    if address.port() == 0 {
+        return Ok(());
+    }
+
+    match bound.insert(address, name.clone()) {
+        Some(existing) => Err(Error::Config(format!(
+            "{}: {} is already used by {}",
+            name, address, existing
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn change<T: fmt::Display + PartialEq>(changes: &mut Vec<String>, key: &str, old: T, new: T) {
+    if old != new {
+        changes.push(format!("{}: {} -> {}", key, old, new));
+    }
+}
+
+fn option<T: fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "none".into(), |value| value.to_string())
+}
+
+fn check_family(
+    key: &str,
+    name: &str,
+    address: SocketAddr,
+    external: Option<IpAddr>,
+) -> Result<()> {
+    match external {
+        Some(external) if external.is_ipv4() != address.is_ipv4() => Err(Error::Config(format!(
+            "{}.{}: {} must be the same IP family as {}",
+            key, name, external, address
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Check each comma separated `module=level` directive of a log filter.
+fn validate_log_level(filters: &str) -> Result<()> {
+    for directive in filters.split(',').map(str::trim) {
+        let level = directive.rsplit('=').next().unwrap_or_default();
+
+        // a bare module name enables all levels for it
+        if !directive.contains('=') && log::LevelFilter::from_str(level).is_err() {
+            continue;
+        }
+
+        if log::LevelFilter::from_str(level).is_err() {
+            return Err(Error::Config(format!(
+                "logging.level: {:?} is not one of off, error, warn, info, debug or trace",
+                level
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        client = "0.0.0.0:8081"
+
+        [[listeners]]
+        address = "10.0.0.5:3478"
+        alternate = "10.0.0.6:3479"
+        external_address = "203.0.113.7"
+
+        [[listeners]]
+        address = "[::1]:3478"
+        transport = "udp"
+
+        [logging]
+        level = "stun_server=debug"
+    "#;
+
+    const YAML: &str = r#"
+        client: 0.0.0.0:8081
+        listeners:
+          - address: 10.0.0.5:3478
+            alternate: 10.0.0.6:3479
+            external_address: 203.0.113.7
+          - address: "[::1]:3478"
+            transport: udp
+        logging:
+          level: stun_server=debug
+    "#;
+
+    fn error<T: fmt::Debug>(result: Result<T>) -> String {
+        match result {
+            Err(Error::Config(error)) => error,
+            result => panic!("expected a config error, got {:?}", result),
+        }
+    }
+
+    fn listeners(toml: &str) -> ConfigFile {
+        ConfigFile::from_toml(toml).unwrap()
+    }
+
+    #[test]
+    fn it_parses_toml_and_yaml_alike() {
+        let toml = ConfigFile::from_toml(TOML).unwrap();
+        let yaml = ConfigFile::from_yaml(YAML).unwrap();
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml.listeners.len(), 2);
+        assert_eq!(toml.listeners[1].address, "[::1]:3478".parse().unwrap());
+        assert!(toml.validate().is_ok());
+    }
+
+    #[test]
+    fn it_maps_listeners_to_configs() {
+        let configs = ConfigFile::from_toml(TOML).unwrap().configs();
+
+        assert_eq!(configs[0].server, "10.0.0.5:3478");
+        assert_eq!(
+            configs[0].alternate_server.as_deref(),
+            Some("10.0.0.6:3479")
+        );
+        assert_eq!(configs[0].external_address.as_deref(), Some("203.0.113.7"));
+        assert_eq!(configs[1].client, "0.0.0.0:8081");
+    }
+
+    #[test]
+    fn it_rejects_unknown_keys() {
+        let error = ConfigFile::from_toml("[[listeners]]\naddress = \"0.0.0.0:3478\"\nport = 1");
+
+        assert!(
+            matches!(error, Err(Error::Config(error)) if error.starts_with("line 3: unknown field `port`"))
+        );
+    }
+
+    #[test]
+    fn it_rejects_settings_the_server_does_not_support() {
+        for (contents, field) in [
+            ("[auth]\nusers = {}", "auth"),
+            ("[quota]\nrequests_per_second = 10", "quota"),
+            ("[metrics]\naddress = \"0.0.0.0:9090\"", "metrics"),
+            (
+                "[[listeners]]\naddress = \"0.0.0.0:3478\"\nrealm = \"example.org\"",
+                "realm",
+            ),
+        ] {
+            let error = error(ConfigFile::from_toml(contents));
+
+            assert!(
+                error.contains(&format!("unknown field `{}`", field)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn it_requires_a_listener() {
+        assert_eq!(
+            error(ConfigFile::default().validate()),
+            "listeners: at least one is required"
+        );
+    }
+
+    #[test]
+    fn it_rejects_duplicate_addresses() {
+        let config = listeners(
+            r#"
+            [[listeners]]
+            address = "10.0.0.5:3478"
+            alternate = "10.0.0.6:3479"
+
+            [[listeners]]
+            address = "10.0.0.6:3478"
+            "#,
+        );
+
+        assert_eq!(
+            error(config.validate()),
+            "listeners[1].address: 10.0.0.6:3478 is already used by listeners[0].alternate with the address port"
+        );
+    }
+
+    #[test]
+    fn it_rejects_an_alternate_sharing_the_port() {
+        let config = listeners(
+            r#"
+            [[listeners]]
+            address = "10.0.0.5:3478"
+            alternate = "10.0.0.6:3478"
+            "#,
+        );
+
+        assert!(error(config.validate()).starts_with("listeners[0].alternate: 10.0.0.6:3478"));
+    }
+
+    #[test]
+    fn it_rejects_a_mismatched_external_family() {
+        let config = listeners(
+            r#"
+            [[listeners]]
+            address = "10.0.0.5:3478"
+            external_address = "2001:db8::1"
+            "#,
+        );
+
+        assert!(error(config.validate()).starts_with("listeners[0].external_address:"));
+    }
+
+    #[test]
+    fn it_checks_the_tls_files() {
+        let config = listeners(
+            r#"
+            [[listeners]]
+            address = "0.0.0.0:3478"
+
+            [listeners.tls]
+            address = "0.0.0.0:443"
+            certificate = "does-not-exist.pem"
+            private_key = "does-not-exist.pem"
+            "#,
+        );
+
+        assert_eq!(
+            error(config.validate()),
+            "listeners[0].tls.certificate: does-not-exist.pem is not a file"
+        );
+        assert_eq!(
+            config.configs()[0].tls_server.as_deref(),
+            Some("0.0.0.0:443")
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_dtls_address_used_by_udp() {
+        let config = listeners(
+            r#"
+            [[listeners]]
+            address = "0.0.0.0:3478"
+
+            [[listeners]]
+            address = "0.0.0.0:5349"
+
+            [listeners.dtls]
+            address = "0.0.0.0:3478"
+            certificate = "Cargo.toml"
+            private_key = "Cargo.toml"
+            "#,
+        );
+
+        assert_eq!(
+            error(config.validate()),
+            "listeners[1].dtls.address: 0.0.0.0:3478 is already used by listeners[0].address"
+        );
+        assert_eq!(
+            config.configs()[1].dtls_server.as_deref(),
+            Some("0.0.0.0:3478")
+        );
+    }
+
+    #[test]
+    fn it_needs_every_tls_override() {
+        let mut config = listeners("[[listeners]]\naddress = \"0.0.0.0:3478\"");
+        let overrides = Overrides {
+            tls_server: Some("0.0.0.0:443".parse().unwrap()),
+            ..Overrides::default()
+        };
+
+        assert!(error(config.apply(&overrides)).contains("must be set together"));
+    }
+
+    #[test]
+    fn it_rejects_an_invalid_log_level() {
+        let mut config = listeners("[[listeners]]\naddress = \"0.0.0.0:3478\"");
+        config.logging.level = "stun_server=loud".into();
+
+        assert!(error(config.validate()).starts_with("logging.level: \"loud\""));
+
+        config.logging.level = "warn,stun_server".into();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn it_applies_overrides_to_a_single_listener() {
+        let mut config = listeners("[[listeners]]\naddress = \"0.0.0.0:3478\"");
+        let overrides = Overrides {
+            server: Some("127.0.0.1:3480".parse().unwrap()),
+            ..Overrides::default()
+        };
+
+        config.apply(&overrides).unwrap();
+
+        assert_eq!(
+            config.listeners[0].address,
+            "127.0.0.1:3480".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_creates_a_listener_from_overrides() {
+        let mut config = ConfigFile::default();
+        let overrides = Overrides {
+            server: Some("127.0.0.1:3480".parse().unwrap()),
+            ..Overrides::default()
+        };
+
+        config.apply(&overrides).unwrap();
+
+        assert_eq!(
+            config.listeners,
+            vec![ListenerConfig::new(overrides.server.unwrap())]
+        );
+    }
+
+    #[test]
+    fn it_refuses_ambiguous_overrides() {
+        let mut config = ConfigFile::from_toml(TOML).unwrap();
+        let overrides = Overrides {
+            external_address: Some("203.0.113.9".parse().unwrap()),
+            ..Overrides::default()
+        };
+
+        assert_eq!(
+            error(config.apply(&overrides)),
+            "overrides apply to exactly one listener, but 2 are configured"
+        );
+    }
+
+    #[test]
+    fn it_describes_what_changed() {
+        let old = ConfigFile::from_toml(TOML).unwrap();
+        let mut new = old.clone();
+        new.listeners[0].external_address = Some("203.0.113.9".parse().unwrap());
+        new.listeners.pop();
+        new.logging.level = "debug".into();
+
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                "listeners[0].external_address: 203.0.113.7 -> 203.0.113.9",
+                "listeners[1]: [::1]:3478 -> none",
+                "logging.level: stun_server=debug -> debug",
+            ]
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn it_describes_an_env_config() {
+        let config = ConfigFile::try_from(&Config::default()).unwrap();
+
+        assert_eq!(
+            config.listeners,
+            vec![ListenerConfig::new("0.0.0.0:3478".parse().unwrap())]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Server and client configuration.
+//!
+//! A `Config` is an explicit value passed to the server and client.  It can
+//! be built in code, or loaded with the optional helpers below.
+//!
+//! The envy crate injects environment variables into a struct.
+//!
+//! dotenv allows environment variables to be augmented by a .env file.
+//! Variables already set in the environment take precedence over the file.
+//!
+//! Loading never panics, problems are returned as `Error::Config`.
+//!
+//! A `Config` describes a single listener.  The `file` module describes
+//! several in a TOML or YAML file, with each listener mapped to a `Config`.
+
+use crate::error::Result;
+use serde_derive::Deserialize;
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+pub mod file;
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub client: String,
+    pub server: String,
+
+    /// The public IP address that maps 1:1 onto the `server` listener's IP.
+    /// Set this when the server binds a private address behind a NAT (e.g.
+    /// on a cloud VM) so clients are told the address they can reach.  It
+    /// applies to the `server` listener, the one on the alternate port, and
+    /// the TLS and DTLS listeners.
+    pub external_address: Option<String>,
+
+    /// A second `ip:port` for RFC 5780 NAT behavior discovery. When set, the
+    /// server binds every combination of the two IPs and two ports and
+    /// honours CHANGE-REQUEST.
+    pub alternate_server: Option<String>,
+
+    /// The public IP address that maps 1:1 onto the `alternate_server` IP,
+    /// for the two listeners on that IP.
+    pub alternate_external_address: Option<String>,
+
+    /// An `ip:port` to serve STUN over TLS-over-TCP on, alongside `server`.
+    /// Needs the `tls` feature.
+    pub tls_server: Option<String>,
+
+    /// The path of the PEM certificate chain for `tls_server`.
+    pub tls_certificate: Option<String>,
+
+    /// The path of the PEM private key for `tls_server`.
+    pub tls_private_key: Option<String>,
+
+    /// An `ip:port` to serve STUN over DTLS on (usually port 5349),
+    /// alongside `server`.  Needs the `dtls` feature.
+    pub dtls_server: Option<String>,
+
+    /// The path of the PEM certificate chain for `dtls_server`.
+    pub dtls_certificate: Option<String>,
+
+    /// The path of the PEM (PKCS#8) private key for `dtls_server`.
+    pub dtls_private_key: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            client: "0.0.0.0:0".into(),
+            server: "0.0.0.0:3478".into(),
+            external_address: None,
+            alternate_server: None,
+            alternate_external_address: None,
+            tls_server: None,
+            tls_certificate: None,
+            tls_private_key: None,
+            dtls_server: None,
+            dtls_certificate: None,
+            dtls_private_key: None,
+        }
+    }
+}
+
+impl Config {
+    /// Use envy to inject env vars into the Config struct.
+    pub fn from_env() -> Result<Config> {
+        Ok(envy::from_env::<Config>()?)
+    }
+
+    /// Like `from_env`, augmented by a .env file in the current directory or
+    /// one of its parents, if there is one.
+    pub fn from_dotenv() -> Result<Config> {
+        match dotenv::dotenv() {
+            Err(dotenv::Error::Io(error)) if error.kind() == ErrorKind::NotFound => {}
+            result => {
+                result?;
+            }
+        }
+
+        Self::from_env()
+    }
+
+    /// Like `from_env`, augmented by the dotenv file at `path`.
+    pub fn from_dotenv_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        dotenv::from_path(path)?;
+
+        Self::from_env()
+    }
+
+    /// Rewrite the addresses of the locally bound sockets of a single
+    /// listener, or of the four RFC 5780 listeners in index order, into the
+    /// addresses advertised to clients.  The listeners on the `server` IP
+    /// (bit 1 of the index clear) are mapped onto `external_address`, and
+    /// those on the alternate IP onto `alternate_external_address`, whatever
+    /// IP they're actually bound on.  Only the IP is mapped, the port is kept
+    /// as 1:1 NAT preserves it.  Listeners without a mapping are advertised
+    /// as is.
+    pub fn advertised_addresses(&self, local_addrs: &[SocketAddr]) -> Result<Vec<SocketAddr>> {
+        let parse = |ip: &Option<String>| ip.as_deref().map(str::parse::<IpAddr>).transpose();
+        let external = [
+            parse(&self.external_address)?,
+            parse(&self.alternate_external_address)?,
+        ];
+
+        Ok(local_addrs
+            .iter()
+            .enumerate()
+            .map(|(index, local)| match external[(index >> 1) & 1] {
+                Some(ip) => SocketAddr::new(ip, local.port()),
+                None => *local,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn config(external_address: Option<&str>) -> Config {
+        Config {
+            client: "10.0.0.5:8081".into(),
+            server: "10.0.0.5:8082".into(),
+            external_address: external_address.map(Into::into),
+            alternate_server: Some("10.0.0.6:3479".into()),
+            alternate_external_address: Some("203.0.113.8".into()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn it_gets_the_config_from_a_dotenv_file() {
+        let path = std::env::temp_dir().join(format!("stun-server-{}.env", std::process::id()));
+        std::fs::write(&path, "client=0.0.0.0:8081\nserver=0.0.0.0:8082\n").unwrap();
+
+        let config = Config::from_dotenv_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.unwrap().server, "0.0.0.0:8082");
+    }
+
+    #[test]
+    fn it_errors_instead_of_panicking_on_a_missing_file() {
+        let config = Config::from_dotenv_file("does-not-exist.env");
+
+        assert!(matches!(config, Err(Error::Config(_))));
+    }
+
+    fn local_addrs() -> Vec<SocketAddr> {
+        [
+            "10.0.0.5:8082",
+            "10.0.0.5:3479",
+            "10.0.0.6:8082",
+            "10.0.0.6:3479",
+        ]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect()
+    }
+
+    fn addresses(addresses: &[&str]) -> Vec<SocketAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn it_advertises_each_listener_by_its_index() {
+        let expected = addresses(&[
+            "203.0.113.7:8082",
+            "203.0.113.7:3479",
+            "203.0.113.8:8082",
+            "203.0.113.8:3479",
+        ]);
+        let advertised = config(Some("203.0.113.7")).advertised_addresses(&local_addrs());
+
+        assert_eq!(advertised.unwrap(), expected);
+
+        // listeners 2 and 3 are on the alternate IP even when bound elsewhere
+        let unspecified = addresses(&[
+            "0.0.0.0:8082",
+            "0.0.0.0:3479",
+            "0.0.0.0:8082",
+            "0.0.0.0:3479",
+        ]);
+        let advertised = config(Some("203.0.113.7")).advertised_addresses(&unspecified);
+
+        assert_eq!(advertised.unwrap(), expected);
+    }
+
+    #[test]
+    fn it_advertises_the_local_address_without_a_mapping() {
+        let advertised = config(None).advertised_addresses(&local_addrs());
+
+        assert_eq!(
+            advertised.unwrap(),
+            addresses(&[
+                "10.0.0.5:8082",
+                "10.0.0.5:3479",
+                "203.0.113.8:8082",
+                "203.0.113.8:3479"
+            ])
+        );
+    }
+
+    #[test]
+    fn it_advertises_a_listener_bound_on_another_ip() {
+        // e.g. a socket handed to the builder, or `server` bound on 0.0.0.0
+        let local_addrs = addresses(&["0.0.0.0:8082"]);
+        let advertised = config(Some("203.0.113.7")).advertised_addresses(&local_addrs);
+
+        assert_eq!(advertised.unwrap(), addresses(&["203.0.113.7:8082"]));
+    }
+
+    #[test]
+    fn it_errors_on_an_invalid_external_address() {
+        let advertised = config(Some("not-an-ip")).advertised_addresses(&local_addrs());
+
+        assert!(advertised.is_err());
+    }
+}
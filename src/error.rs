@@ -4,6 +4,7 @@
 //!
 //! Define a reusable Result type.
 
+use dotenv::Error as DotenvError;
 use envy::Error as EnvyError;
 use log::error;
 use std::net::AddrParseError;
@@ -21,20 +22,38 @@ pub enum Error {
     #[error("Error sending the binding response: {0}")]
     BindingResponse(String),
 
-    #[error("{0}.  Make sure you copied .env.example to .env")]
+    #[error("Invalid configuration: {0}.")]
     Config(String),
 
+    #[error("Received an error response: {0}.")]
+    ErrorResponse(String),
+
+    #[error("ICE failed: {0}.")]
+    Ice(String),
+
+    #[error("Error detecting NAT behavior: {0}.")]
+    NatDetection(String),
+
     #[error("Error decoding: {0}.")]
     Decode(String),
 
+    #[error("Error encoding: {0}.")]
+    Encode(String),
+
     #[error("Parse error: {0}.")]
     Parse(String),
 
     #[error("Error receiving bytes: {0}.")]
     Receive(String),
 
+    #[error("Error sending a datagram: {0}.")]
+    Send(String),
+
     #[error("Error starting the server: {0}.")]
     Startup(String),
+
+    #[error("Timed out waiting for a response from {0}.")]
+    Timeout(String),
 }
 
 // Log out errors
@@ -46,13 +65,22 @@ fn log_error(error: Error) -> Error {
 impl From<EnvyError> for Error {
     fn from(error: EnvyError) -> Self {
         let error = match error {
-            EnvyError::MissingValue(error) => format!("Missing config value in .env: {}", error),
+            EnvyError::MissingValue(error) => format!(
+                "Missing config value {}.  Set it in the environment, or copy .env.example to .env",
+                error
+            ),
             EnvyError::Custom(error) => error,
         };
         log_error(Error::Config(error))
     }
 }
 
+impl From<DotenvError> for Error {
+    fn from(error: DotenvError) -> Self {
+        log_error(Error::Config(error.to_string()))
+    }
+}
+
 impl From<AddrParseError> for Error {
     fn from(error: AddrParseError) -> Self {
         log_error(Error::Parse(error.to_string()))
@@ -0,0 +1,1052 @@
+//! A full ICE agent (RFC 8445) on tokio.
+//!
+//! `Agent::gather` binds a socket on each host address and asks the STUN
+//! servers for server reflexive candidates, in parallel.  Without host
+//! addresses, it gathers on the local interfaces, see `gather`, and
+//! `Agent::trickle` streams the candidates as they're gathered.  Relayed
+//! candidates need a TURN client, which this crate doesn't have, so they're
+//! only ever remote, but TURN servers answer Binding requests as well.  Once
+//! the candidates and credentials were exchanged with the peer, `connect`
+//! checks the candidate pairs, one every Ta, until a pair is selected:
+//!
+//! * Checks are Binding requests with short-term credentials, sent as
+//!   `StunClient` transactions: USERNAME `remote_ufrag:local_ufrag`,
+//!   MESSAGE-INTEGRITY keyed with the remote password, PRIORITY,
+//!   ICE-CONTROLLING or ICE-CONTROLLED with the tiebreaker, and FINGERPRINT.
+//! * The peer's checks are answered in the background, and trigger a check
+//!   of their pair.  A source that isn't a known remote candidate becomes a
+//!   peer reflexive one, and so does a mapped address that isn't a local
+//!   candidate.
+//! * Role conflicts are settled by the tiebreakers, answering with 487 (Role
+//!   Conflict) or switching roles (RFC 8445 section 7.3.1.1), and a 487
+//!   response switches roles (section 7.2.5.1).
+//! * The controlling agent nominates the first pair that succeeds by checking
+//!   it again with USE-CANDIDATE, and the controlled agent selects the pair
+//!   its peer nominated once its own check of it succeeded.
+//!
+//! Once a pair is selected, `keep_consent` keeps consent to send on it
+//! (RFC 7675), see `consent`.
+
+use crate::{
+    client::{self, RetransmissionPolicy, StunClient},
+    error::{Error, Result},
+    ice::candidate::{Candidate, CandidateType},
+    ice::check_list::{CheckList, State as PairState},
+    ice::consent::{Consent, ConsentConfig},
+    ice::gather::{Gathering, InterfacePolicy},
+    message::{
+        attribute::Attribute, class::Class, fingerprint, integrity, transaction_id::TransactionId,
+        Message,
+    },
+    protocol::demux::{Datagram, Kind},
+    protocol::handler::{self, Credentials},
+};
+use bytes::{Bytes, BytesMut};
+use rand::{distributions::Alphanumeric, Rng};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+    time,
+};
+
+/// Ta, the pacing of connectivity checks (RFC 8445 section 14.2).
+const PACING: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    /// The local addresses to gather host candidates on, each bound to an
+    /// ephemeral port.  The first is preferred.  When empty, the addresses of
+    /// the local interfaces that `interfaces` allows.
+    pub host_addresses: Vec<IpAddr>,
+    pub interfaces: InterfacePolicy,
+    /// The STUN (or TURN) servers to gather server reflexive candidates from.
+    pub stun_servers: Vec<SocketAddr>,
+    /// Whether the agent starts in the controlling role, i.e. it's the
+    /// offerer (RFC 8445 section 6.1.1).
+    pub controlling: bool,
+    /// Ta, the time between two checks.
+    pub pacing: Duration,
+    /// Retransmissions of gathering requests and checks.
+    pub policy: RetransmissionPolicy,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            host_addresses: vec![],
+            interfaces: InterfacePolicy::default(),
+            stun_servers: vec![],
+            controlling: false,
+            pacing: PACING,
+            policy: RetransmissionPolicy::default(),
+        }
+    }
+}
+
+/// The ufrag and password of one agent, exchanged with the candidates.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IceCredentials {
+    pub ufrag: String,
+    pub password: String,
+}
+
+impl IceCredentials {
+    /// The ufrag has at least 24 bits of randomness and the password at
+    /// least 128 (RFC 8445 section 5.3).
+    pub fn random() -> Self {
+        let random = |length| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(length)
+                .map(char::from)
+                .collect()
+        };
+
+        Self {
+            ufrag: random(8),
+            password: random(24),
+        }
+    }
+}
+
+/// The pair an agent sends and receives on once ICE completed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SelectedPair {
+    pub local: Candidate,
+    pub remote: Candidate,
+}
+
+/// A connectivity check sent on a pair.
+#[derive(Debug)]
+struct Check {
+    pair: usize,
+    /// The socket it's sent from.
+    local: usize,
+    destination: SocketAddr,
+    transaction_id: TransactionId,
+    request: Bytes,
+    /// The role it was sent with.
+    controlling: bool,
+    use_candidate: bool,
+}
+
+/// The state shared by `connect` and the responder.
+#[derive(Debug)]
+struct Shared {
+    local: IceCredentials,
+    remote: Option<IceCredentials>,
+    tiebreaker: u64,
+    candidates: Vec<Candidate>,
+    check_list: CheckList,
+    selected: Option<usize>,
+    /// When `send` last sent on the selected pair.
+    sent: Option<Instant>,
+}
+
+/// Requests must be for the local ufrag, from the remote one once it's
+/// known, and are signed with the local password.
+impl Credentials for Shared {
+    fn password(&self, username: &str) -> Option<String> {
+        let (local, remote) = username.split_once(':')?;
+        let known = self
+            .remote
+            .as_ref()
+            .is_none_or(|other| other.ufrag == remote);
+
+        (local == self.local.ufrag && known).then(|| self.local.password.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct Agent {
+    /// The socket of each host candidate.
+    sockets: Vec<Arc<StunClient>>,
+    shared: Arc<Mutex<Shared>>,
+    pacing: Duration,
+    responder: JoinHandle<()>,
+    gatherer: JoinHandle<()>,
+    datagrams: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl Agent {
+    /// Bind the host candidates, and gather the server reflexive ones.  A
+    /// STUN server that doesn't answer only costs its candidate.
+    pub async fn gather(config: AgentConfig) -> Result<Self> {
+        let (agent, mut gathering) = Self::trickle(config).await?;
+
+        while gathering.next().await.is_some() {}
+
+        log::info!("gathered {:?}", agent.local_candidates());
+
+        Ok(agent)
+    }
+
+    /// Bind the host candidates, and gather the server reflexive ones in the
+    /// background, streaming each candidate as it's gathered.  Checks can
+    /// start before gathering completes.
+    pub async fn trickle(config: AgentConfig) -> Result<(Self, Gathering)> {
+        // enumerated addresses that can't be bound are skipped, given ones
+        // are errors
+        let (host_addresses, enumerated) = match config.host_addresses.is_empty() {
+            true => (config.interfaces.host_addresses()?, true),
+            false => (config.host_addresses.clone(), false),
+        };
+
+        let (incoming, received) = mpsc::unbounded_channel();
+        let (gathered, candidates) = mpsc::unbounded_channel();
+        let mut sockets = vec![];
+        let mut hosts = vec![];
+        let mut check_list = CheckList::new(config.controlling);
+
+        for ip in host_addresses {
+            let bound = async {
+                let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+                let address = socket.local_addr()?;
+
+                Ok::<_, std::io::Error>((socket, address))
+            };
+            let (socket, address) = match bound.await {
+                Ok(bound) => bound,
+                Err(e) if enumerated => {
+                    log::warn!("not gathering on {}: {}", ip, e);
+                    continue;
+                }
+                Err(e) => return Err(Error::Startup(e.to_string())),
+            };
+            let index = sockets.len();
+            let incoming = incoming.clone();
+            let sink = move |mut datagram: Datagram| {
+                datagram.listener = index;
+                let _ = incoming.send(datagram);
+            };
+
+            let host = Candidate::host(address, u16::MAX - index as u16);
+            sockets.push(Arc::new(StunClient::with_sink(
+                socket,
+                config.policy.clone(),
+                sink,
+            )));
+            check_list.add_local(host.clone());
+            let _ = gathered.send(host.clone());
+            hosts.push(host);
+        }
+
+        if sockets.is_empty() {
+            return Err(Error::Arguments(
+                "no local interface address to gather on".into(),
+            ));
+        }
+
+        let shared = Arc::new(Mutex::new(Shared {
+            local: IceCredentials::random(),
+            remote: None,
+            tiebreaker: rand::random(),
+            candidates: hosts.clone(),
+            check_list,
+            selected: None,
+            sent: None,
+        }));
+        let gatherer = tokio::spawn(gather_server_reflexive(
+            sockets.clone(),
+            hosts,
+            config.stun_servers,
+            shared.clone(),
+            gathered,
+        ));
+        let (forward, datagrams) = mpsc::unbounded_channel();
+        let responder = tokio::spawn(respond(received, sockets.clone(), shared.clone(), forward));
+        let agent = Self {
+            sockets,
+            shared,
+            pacing: config.pacing,
+            responder,
+            gatherer,
+            datagrams: tokio::sync::Mutex::new(datagrams),
+        };
+
+        Ok((agent, Gathering { candidates }))
+    }
+
+    pub fn local_credentials(&self) -> IceCredentials {
+        self.shared.lock().unwrap().local.clone()
+    }
+
+    /// The host and server reflexive candidates, and the peer reflexive ones
+    /// checks discovered.
+    pub fn local_candidates(&self) -> Vec<Candidate> {
+        self.shared.lock().unwrap().candidates.clone()
+    }
+
+    /// Check the pairs until one is selected, or every one failed.  Remote
+    /// candidates are also learned from the peer's checks, so there may be
+    /// none up front, but then nothing fails and the caller should set a
+    /// timeout.
+    pub async fn connect(
+        &self,
+        remote: IceCredentials,
+        remote_candidates: Vec<Candidate>,
+    ) -> Result<SelectedPair> {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.remote = Some(remote);
+
+            for candidate in remote_candidates {
+                shared.check_list.add_remote(candidate);
+            }
+        }
+
+        let (results, mut responses) = mpsc::unbounded_channel();
+        let mut in_flight = 0;
+        let mut pacing = time::interval(self.pacing);
+
+        loop {
+            tokio::select! {
+                _ = pacing.tick() => {
+                    let check = {
+                        let mut shared = self.shared.lock().unwrap();
+
+                        if let Some(selected) = shared.selected() {
+                            return Ok(selected);
+                        }
+
+                        if in_flight == 0 && shared.check_list.is_failed() {
+                            return Err(Error::Ice("every candidate pair failed".into()));
+                        }
+
+                        shared.check()
+                    };
+
+                    if let Some(check) = check {
+                        in_flight += 1;
+                        self.spawn(check, None, results.clone());
+                    }
+                }
+                Some((check, response)) = responses.recv() => {
+                    in_flight -= 1;
+                    self.shared.lock().unwrap().checked(check, response);
+                }
+            }
+        }
+    }
+
+    /// Check consent on the selected pair, and send keepalives when nothing
+    /// else was sent.  Runs until consent expires, when the pair is dropped
+    /// so nothing more can be sent, and errors.
+    pub async fn keep_consent(&self, config: ConsentConfig) -> Result<()> {
+        let mut consent = Consent::new(config, Instant::now());
+        let (results, mut responses) = mpsc::unbounded_channel();
+
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(consent.poll_timeout().into()) => {
+                    let now = Instant::now();
+                    let (check, keepalive) = {
+                        let mut shared = self.shared.lock().unwrap();
+
+                        if let Some(sent) = shared.sent {
+                            consent.sent(sent);
+                        }
+
+                        if consent.is_expired(now) {
+                            shared.selected = None;
+                            return Err(Error::Ice("consent expired".into()));
+                        }
+
+                        let index = shared
+                            .selected
+                            .ok_or_else(|| Error::Ice("no pair was selected".into()))?;
+                        // sent once, and answered until the next one is due
+                        // (RFC 7675 section 5.1)
+                        let check = match consent.poll_check(now) {
+                            true => shared.request(index, false).map(|check| {
+                                let policy = RetransmissionPolicy {
+                                    rto: consent.next_check() - now,
+                                    rc: 1,
+                                    rm: 1,
+                                };
+
+                                (check, policy)
+                            }),
+                            false => None,
+                        };
+                        let pair = shared.check_list.pair(index);
+                        let keepalive = consent.poll_keepalive(now);
+
+                        (check, keepalive.then_some((pair.local, pair.remote.address)))
+                    };
+
+                    if let Some((check, policy)) = check {
+                        self.spawn(check, Some(policy), results.clone());
+                    }
+
+                    if let Some((local, destination)) = keepalive {
+                        let mut buf = BytesMut::new();
+                        Message::binding_indication(vec![]).encode(&mut buf)?;
+                        fingerprint::append(&mut buf);
+
+                        if let Err(error) = self.sockets[local].send_to(&buf, destination).await {
+                            log::warn!("error sending a keepalive to {}: {}", destination, error);
+                        }
+                    }
+                }
+                Some((check, response)) = responses.recv() => {
+                    if self.shared.lock().unwrap().consented(&check, response) {
+                        consent.responded(Instant::now());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a datagram, e.g. media, on the selected pair.
+    pub async fn send(&self, contents: &[u8]) -> Result<()> {
+        let (local, destination) = {
+            let mut shared = self.shared.lock().unwrap();
+            let index = shared
+                .selected
+                .ok_or_else(|| Error::Ice("no pair was selected".into()))?;
+            shared.sent = Some(Instant::now());
+            let pair = shared.check_list.pair(index);
+
+            (pair.local, pair.remote.address)
+        };
+
+        self.sockets[local].send_to(contents, destination).await
+    }
+
+    /// The next datagram that isn't STUN, e.g. media, received on any host
+    /// candidate.  Its `listener` is the index of the host candidate.
+    pub async fn recv(&self) -> Option<Datagram> {
+        self.datagrams.lock().await.recv().await
+    }
+}
+
+impl Agent {
+    /// Send a check as a transaction, and hand its response to `results`.
+    /// It's retransmitted per `policy`, or else the agent's policy.
+    fn spawn(
+        &self,
+        check: Check,
+        policy: Option<RetransmissionPolicy>,
+        results: mpsc::UnboundedSender<(Check, Result<(Bytes, SocketAddr)>)>,
+    ) {
+        let socket = self.sockets[check.local].clone();
+
+        tokio::spawn(async move {
+            let (destination, id, request) =
+                (check.destination, check.transaction_id, &check.request);
+            let response = match &policy {
+                Some(policy) => {
+                    socket
+                        .transaction_with_policy(destination, id, request, policy)
+                        .await
+                }
+                None => socket.transaction_encoded(destination, id, request).await,
+            };
+            let _ = results.send((check, response));
+        });
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        self.responder.abort();
+        self.gatherer.abort();
+    }
+}
+
+impl Shared {
+    fn selected(&self) -> Option<SelectedPair> {
+        let pair = self.check_list.pair(self.selected?);
+
+        Some(SelectedPair {
+            local: pair.valid.clone()?,
+            remote: pair.remote.clone(),
+        })
+    }
+
+    fn select(&mut self, index: usize) {
+        if self.selected.is_none() {
+            log::info!("selected {:?}", self.check_list.pair(index));
+            self.selected = Some(index);
+        }
+    }
+
+    /// The next check to send, if the remote credentials are known.
+    fn check(&mut self) -> Option<Check> {
+        self.remote.as_ref()?;
+        let index = self.check_list.next()?;
+        let pair = self.check_list.pair(index);
+        let use_candidate = self.check_list.controlling() && pair.nominated;
+
+        self.request(index, use_candidate)
+    }
+
+    /// A check of a pair, if the remote credentials are known.
+    fn request(&self, index: usize, use_candidate: bool) -> Option<Check> {
+        let remote = self.remote.as_ref()?;
+        let pair = self.check_list.pair(index);
+        let local = self.check_list.local(pair.local);
+        let controlling = self.check_list.controlling();
+        let username = format!("{}:{}", remote.ufrag, self.local.ufrag);
+        let priority = Candidate::priority(CandidateType::PeerReflexive, local.local_preference());
+
+        let mut attributes = vec![
+            Attribute::Username(&username),
+            Attribute::Priority(priority),
+            match controlling {
+                true => Attribute::IceControlling(self.tiebreaker),
+                false => Attribute::IceControlled(self.tiebreaker),
+            },
+        ];
+
+        if use_candidate {
+            attributes.push(Attribute::UseCandidate);
+        }
+
+        let request = Message::binding_request(attributes);
+        let mut buf = BytesMut::new();
+
+        // e.g. a remote ufrag too long for USERNAME
+        if let Err(error) = request.encode(&mut buf) {
+            log::warn!("not checking pair {}: {}", index, error);
+            return None;
+        }
+
+        integrity::sign(&mut buf, remote.password.as_bytes());
+        fingerprint::append(&mut buf);
+
+        Some(Check {
+            pair: index,
+            local: pair.local,
+            destination: pair.remote.address,
+            transaction_id: request.transaction_id,
+            request: buf.freeze(),
+            controlling,
+            use_candidate,
+        })
+    }
+
+    /// Process the response to a check (RFC 8445 section 7.2.5).
+    fn checked(&mut self, check: Check, response: Result<(Bytes, SocketAddr)>) {
+        let mut bytes = match self.authenticated(&check, response) {
+            Ok(bytes) => bytes,
+            Err(error) => return self.failed(&check, error),
+        };
+
+        let response = match Message::decode(&mut bytes) {
+            Ok(response) => response,
+            Err(error) => return self.failed(&check, error),
+        };
+
+        if response.class != Class::SuccessResponse {
+            if error_code(&response) != Some(487) {
+                return self.failed(&check, client::error_response(&response));
+            }
+
+            // switch to the role opposite the one the check was sent with,
+            // and check again
+            self.check_list.set_controlling(!check.controlling);
+            self.check_list.pair_mut(check.pair).state = PairState::Waiting;
+            self.check_list.trigger(check.pair);
+
+            return;
+        }
+
+        let mapped = match response.mapped_address() {
+            Some(mapped) => mapped,
+            None => return self.failed(&check, client::error_response(&response)),
+        };
+
+        let base = self.check_list.local(check.local).clone();
+        let valid = self
+            .candidates
+            .iter()
+            .find(|candidate| candidate.address == mapped && candidate.base == base.address)
+            .cloned()
+            .unwrap_or_else(|| {
+                let priority =
+                    Candidate::priority(CandidateType::PeerReflexive, base.local_preference());
+                let candidate = Candidate::peer_reflexive(mapped, base.address, priority);
+                self.candidates.push(candidate.clone());
+                candidate
+            });
+
+        self.check_list.succeeded(check.pair, valid);
+
+        let controlling = self.check_list.controlling();
+
+        if check.use_candidate || (!controlling && self.check_list.pair(check.pair).nominated) {
+            self.select(check.pair);
+        } else if controlling && !self.check_list.is_nominating() {
+            self.check_list.nominate(check.pair);
+        }
+    }
+
+    /// Whether a consent check was answered with success.
+    fn consented(&self, check: &Check, response: Result<(Bytes, SocketAddr)>) -> bool {
+        let result = self.authenticated(check, response).and_then(|mut bytes| {
+            let response = Message::decode(&mut bytes)?;
+
+            match response.class {
+                Class::SuccessResponse => Ok(()),
+                _ => Err(client::error_response(&response)),
+            }
+        });
+
+        if let Err(error) = &result {
+            log::debug!("consent check to {} failed: {}", check.destination, error);
+        }
+
+        result.is_ok()
+    }
+
+    /// The response to a check, if it came from the address the check was
+    /// sent to (the addresses must be symmetric) and the peer signed it.
+    fn authenticated(&self, check: &Check, response: Result<(Bytes, SocketAddr)>) -> Result<Bytes> {
+        let (bytes, source) = response?;
+
+        if source != check.destination {
+            return Err(Error::Ice(format!("response from {}", source)));
+        }
+
+        let password = self.remote.as_ref().map(|remote| remote.password.as_str());
+
+        if !integrity::verify(&bytes, password.unwrap_or_default().as_bytes()) {
+            return Err(Error::Ice(
+                "response without a valid MESSAGE-INTEGRITY".into(),
+            ));
+        }
+
+        Ok(bytes)
+    }
+
+    /// Fail a check's pair.  If it was the nominated one, the controlling
+    /// agent nominates the best pair that succeeded instead.
+    fn failed(&mut self, check: &Check, error: Error) {
+        log::debug!("check to {} failed: {}", check.destination, error);
+        self.check_list.failed(check.pair);
+
+        if self.check_list.controlling() && !self.check_list.is_nominating() {
+            if let Some(index) = self.check_list.best_succeeded() {
+                self.check_list.nominate(index);
+            }
+        }
+    }
+
+    /// Answer a datagram the peer sent to the host candidate `datagram.listener`.
+    fn answer(&mut self, datagram: &Datagram) -> Option<Bytes> {
+        let mut bytes = datagram.contents.clone();
+        let request = match Message::decode(&mut bytes) {
+            Ok(request) => request,
+            Err(error) => {
+                log::debug!("dropping a datagram from {}: {}", datagram.source, error);
+                return None;
+            }
+        };
+
+        let has_fingerprint = request
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, Attribute::FingerPrint(_)));
+
+        if request.class != Class::Request
+            || (has_fingerprint && !fingerprint::verify(&datagram.contents))
+        {
+            return None;
+        }
+
+        let (key, message) = match handler::authenticate(&datagram.contents, &request, Some(&*self))
+        {
+            Ok(key) => (
+                key,
+                self.answer_check(&request, datagram.listener, datagram.source),
+            ),
+            Err(message) => (None, message),
+        };
+
+        let mut buf = handler::encode(&message, key.as_deref()).ok()?;
+        fingerprint::append(&mut buf);
+
+        Some(buf.freeze())
+    }
+
+    /// Answer an authenticated check (RFC 8445 section 7.3.1).
+    fn answer_check(
+        &mut self,
+        request: &Message,
+        local: usize,
+        source: SocketAddr,
+    ) -> Message<'static> {
+        let mut priority = None;
+        let mut use_candidate = false;
+        let mut controlling = None;
+        let mut controlled = None;
+
+        for attribute in &request.attributes {
+            match attribute {
+                Attribute::Priority(value) => priority = Some(*value),
+                Attribute::UseCandidate => use_candidate = true,
+                Attribute::IceControlling(tiebreaker) => controlling = Some(*tiebreaker),
+                Attribute::IceControlled(tiebreaker) => controlled = Some(*tiebreaker),
+                _ => {}
+            }
+        }
+
+        let role_conflict =
+            || handler::error_response(request.transaction_id, 487, "Role Conflict", vec![]);
+
+        match (self.check_list.controlling(), controlling, controlled) {
+            (true, Some(tiebreaker), _) if self.tiebreaker >= tiebreaker => return role_conflict(),
+            (true, Some(_), _) => self.check_list.set_controlling(false),
+            (false, _, Some(tiebreaker)) if self.tiebreaker >= tiebreaker => {
+                self.check_list.set_controlling(true)
+            }
+            (false, _, Some(_)) => return role_conflict(),
+            _ => {}
+        }
+
+        let priority = match priority {
+            Some(priority) => priority,
+            None => {
+                return handler::error_response(request.transaction_id, 400, "Bad Request", vec![])
+            }
+        };
+
+        if !self.check_list.contains_remote(source) {
+            let candidate = Candidate::peer_reflexive(source, source, priority);
+            log::info!("learned {:?}", candidate);
+            self.check_list.add_remote(candidate);
+        }
+
+        if let Some(index) = self.check_list.find(local, source) {
+            self.check_list.trigger(index);
+
+            if use_candidate && !self.check_list.controlling() {
+                self.check_list.pair_mut(index).nominated = true;
+
+                if self.check_list.pair(index).state == PairState::Succeeded {
+                    self.select(index);
+                }
+            }
+        }
+
+        let (_, _, message) = handler::binding_response(&[], 0, request, source);
+
+        message
+    }
+}
+
+/// Ask every STUN server of each host's family for its mapped address, in
+/// parallel, adding each new one as a server reflexive candidate.
+async fn gather_server_reflexive(
+    sockets: Vec<Arc<StunClient>>,
+    hosts: Vec<Candidate>,
+    stun_servers: Vec<SocketAddr>,
+    shared: Arc<Mutex<Shared>>,
+    gathered: mpsc::UnboundedSender<Candidate>,
+) {
+    let mut bindings = JoinSet::new();
+
+    for (index, socket) in sockets.iter().enumerate() {
+        for server in &stun_servers {
+            if server.is_ipv4() == hosts[index].address.is_ipv4() {
+                let (socket, server) = (socket.clone(), *server);
+                bindings.spawn(async move { (index, server, socket.binding(server).await) });
+            }
+        }
+    }
+
+    while let Some(joined) = bindings.join_next().await {
+        match joined {
+            Ok((index, server, Ok(mapped))) => {
+                let host = &hosts[index];
+                let mut shared = shared.lock().unwrap();
+
+                // without a NAT it's the host candidate again, and servers
+                // behind the same NAT all see the same address
+                if !shared
+                    .candidates
+                    .iter()
+                    .any(|candidate| candidate.address == mapped && candidate.base == host.address)
+                {
+                    let candidate = Candidate::server_reflexive(
+                        mapped,
+                        host.address,
+                        server,
+                        host.local_preference(),
+                    );
+
+                    shared.candidates.push(candidate.clone());
+                    let _ = gathered.send(candidate);
+                }
+            }
+            Ok((_, server, Err(error))) => {
+                log::warn!("no server reflexive candidate from {}: {}", server, error)
+            }
+            Err(error) => log::warn!("error gathering: {}", error),
+        }
+    }
+}
+
+/// Answer the peer's checks, and forward whatever isn't STUN.
+async fn respond(
+    mut received: mpsc::UnboundedReceiver<Datagram>,
+    sockets: Vec<Arc<StunClient>>,
+    shared: Arc<Mutex<Shared>>,
+    forward: mpsc::UnboundedSender<Datagram>,
+) {
+    while let Some(datagram) = received.recv().await {
+        if datagram.kind != Kind::Stun {
+            let _ = forward.send(datagram);
+            continue;
+        }
+
+        let response = shared.lock().unwrap().answer(&datagram);
+
+        if let Some(response) = response {
+            if let Err(error) = sockets[datagram.listener]
+                .send_to(&response, datagram.source)
+                .await
+            {
+                log::warn!("error answering {}: {}", datagram.source, error);
+            }
+        }
+    }
+}
+
+fn error_code(response: &Message) -> Option<u32> {
+    response
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::ErrorCode { code, .. } => Some(*code),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::policy;
+    use crate::server::tests::{config, start};
+    use tokio::time::timeout;
+
+    fn agent_config(controlling: bool) -> AgentConfig {
+        AgentConfig {
+            host_addresses: vec!["127.0.0.1".parse().unwrap()],
+            controlling,
+            pacing: Duration::from_millis(5),
+            policy: policy(),
+            ..AgentConfig::default()
+        }
+    }
+
+    /// Connect two agents, giving `b` the candidates of `a` only if `trickle`.
+    async fn connect(a: &Agent, b: &Agent, trickle: bool) -> (SelectedPair, SelectedPair) {
+        let a_candidates = match trickle {
+            true => a.local_candidates(),
+            false => vec![],
+        };
+        let connected = async {
+            tokio::join!(
+                a.connect(b.local_credentials(), b.local_candidates()),
+                b.connect(a.local_credentials(), a_candidates),
+            )
+        };
+        let (a_selected, b_selected) = timeout(Duration::from_secs(5), connected).await.unwrap();
+
+        (a_selected.unwrap(), b_selected.unwrap())
+    }
+
+    #[tokio::test]
+    async fn it_connects_two_agents_over_loopback() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let b = Agent::gather(agent_config(false)).await.unwrap();
+        let (a_selected, b_selected) = connect(&a, &b, true).await;
+
+        assert_eq!(a_selected.local, a.local_candidates()[0]);
+        assert_eq!(a_selected.remote.address, b.local_candidates()[0].address);
+        assert_eq!(b_selected.remote.address, a.local_candidates()[0].address);
+
+        a.send(b"hello").await.unwrap();
+        let datagram = timeout(Duration::from_secs(5), b.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(datagram.contents, &b"hello"[..]);
+        assert_eq!(datagram.source, a_selected.local.address);
+    }
+
+    #[tokio::test]
+    async fn it_resolves_a_role_conflict() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let b = Agent::gather(agent_config(true)).await.unwrap();
+        connect(&a, &b, true).await;
+
+        let a_controlling = a.shared.lock().unwrap().check_list.controlling();
+        let b_controlling = b.shared.lock().unwrap().check_list.controlling();
+
+        assert_ne!(a_controlling, b_controlling);
+    }
+
+    #[tokio::test]
+    async fn it_learns_peer_reflexive_candidates() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let b = Agent::gather(agent_config(false)).await.unwrap();
+        let (_, b_selected) = connect(&a, &b, false).await;
+
+        assert_eq!(b_selected.remote.kind, CandidateType::PeerReflexive);
+        assert_eq!(b_selected.remote.address, a.local_candidates()[0].address);
+    }
+
+    #[tokio::test]
+    async fn it_drops_stun_of_other_methods() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let b = Agent::gather(agent_config(false)).await.unwrap();
+
+        // a TURN Allocate request, from anyone who knows the host candidate
+        let mut allocate = crate::message::tests::BINDING_REQUEST.to_vec();
+        allocate[1] = 0x03;
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(&allocate, a.local_candidates()[0].address)
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+
+        connect(&a, &b, true).await;
+        a.send(b"hello").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_fails_when_every_pair_fails() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = closed.local_addr().unwrap();
+        drop(closed);
+
+        let remote = Candidate::remote(CandidateType::Host, address, 1, "1");
+        let result = a.connect(IceCredentials::random(), vec![remote]).await;
+
+        assert!(matches!(result, Err(Error::Ice(_))));
+    }
+
+    #[tokio::test]
+    async fn it_drops_a_server_reflexive_candidate_that_is_the_host() {
+        let addresses = start(config(None)).await;
+        let agent = Agent::gather(AgentConfig {
+            stun_servers: vec![addresses[0]],
+            ..agent_config(true)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(agent.local_candidates().len(), 1);
+        assert_eq!(agent.local_candidates()[0].kind, CandidateType::Host);
+    }
+
+    fn consent_config() -> ConsentConfig {
+        ConsentConfig {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(200),
+            keepalive: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_trickles_candidates_until_gathering_completes() {
+        let addresses = start(config(None)).await;
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (agent, mut gathering) = Agent::trickle(AgentConfig {
+            stun_servers: vec![addresses[0], closed.local_addr().unwrap()],
+            ..agent_config(true)
+        })
+        .await
+        .unwrap();
+
+        let host = gathering.next().await.unwrap();
+        assert_eq!(host.kind, CandidateType::Host);
+        assert_eq!(agent.local_candidates(), vec![host]);
+
+        // the only mapped address is the host's, and the closed server never
+        // answers, so gathering completes without another candidate
+        let completed = timeout(Duration::from_secs(5), gathering.next()).await;
+        assert_eq!(completed.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn it_gathers_on_the_interfaces_the_policy_allows() {
+        let agent = Agent::gather(AgentConfig {
+            host_addresses: vec![],
+            interfaces: InterfacePolicy {
+                ipv6: false,
+                loopback: true,
+                ..InterfacePolicy::default()
+            },
+            ..agent_config(true)
+        })
+        .await
+        .unwrap();
+
+        assert!(agent
+            .local_candidates()
+            .iter()
+            .any(|candidate| candidate.address.ip() == IpAddr::from([127, 0, 0, 1])));
+
+        let nothing = Agent::gather(AgentConfig {
+            host_addresses: vec![],
+            interfaces: InterfacePolicy {
+                ipv4: false,
+                ipv6: false,
+                ..InterfacePolicy::default()
+            },
+            ..agent_config(true)
+        })
+        .await;
+
+        assert!(matches!(nothing, Err(Error::Arguments(_))));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_consent_while_the_peer_answers() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let b = Agent::gather(agent_config(false)).await.unwrap();
+        connect(&a, &b, true).await;
+
+        let kept = timeout(Duration::from_millis(500), a.keep_consent(consent_config())).await;
+
+        assert!(kept.is_err(), "consent was lost: {:?}", kept);
+        a.send(b"hello").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_reports_consent_loss() {
+        let a = Agent::gather(agent_config(true)).await.unwrap();
+        let b = Agent::gather(agent_config(false)).await.unwrap();
+        connect(&a, &b, true).await;
+        drop(b);
+
+        let result = timeout(Duration::from_secs(5), a.keep_consent(consent_config()))
+            .await
+            .unwrap();
+
+        assert!(matches!(result, Err(Error::Ice(_))));
+        assert!(a.send(b"hello").await.is_err());
+    }
+
+    #[test]
+    fn it_generates_random_credentials() {
+        let credentials = IceCredentials::random();
+
+        assert_eq!(credentials.ufrag.len(), 8);
+        assert_eq!(credentials.password.len(), 24);
+        assert_ne!(credentials, IceCredentials::random());
+    }
+}
@@ -0,0 +1,587 @@
+//! Candidates are transport addresses an agent may be reachable at (RFC 8445
+//! section 5.1).
+//!
+//! A host candidate is an address on a local interface, a server reflexive
+//! candidate is the address a NAT mapped it to as seen by a STUN server, and
+//! a relayed candidate is allocated on a TURN server.  Peer reflexive
+//! candidates are learned during connectivity checks, from the source of a
+//! request or the mapped address of a response.
+//!
+//! Only one component is used, as with rtcp-mux and BUNDLE.
+//!
+//! Candidates are signalled as SDP attributes (RFC 8839 section 5.1), with
+//! the TCP candidate extensions of RFC 6544:
+//!
+//! ```text
+//! candidate-attribute   = "candidate" ":" foundation SP component-id SP
+//!                         transport SP
+//!                         priority SP
+//!                         connection-address SP     ;from RFC 4566
+//!                         port         ;port from RFC 4566
+//!                         SP cand-type
+//!                         [SP rel-addr]
+//!                         [SP rel-port]
+//!                         *(SP cand-extension)
+//! ```
+//!
+//! e.g. `candidate:1 1 udp 2122260223 192.0.2.1 54400 typ host`.  Unknown
+//! extensions are ignored, and connection addresses must be IP addresses,
+//! so hostnames (e.g. mDNS) aren't supported.  A foundation is 1 to 32
+//! ice-chars: ALPHA, DIGIT, "+" or "/".
+
+use crate::error::{Error, Result};
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    fmt,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+};
+
+/// The component ID of RTP, the only component.
+pub const COMPONENT: u16 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+impl CandidateType {
+    /// The RECOMMENDED type preferences of RFC 8445 section 5.1.2.2.
+    pub fn preference(self) -> u32 {
+        match self {
+            CandidateType::Host => 126,
+            CandidateType::PeerReflexive => 110,
+            CandidateType::ServerReflexive => 100,
+            CandidateType::Relayed => 0,
+        }
+    }
+
+    fn token(self) -> &'static str {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::ServerReflexive => "srflx",
+            CandidateType::PeerReflexive => "prflx",
+            CandidateType::Relayed => "relay",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// The role of a TCP candidate in opening the connection (RFC 6544 section
+/// 4.5).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TcpType {
+    Active,
+    Passive,
+    SimultaneousOpen,
+}
+
+impl TcpType {
+    /// The RECOMMENDED direction preferences of host candidates (RFC 6544
+    /// section 4.2).
+    pub fn direction_preference(self) -> u16 {
+        match self {
+            TcpType::Active => 6,
+            TcpType::Passive => 4,
+            TcpType::SimultaneousOpen => 2,
+        }
+    }
+
+    fn token(self) -> &'static str {
+        match self {
+            TcpType::Active => "active",
+            TcpType::Passive => "passive",
+            TcpType::SimultaneousOpen => "so",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Candidate {
+    pub kind: CandidateType,
+    pub address: SocketAddr,
+    /// The host address a local candidate was derived from, which checks are
+    /// sent from.  The same as `address` for host candidates, and for remote
+    /// candidates, whose base isn't known.
+    pub base: SocketAddr,
+    pub priority: u32,
+    /// Candidates with the same foundation are likely to succeed or fail
+    /// together, so only one of them is checked at first.
+    pub foundation: String,
+    pub component: u16,
+    pub transport: Transport,
+    /// raddr and rport: the base of a reflexive candidate, or the mapped
+    /// address of a relayed one.
+    pub related: Option<SocketAddr>,
+    pub tcp_type: Option<TcpType>,
+}
+
+impl Candidate {
+    /// `local_preference` ranks the candidates of one type, e.g. by
+    /// interface, from 0 (lowest) to 65535.
+    pub fn host(address: SocketAddr, local_preference: u16) -> Self {
+        Self::new(
+            CandidateType::Host,
+            address,
+            address,
+            Self::priority(CandidateType::Host, local_preference),
+            None,
+            Transport::Udp,
+        )
+    }
+
+    /// A host candidate for ICE-TCP (RFC 6544 section 4.1), whose local
+    /// preference is made of the direction preference of `tcp_type` and
+    /// `other_preference`, from 0 to 8191 (section 4.2).  Active candidates
+    /// don't listen, so their port is 9, the discard port (section 4.5).
+    pub fn tcp_host(address: SocketAddr, tcp_type: TcpType, other_preference: u16) -> Self {
+        let address = match tcp_type {
+            TcpType::Active => SocketAddr::new(address.ip(), 9),
+            TcpType::Passive | TcpType::SimultaneousOpen => address,
+        };
+        let local_preference =
+            (tcp_type.direction_preference() << 13) | (other_preference & 0x1FFF);
+
+        Self {
+            tcp_type: Some(tcp_type),
+            ..Self::new(
+                CandidateType::Host,
+                address,
+                address,
+                Self::priority(CandidateType::Host, local_preference),
+                None,
+                Transport::Tcp,
+            )
+        }
+    }
+
+    /// The address `server` saw a request from `base` come from.
+    pub fn server_reflexive(
+        address: SocketAddr,
+        base: SocketAddr,
+        server: SocketAddr,
+        local_preference: u16,
+    ) -> Self {
+        Self {
+            related: Some(base),
+            ..Self::new(
+                CandidateType::ServerReflexive,
+                address,
+                base,
+                Self::priority(CandidateType::ServerReflexive, local_preference),
+                Some(server.ip()),
+                Transport::Udp,
+            )
+        }
+    }
+
+    /// A candidate learned from a connectivity check, with the PRIORITY the
+    /// check carried.
+    pub fn peer_reflexive(address: SocketAddr, base: SocketAddr, priority: u32) -> Self {
+        let candidate = Self::new(
+            CandidateType::PeerReflexive,
+            address,
+            base,
+            priority,
+            None,
+            Transport::Udp,
+        );
+
+        Self {
+            related: (base != address).then_some(base),
+            ..candidate
+        }
+    }
+
+    /// A remote candidate as signalled by the peer.
+    pub fn remote(
+        kind: CandidateType,
+        address: SocketAddr,
+        priority: u32,
+        foundation: impl Into<String>,
+    ) -> Self {
+        Self {
+            kind,
+            address,
+            base: address,
+            priority,
+            foundation: foundation.into(),
+            component: COMPONENT,
+            transport: Transport::Udp,
+            related: None,
+            tcp_type: None,
+        }
+    }
+
+    /// priority = (2^24)*(type preference) + (2^8)*(local preference) +
+    /// (2^0)*(256 - component ID)  (RFC 8445 section 5.1.2.1)
+    pub fn priority(kind: CandidateType, local_preference: u16) -> u32 {
+        (kind.preference() << 24) + ((local_preference as u32) << 8) + (256 - COMPONENT as u32)
+    }
+
+    /// The local preference the priority was computed with.
+    pub fn local_preference(&self) -> u16 {
+        (self.priority >> 8) as u16
+    }
+
+    /// Whether checks can be sent from this local candidate to `remote`: they
+    /// must share an address family and transport, and over TCP an active
+    /// candidate connects to a passive one, or two simultaneous-open ones to
+    /// each other (RFC 6544 section 6.2).
+    pub fn pairs_with(&self, remote: &Candidate) -> bool {
+        let tcp_types = matches!(
+            (self.tcp_type, remote.tcp_type),
+            (None, None)
+                | (Some(TcpType::Active), Some(TcpType::Passive))
+                | (Some(TcpType::Passive), Some(TcpType::Active))
+                | (
+                    Some(TcpType::SimultaneousOpen),
+                    Some(TcpType::SimultaneousOpen)
+                )
+        );
+
+        self.address.is_ipv4() == remote.address.is_ipv4()
+            && self.transport == remote.transport
+            && tcp_types
+    }
+
+    /// The foundation to signal.  One that isn't valid, which can only be set
+    /// directly, is signalled as its hash, so the line still parses and
+    /// candidates that shared a foundation still do.
+    fn signalled_foundation(&self) -> Cow<'_, str> {
+        match valid_foundation(&self.foundation) {
+            true => Cow::Borrowed(&self.foundation),
+            false => Cow::Owned(hash(&self.foundation).to_string()),
+        }
+    }
+
+    /// Candidates of the same type and transport, from the same base IP and
+    /// STUN server, share a foundation (RFC 8445 section 5.1.1.3).
+    fn new(
+        kind: CandidateType,
+        address: SocketAddr,
+        base: SocketAddr,
+        priority: u32,
+        server: Option<IpAddr>,
+        transport: Transport,
+    ) -> Self {
+        Self {
+            kind,
+            address,
+            base,
+            priority,
+            foundation: hash((kind, base.ip(), server, transport)).to_string(),
+            component: COMPONENT,
+            transport,
+            related: None,
+            tcp_type: None,
+        }
+    }
+}
+
+/// Serialize as the value of an `a=candidate` attribute, which starts with
+/// `candidate:`.
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let transport = match self.transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        };
+
+        write!(
+            f,
+            "candidate:{} {} {} {} {} {} typ {}",
+            self.signalled_foundation(),
+            self.component,
+            transport,
+            self.priority,
+            self.address.ip(),
+            self.address.port(),
+            self.kind.token()
+        )?;
+
+        if let Some(related) = self.related {
+            write!(f, " raddr {} rport {}", related.ip(), related.port())?;
+        }
+
+        if let Some(tcp_type) = self.tcp_type {
+            write!(f, " tcptype {}", tcp_type.token())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse an `a=candidate` line, with or without the `a=`.
+impl TryFrom<&str> for Candidate {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Candidate> {
+        let invalid =
+            |reason: &str| Error::Parse(format!("Invalid candidate {:?}: {}", value, reason));
+        let line = value.trim();
+        let line = line.strip_prefix("a=").unwrap_or(line);
+        let line = line
+            .strip_prefix("candidate:")
+            .ok_or_else(|| invalid("expected candidate:"))?;
+        let mut fields = line.split_ascii_whitespace();
+        let mut next = |name: &str| {
+            fields
+                .next()
+                .ok_or_else(|| invalid(&format!("missing the {}", name)))
+        };
+
+        let foundation = next("foundation")?;
+
+        if !valid_foundation(foundation) {
+            return Err(invalid(
+                "the foundation must be 1 to 32 of ALPHA, DIGIT, + and /",
+            ));
+        }
+
+        let component = match next("component")?.parse() {
+            Ok(component @ 1..=256) => component,
+            _ => return Err(invalid("the component must be 1 to 256")),
+        };
+        let transport = match next("transport")?.to_ascii_lowercase().as_str() {
+            "udp" => Transport::Udp,
+            "tcp" => Transport::Tcp,
+            transport => return Err(invalid(&format!("unsupported transport {}", transport))),
+        };
+        let priority = next("priority")?
+            .parse()
+            .map_err(|_| invalid("the priority must be a 32-bit number"))?;
+        let address = socket_addr(next("address")?, next("port")?).map_err(invalid)?;
+
+        if next("typ")? != "typ" {
+            return Err(invalid("expected typ"));
+        }
+
+        let kind = match next("type")? {
+            "host" => CandidateType::Host,
+            "srflx" => CandidateType::ServerReflexive,
+            "prflx" => CandidateType::PeerReflexive,
+            "relay" => CandidateType::Relayed,
+            kind => return Err(invalid(&format!("unknown type {}", kind))),
+        };
+
+        let mut candidate = Candidate {
+            transport,
+            component,
+            ..Candidate::remote(kind, address, priority, foundation)
+        };
+        let mut related_address = None;
+
+        // the rest are name value pairs: raddr, rport and extensions
+        while let Some(name) = fields.next() {
+            let value = fields
+                .next()
+                .ok_or_else(|| invalid(&format!("{} has no value", name)))?;
+
+            match name {
+                "raddr" => related_address = Some(value),
+                "rport" => {
+                    let address = related_address.ok_or_else(|| invalid("rport without raddr"))?;
+                    candidate.related = Some(socket_addr(address, value).map_err(invalid)?);
+                }
+                "tcptype" => {
+                    candidate.tcp_type = Some(match value {
+                        "active" => TcpType::Active,
+                        "passive" => TcpType::Passive,
+                        "so" => TcpType::SimultaneousOpen,
+                        _ => return Err(invalid(&format!("unknown tcptype {}", value))),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        if candidate.transport == Transport::Tcp && candidate.tcp_type.is_none() {
+            return Err(invalid("a TCP candidate needs a tcptype"));
+        }
+
+        Ok(candidate)
+    }
+}
+
+/// foundation = 1*32ice-char (RFC 8839 section 5.1)
+fn valid_foundation(foundation: &str) -> bool {
+    (1..=32).contains(&foundation.len())
+        && foundation
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'/')
+}
+
+fn hash(value: impl Hash) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+
+    hasher.finish() as u32
+}
+
+fn socket_addr(address: &str, port: &str) -> std::result::Result<SocketAddr, &'static str> {
+    let ip: IpAddr = address
+        .parse()
+        .map_err(|_| "the address must be an IP address")?;
+    let port = port.parse().map_err(|_| "the port must be 0 to 65535")?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_the_priority() {
+        let host = Candidate::host("192.0.2.1:5000".parse().unwrap(), 65535);
+
+        assert_eq!(host.priority, 0x7effffff);
+        assert_eq!(host.local_preference(), 65535);
+        assert_eq!(
+            Candidate::priority(CandidateType::PeerReflexive, 65535),
+            0x6effffff
+        );
+    }
+
+    #[test]
+    fn it_parses_and_serializes_sdp_candidates() {
+        for line in [
+            "candidate:1 1 udp 2122260223 192.0.2.1 54400 typ host",
+            "candidate:2 1 udp 1686052607 203.0.113.7 61665 typ srflx raddr 192.0.2.1 rport 54400",
+            "candidate:3 1 tcp 1518280447 2001:db8::1 9 typ host tcptype active",
+            "candidate:4 1 udp 41885439 198.51.100.9 3478 typ relay raddr 203.0.113.7 rport 61665",
+        ] {
+            let candidate = Candidate::try_from(line).unwrap();
+
+            assert_eq!(candidate.to_string(), line);
+        }
+
+        let candidate = Candidate::try_from(
+            "a=candidate:1 2 UDP 2122260223 192.0.2.1 54400 typ prflx generation 0",
+        )
+        .unwrap();
+
+        assert_eq!(candidate.component, 2);
+        assert_eq!(candidate.transport, Transport::Udp);
+        assert_eq!(candidate.kind, CandidateType::PeerReflexive);
+        assert_eq!(candidate.address, "192.0.2.1:54400".parse().unwrap());
+        assert_eq!(candidate.related, None);
+    }
+
+    #[test]
+    fn it_rejects_invalid_sdp_candidates() {
+        for line in [
+            "candidate:1 1 udp 2122260223 192.0.2.1 54400",
+            "candidate:1 0 udp 2122260223 192.0.2.1 54400 typ host",
+            "candidate:1 1 sctp 2122260223 192.0.2.1 54400 typ host",
+            "candidate:1 1 udp 2122260223 abc.local 54400 typ host",
+            "candidate:1 1 udp 2122260223 192.0.2.1 54400 typ relayed",
+            "candidate:1 1 udp 2122260223 192.0.2.1 54400 typ srflx rport 1",
+            "candidate:1 1 tcp 2122260223 192.0.2.1 9 typ host tcptype both",
+            "candidate:1 1 tcp 2122260223 192.0.2.1 9 typ host",
+            "candidate:a-b 1 udp 2122260223 192.0.2.1 54400 typ host",
+            "candidate:123456789012345678901234567890123 1 udp 2122260223 192.0.2.1 54400 typ host",
+            "a=mid:0",
+        ] {
+            assert!(
+                matches!(Candidate::try_from(line), Err(Error::Parse(_))),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn it_serializes_gathered_candidates() {
+        let base = "192.0.2.1:54400".parse().unwrap();
+        let server = "198.51.100.1:3478".parse().unwrap();
+        let mapped = "203.0.113.7:61665".parse().unwrap();
+        let host = Candidate::host(base, 65535);
+        let srflx = Candidate::server_reflexive(mapped, base, server, 65535);
+
+        assert!(host
+            .to_string()
+            .ends_with(" 1 udp 2130706431 192.0.2.1 54400 typ host"));
+        assert!(srflx
+            .to_string()
+            .ends_with(" typ srflx raddr 192.0.2.1 rport 54400"));
+
+        let parsed = Candidate::try_from(srflx.to_string().as_str()).unwrap();
+        assert_eq!(parsed.address, srflx.address);
+        assert_eq!(parsed.foundation, srflx.foundation);
+        assert_eq!(parsed.priority, srflx.priority);
+    }
+
+    #[test]
+    fn it_serializes_an_invalid_foundation_as_a_valid_one() {
+        let address = "192.0.2.1:54400".parse().unwrap();
+        let candidate = Candidate::remote(CandidateType::Host, address, 1, "not valid");
+        let same = Candidate::remote(CandidateType::Host, address, 2, "not valid");
+
+        let parsed = Candidate::try_from(candidate.to_string().as_str()).unwrap();
+        let parsed_same = Candidate::try_from(same.to_string().as_str()).unwrap();
+
+        assert_eq!(parsed.foundation, parsed_same.foundation);
+    }
+
+    #[test]
+    fn it_prioritizes_and_pairs_tcp_candidates() {
+        let address = "192.0.2.1:5000".parse().unwrap();
+        let active = Candidate::tcp_host(address, TcpType::Active, 8191);
+        let passive = Candidate::tcp_host(address, TcpType::Passive, 8191);
+        let so = Candidate::tcp_host(address, TcpType::SimultaneousOpen, 8191);
+        let udp = Candidate::host(address, 65535);
+
+        assert_eq!(active.address.port(), 9);
+        assert_eq!(passive.address, address);
+        assert_eq!(active.local_preference(), (6 << 13) + 8191);
+        assert_eq!(passive.local_preference(), (4 << 13) + 8191);
+        assert_eq!(so.local_preference(), (2 << 13) + 8191);
+        assert_ne!(udp.foundation, passive.foundation);
+        assert_eq!(active.foundation, passive.foundation);
+        assert!(passive
+            .to_string()
+            .ends_with(" tcp 2124414975 192.0.2.1 5000 typ host tcptype passive"));
+
+        assert!(active.pairs_with(&passive));
+        assert!(passive.pairs_with(&active));
+        assert!(so.pairs_with(&so));
+        assert!(!active.pairs_with(&active));
+        assert!(!passive.pairs_with(&so));
+        assert!(!udp.pairs_with(&passive));
+        assert!(udp.pairs_with(&udp));
+    }
+
+    #[test]
+    fn it_shares_foundations_by_type_base_and_server() {
+        let base = "192.0.2.1:5000".parse().unwrap();
+        let other_port = "192.0.2.1:5001".parse().unwrap();
+        let server = "198.51.100.1:3478".parse().unwrap();
+        let other_server = "198.51.100.2:3478".parse().unwrap();
+        let mapped = "203.0.113.1:6000".parse().unwrap();
+
+        assert_eq!(
+            Candidate::host(base, 1).foundation,
+            Candidate::host(other_port, 2).foundation
+        );
+        assert_ne!(
+            Candidate::host(base, 1).foundation,
+            Candidate::server_reflexive(mapped, base, server, 1).foundation
+        );
+        assert_ne!(
+            Candidate::server_reflexive(mapped, base, server, 1).foundation,
+            Candidate::server_reflexive(mapped, base, other_server, 1).foundation
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! The check list of candidate pairs (RFC 8445 section 6.1.2).
+//!
+//! Each local host candidate is paired with each remote candidate that
+//! `Candidate::pairs_with` accepts.  Local server reflexive candidates aren't
+//! paired.  Checks are sent from their base, which is already a host
+//! candidate.  A pair's priority depends on the role:
+//!
+//! ```text
+//! pair priority = 2^32*MIN(G,D) + 2*MAX(G,D) + (G>D?1:0)
+//! ```
+//!
+//! where G is the priority of the controlling agent's candidate and D that of
+//! the controlled agent's.
+//!
+//! Pairs start Frozen, and the highest-priority pair of each foundation
+//! Waiting.  Checks run on triggered pairs first, then on the
+//! highest-priority Waiting pair.  A successful check unfreezes the pairs of
+//! its foundation.
+
+use crate::ice::candidate::Candidate;
+use std::{collections::VecDeque, net::SocketAddr};
+
+/// Beyond this many pairs, a new pair takes the place of the lowest-priority
+/// one, if that's lower than its own (RFC 8445 section 6.1.2.5).  Pairs in
+/// progress, succeeded or nominated are kept.
+const MAX_PAIRS: usize = 100;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum State {
+    Frozen,
+    Waiting,
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Pair {
+    /// The index of the local host candidate, i.e. the socket.
+    pub(crate) local: usize,
+    pub(crate) remote: Candidate,
+    pub(crate) state: State,
+    pub(crate) nominated: bool,
+    /// The local candidate the check discovered, i.e. its mapped address.
+    pub(crate) valid: Option<Candidate>,
+}
+
+#[derive(Debug)]
+pub(crate) struct CheckList {
+    locals: Vec<Candidate>,
+    pairs: Vec<Pair>,
+    triggered: VecDeque<usize>,
+    controlling: bool,
+}
+
+impl CheckList {
+    pub(crate) fn new(controlling: bool) -> Self {
+        Self {
+            locals: vec![],
+            pairs: vec![],
+            triggered: VecDeque::new(),
+            controlling,
+        }
+    }
+
+    /// Add a host candidate, before any remote candidate.
+    pub(crate) fn add_local(&mut self, candidate: Candidate) {
+        self.locals.push(candidate);
+    }
+
+    pub(crate) fn local(&self, index: usize) -> &Candidate {
+        &self.locals[index]
+    }
+
+    pub(crate) fn controlling(&self) -> bool {
+        self.controlling
+    }
+
+    /// Pair priorities are recomputed from the new role.
+    pub(crate) fn set_controlling(&mut self, controlling: bool) {
+        self.controlling = controlling;
+    }
+
+    /// Pair a remote candidate with every local one of its family and
+    /// transport.  A candidate whose address is already known is ignored.
+    pub(crate) fn add_remote(&mut self, candidate: Candidate) {
+        if self
+            .pairs
+            .iter()
+            .any(|pair| pair.remote.address == candidate.address)
+        {
+            return;
+        }
+
+        for local in 0..self.locals.len() {
+            if !self.locals[local].pairs_with(&candidate) {
+                continue;
+            }
+
+            self.insert(Pair {
+                local,
+                remote: candidate.clone(),
+                state: State::Frozen,
+                nominated: false,
+                valid: None,
+            });
+        }
+
+        self.unfreeze();
+    }
+
+    /// Add a pair, pruning the lowest-priority one when there are too many.
+    /// A pruned pair is replaced in place, so other indexes stay valid.
+    fn insert(&mut self, pair: Pair) {
+        if self.pairs.len() < MAX_PAIRS {
+            return self.pairs.push(pair);
+        }
+
+        let priority = self.pair_priority(pair.local, &pair.remote);
+        let lowest = (0..self.pairs.len())
+            .filter(|index| {
+                let pair = &self.pairs[*index];
+
+                !pair.nominated
+                    && matches!(pair.state, State::Frozen | State::Waiting | State::Failed)
+            })
+            .min_by_key(|index| self.priority(*index));
+
+        match lowest {
+            Some(index) if self.priority(index) < priority => {
+                self.triggered.retain(|triggered| *triggered != index);
+                self.pairs[index] = pair;
+            }
+            _ => log::debug!("too many candidate pairs, dropping {:?}", pair),
+        }
+    }
+
+    pub(crate) fn pair(&self, index: usize) -> &Pair {
+        &self.pairs[index]
+    }
+
+    pub(crate) fn pair_mut(&mut self, index: usize) -> &mut Pair {
+        &mut self.pairs[index]
+    }
+
+    pub(crate) fn find(&self, local: usize, remote: SocketAddr) -> Option<usize> {
+        self.pairs
+            .iter()
+            .position(|pair| pair.local == local && pair.remote.address == remote)
+    }
+
+    pub(crate) fn contains_remote(&self, remote: SocketAddr) -> bool {
+        self.pairs.iter().any(|pair| pair.remote.address == remote)
+    }
+
+    pub(crate) fn priority(&self, index: usize) -> u64 {
+        let pair = &self.pairs[index];
+
+        self.pair_priority(pair.local, &pair.remote)
+    }
+
+    fn pair_priority(&self, local: usize, remote: &Candidate) -> u64 {
+        let local = self.locals[local].priority as u64;
+        let remote = remote.priority as u64;
+        let (g, d) = match self.controlling {
+            true => (local, remote),
+            false => (remote, local),
+        };
+
+        (1 << 32) * g.min(d) + 2 * g.max(d) + (g > d) as u64
+    }
+
+    /// The pair to check next, which is now in progress.
+    pub(crate) fn next(&mut self) -> Option<usize> {
+        while let Some(index) = self.triggered.pop_front() {
+            if self.pairs[index].state == State::Waiting {
+                self.pairs[index].state = State::InProgress;
+                return Some(index);
+            }
+        }
+
+        let index = (0..self.pairs.len())
+            .filter(|index| self.pairs[*index].state == State::Waiting)
+            .max_by_key(|index| self.priority(*index))
+            .or_else(|| {
+                // nothing is waiting, so unfreeze the best frozen pair
+                (0..self.pairs.len())
+                    .filter(|index| self.pairs[*index].state == State::Frozen)
+                    .max_by_key(|index| self.priority(*index))
+            })?;
+
+        self.pairs[index].state = State::InProgress;
+
+        Some(index)
+    }
+
+    /// Check a pair ahead of the others, e.g. after receiving a check on it
+    /// (RFC 8445 section 7.3.1.4).  A pair that succeeded or is in progress
+    /// is left alone.
+    pub(crate) fn trigger(&mut self, index: usize) {
+        match self.pairs[index].state {
+            State::Succeeded | State::InProgress => {}
+            State::Frozen | State::Waiting | State::Failed => {
+                self.pairs[index].state = State::Waiting;
+
+                if !self.triggered.contains(&index) {
+                    self.triggered.push_back(index);
+                }
+            }
+        }
+    }
+
+    /// Check a pair again with USE-CANDIDATE, ahead of any other.
+    pub(crate) fn nominate(&mut self, index: usize) {
+        let pair = &mut self.pairs[index];
+        pair.nominated = true;
+        pair.state = State::Waiting;
+        self.triggered.push_front(index);
+    }
+
+    pub(crate) fn succeeded(&mut self, index: usize, valid: Candidate) {
+        let pair = &mut self.pairs[index];
+        pair.state = State::Succeeded;
+        pair.valid = Some(valid);
+
+        for other in 0..self.pairs.len() {
+            if self.pairs[other].state == State::Frozen
+                && self.foundation(other) == self.foundation(index)
+            {
+                self.pairs[other].state = State::Waiting;
+            }
+        }
+    }
+
+    /// A failed pair is no longer nominated, so another one can be.
+    pub(crate) fn failed(&mut self, index: usize) {
+        let pair = &mut self.pairs[index];
+        pair.state = State::Failed;
+        pair.nominated = false;
+    }
+
+    /// Whether there are pairs, and every one of them failed.
+    pub(crate) fn is_failed(&self) -> bool {
+        !self.pairs.is_empty() && self.pairs.iter().all(|pair| pair.state == State::Failed)
+    }
+
+    /// Whether any pair was nominated.
+    pub(crate) fn is_nominating(&self) -> bool {
+        self.pairs.iter().any(|pair| pair.nominated)
+    }
+
+    /// The highest-priority pair that succeeded, to nominate.
+    pub(crate) fn best_succeeded(&self) -> Option<usize> {
+        (0..self.pairs.len())
+            .filter(|index| self.pairs[*index].state == State::Succeeded)
+            .max_by_key(|index| self.priority(*index))
+    }
+
+    /// The foundation of a pair is the foundations of its candidates.
+    fn foundation(&self, index: usize) -> (&str, &str) {
+        let pair = &self.pairs[index];
+
+        (&self.locals[pair.local].foundation, &pair.remote.foundation)
+    }
+
+    /// Make the highest-priority pair of each foundation Waiting, unless the
+    /// foundation already has a pair being checked.
+    fn unfreeze(&mut self) {
+        let mut indexes: Vec<usize> = (0..self.pairs.len()).collect();
+        indexes.sort_by_key(|index| std::cmp::Reverse(self.priority(*index)));
+
+        for index in indexes {
+            let foundation = self.foundation(index);
+            let active = self.pairs.iter().enumerate().any(|(other, pair)| {
+                pair.state != State::Frozen
+                    && pair.state != State::Failed
+                    && self.foundation(other) == foundation
+            });
+
+            if !active && self.pairs[index].state == State::Frozen {
+                self.pairs[index].state = State::Waiting;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ice::candidate::{CandidateType, Transport};
+
+    fn check_list(controlling: bool) -> CheckList {
+        let mut check_list = CheckList::new(controlling);
+        check_list.add_local(Candidate::host("192.0.2.1:5000".parse().unwrap(), 65535));
+        check_list.add_local(Candidate::host(
+            "[2001:db8::1]:5000".parse().unwrap(),
+            65534,
+        ));
+        check_list
+    }
+
+    fn remote(address: &str, priority: u32, foundation: &str) -> Candidate {
+        Candidate::remote(
+            CandidateType::Host,
+            address.parse().unwrap(),
+            priority,
+            foundation,
+        )
+    }
+
+    #[test]
+    fn it_pairs_candidates_of_the_same_family() {
+        let mut check_list = check_list(true);
+        check_list.add_remote(remote("198.51.100.1:6000", 100, "1"));
+        check_list.add_remote(remote("[2001:db8::2]:6000", 200, "2"));
+        check_list.add_remote(remote("198.51.100.1:6000", 300, "3"));
+        check_list.add_remote(Candidate {
+            transport: Transport::Tcp,
+            ..remote("198.51.100.2:9", 400, "4")
+        });
+
+        assert_eq!(check_list.pairs.len(), 2);
+        assert_eq!(check_list.pair(0).local, 0);
+        assert_eq!(check_list.pair(1).local, 1);
+        assert!(check_list.contains_remote("[2001:db8::2]:6000".parse().unwrap()));
+        assert_eq!(
+            check_list.find(1, "[2001:db8::2]:6000".parse().unwrap()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn it_computes_the_pair_priority_from_the_role() {
+        let mut check_list = check_list(true);
+        check_list.add_remote(remote("198.51.100.1:6000", 100, "1"));
+        let local = check_list.local(0).priority as u64;
+
+        assert_eq!(check_list.priority(0), (1 << 32) * 100 + 2 * local + 1);
+
+        check_list.set_controlling(false);
+        assert_eq!(check_list.priority(0), (1 << 32) * 100 + 2 * local);
+    }
+
+    #[test]
+    fn it_checks_one_pair_per_foundation_then_unfreezes_the_rest() {
+        let mut check_list = check_list(true);
+        check_list.add_remote(remote("198.51.100.1:6000", 200, "1"));
+        check_list.add_remote(remote("198.51.100.2:6000", 100, "1"));
+
+        assert_eq!(check_list.pair(0).state, State::Waiting);
+        assert_eq!(check_list.pair(1).state, State::Frozen);
+        assert_eq!(check_list.next(), Some(0));
+
+        let valid = check_list.local(0).clone();
+        check_list.succeeded(0, valid);
+
+        assert_eq!(check_list.pair(1).state, State::Waiting);
+        assert_eq!(check_list.next(), Some(1));
+        assert_eq!(check_list.next(), None);
+
+        check_list.failed(1);
+        assert!(!check_list.is_failed());
+    }
+
+    #[test]
+    fn it_checks_triggered_and_nominated_pairs_first() {
+        let mut check_list = check_list(true);
+        check_list.add_remote(remote("198.51.100.1:6000", 200, "1"));
+        check_list.add_remote(remote("198.51.100.2:6000", 100, "2"));
+
+        check_list.trigger(1);
+        assert_eq!(check_list.next(), Some(1));
+        assert_eq!(check_list.next(), Some(0));
+
+        check_list.failed(0);
+        check_list.failed(1);
+        assert!(check_list.is_failed());
+
+        check_list.nominate(1);
+        assert!(check_list.is_nominating());
+        assert_eq!(check_list.next(), Some(1));
+
+        // so the controlling agent can nominate another pair
+        check_list.failed(1);
+        assert!(!check_list.is_nominating());
+        assert_eq!(check_list.best_succeeded(), None);
+
+        let valid = check_list.local(0).clone();
+        check_list.succeeded(0, valid);
+        assert_eq!(check_list.best_succeeded(), Some(0));
+    }
+
+    #[test]
+    fn it_prunes_the_lowest_priority_pairs() {
+        let mut check_list = check_list(true);
+
+        for port in 0..MAX_PAIRS as u32 {
+            let address = format!("198.51.100.1:{}", 6000 + port);
+            check_list.add_remote(remote(&address, 1000 + port, "1"));
+        }
+
+        check_list.nominate(0);
+        check_list.add_remote(remote("198.51.100.2:6000", 5000, "2"));
+        check_list.add_remote(remote("198.51.100.3:6000", 10, "3"));
+
+        assert_eq!(check_list.pairs.len(), MAX_PAIRS);
+        assert!(check_list.pair(0).nominated);
+        assert_eq!(check_list.pair(1).remote.priority, 5000);
+        assert!(!check_list.contains_remote("198.51.100.1:6001".parse().unwrap()));
+        assert!(!check_list.contains_remote("198.51.100.3:6000".parse().unwrap()));
+    }
+}
@@ -0,0 +1,163 @@
+//! Consent freshness (RFC 7675) and keepalives on the selected pair.
+//!
+//! The peer has to keep agreeing to receive what's sent on the pair, so a
+//! consent check, an authenticated Binding request, goes out every 5
+//! seconds, give or take 20% so agents don't fall into step.  Each check is
+//! sent once, without retransmissions, and its response counts until the
+//! next check is due.  Once nothing has answered for 30 seconds, consent is
+//! lost and the pair is dropped, so nothing more is sent on it.
+//!
+//! Separately, a Binding indication keeps the NAT bindings open when
+//! nothing at all was sent on the pair for Tr (RFC 8445 section 11).
+//! Consent checks count, so with the default periods no indication is ever
+//! needed.
+//!
+//! `Consent` only tracks the timers, `Agent::keep_consent` sends the checks.
+
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConsentConfig {
+    /// The basic period of consent checks, randomized by 20% either way.
+    pub interval: Duration,
+    /// Consent expires this long after the last response.
+    pub timeout: Duration,
+    /// Tr, how long nothing may be sent before a keepalive.
+    pub keepalive: Duration,
+}
+
+impl Default for ConsentConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            keepalive: Duration::from_secs(15),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Consent {
+    config: ConsentConfig,
+    last_response: Instant,
+    last_sent: Instant,
+    next_check: Instant,
+}
+
+impl Consent {
+    /// Consent was just granted, by the check that selected the pair.
+    pub(crate) fn new(config: ConsentConfig, now: Instant) -> Self {
+        let next_check = now + jitter(config.interval);
+
+        Self {
+            config,
+            last_response: now,
+            last_sent: now,
+            next_check,
+        }
+    }
+
+    /// Whether a consent check is due, scheduling the next one if it is.
+    pub(crate) fn poll_check(&mut self, now: Instant) -> bool {
+        if now < self.next_check {
+            return false;
+        }
+
+        self.next_check = now + jitter(self.config.interval);
+        self.last_sent = self.last_sent.max(now);
+
+        true
+    }
+
+    /// Whether a keepalive is due, counting it as sent if it is.
+    pub(crate) fn poll_keepalive(&mut self, now: Instant) -> bool {
+        if now < self.last_sent + self.config.keepalive {
+            return false;
+        }
+
+        self.last_sent = now;
+
+        true
+    }
+
+    /// A packet was sent on the pair.
+    pub(crate) fn sent(&mut self, at: Instant) {
+        self.last_sent = self.last_sent.max(at);
+    }
+
+    /// A consent check was answered.
+    pub(crate) fn responded(&mut self, now: Instant) {
+        self.last_response = self.last_response.max(now);
+    }
+
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        now >= self.last_response + self.config.timeout
+    }
+
+    /// When the next check is due.
+    pub(crate) fn next_check(&self) -> Instant {
+        self.next_check
+    }
+
+    /// When the next check, keepalive or expiry is due.
+    pub(crate) fn poll_timeout(&self) -> Instant {
+        self.next_check
+            .min(self.last_sent + self.config.keepalive)
+            .min(self.last_response + self.config.timeout)
+    }
+}
+
+/// `interval` randomized between 0.8 and 1.2 times.
+fn jitter(interval: Duration) -> Duration {
+    interval.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn it_randomizes_the_check_interval() {
+        let now = Instant::now();
+        let mut consent = Consent::new(ConsentConfig::default(), now);
+
+        assert!(!consent.poll_check(now + seconds(3)));
+        assert!(consent.poll_check(now + seconds(6)));
+        assert!(!consent.poll_check(now + seconds(6)));
+
+        let next = consent.next_check - (now + seconds(6));
+        assert!(next >= seconds(4) && next <= seconds(6), "{:?}", next);
+    }
+
+    #[test]
+    fn it_expires_without_responses() {
+        let now = Instant::now();
+        let mut consent = Consent::new(ConsentConfig::default(), now);
+
+        consent.responded(now + seconds(10));
+        assert!(!consent.is_expired(now + seconds(39)));
+        assert!(consent.is_expired(now + seconds(40)));
+        assert!(consent.poll_timeout() <= now + seconds(40));
+    }
+
+    #[test]
+    fn it_sends_keepalives_when_nothing_else_was_sent() {
+        let now = Instant::now();
+        let config = ConsentConfig {
+            interval: seconds(60),
+            ..ConsentConfig::default()
+        };
+        let mut consent = Consent::new(config, now);
+
+        consent.sent(now + seconds(10));
+        assert!(!consent.poll_keepalive(now + seconds(20)));
+        assert_eq!(consent.poll_timeout(), now + seconds(25));
+        assert!(consent.poll_keepalive(now + seconds(25)));
+        assert!(!consent.poll_keepalive(now + seconds(26)));
+    }
+}
@@ -0,0 +1,170 @@
+//! Choosing the interfaces to gather host candidates on, and streaming the
+//! candidates as they're gathered.
+//!
+//! An agent that isn't given host addresses gathers on every address of the
+//! local interfaces, except the ones the `InterfacePolicy` rules out.  By
+//! default those are
+//!
+//! * loopback addresses, which no peer can reach,
+//! * link-local addresses, which only work on the same link and, over IPv6,
+//!   need a scope,
+//! * and addresses of VPN interfaces, recognized by their name, which tend to
+//!   be slow and to leak addresses the user may not want to reveal.
+//!
+//! Candidates are trickled (RFC 8838): host candidates are available as soon
+//! as their sockets are bound, server reflexive ones as each STUN server
+//! answers, see `Agent::trickle`.
+
+use crate::{
+    error::{Error, Result},
+    ice::candidate::Candidate,
+};
+use std::net::IpAddr;
+use tokio::sync::mpsc;
+
+/// Interface names starting with these are VPNs: TUN/TAP devices, WireGuard,
+/// PPP, IPsec, Tailscale and ZeroTier.
+pub const VPN_PREFIXES: [&str; 8] = [
+    "tun",
+    "tap",
+    "utun",
+    "wg",
+    "ppp",
+    "ipsec",
+    "tailscale",
+    "zt",
+];
+
+/// Which interface addresses host candidates are gathered on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InterfacePolicy {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub loopback: bool,
+    pub link_local: bool,
+    pub vpn: bool,
+}
+
+impl Default for InterfacePolicy {
+    fn default() -> Self {
+        Self {
+            ipv4: true,
+            ipv6: true,
+            loopback: false,
+            link_local: false,
+            vpn: false,
+        }
+    }
+}
+
+impl InterfacePolicy {
+    /// Whether `ip`, on the interface named `name`, may be gathered on.
+    pub fn allows(&self, name: &str, ip: IpAddr) -> bool {
+        let (family, link_local) = match ip {
+            IpAddr::V4(ip) => (self.ipv4, ip.is_link_local()),
+            IpAddr::V6(ip) => (self.ipv6, ip.segments()[0] & 0xFFC0 == 0xFE80),
+        };
+        let vpn = VPN_PREFIXES.iter().any(|prefix| name.starts_with(prefix));
+
+        family
+            && !ip.is_unspecified()
+            && !ip.is_multicast()
+            && (self.loopback || !ip.is_loopback())
+            && (self.link_local || !link_local)
+            && (self.vpn || !vpn)
+    }
+
+    /// The addresses of the local interfaces the policy allows, in the order
+    /// the system lists them.
+    pub fn host_addresses(&self) -> Result<Vec<IpAddr>> {
+        let interfaces = if_addrs::get_if_addrs()
+            .map_err(|e| Error::Startup(format!("listing the interfaces: {}", e)))?;
+        let mut addresses: Vec<IpAddr> = vec![];
+
+        for interface in interfaces {
+            let ip = interface.ip();
+
+            if !self.allows(&interface.name, ip) {
+                log::debug!("not gathering on {} ({})", ip, interface.name);
+            } else if !addresses.contains(&ip) {
+                addresses.push(ip);
+            }
+        }
+
+        Ok(addresses)
+    }
+}
+
+/// The local candidates of an agent, as they're gathered.
+#[derive(Debug)]
+pub struct Gathering {
+    pub(crate) candidates: mpsc::UnboundedReceiver<Candidate>,
+}
+
+impl Gathering {
+    /// The next candidate, or `None` once gathering completed, when the peer
+    /// can be told there are no more (end-of-candidates).
+    pub async fn next(&mut self) -> Option<Candidate> {
+        self.candidates.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_filters_addresses_by_policy() {
+        let policy = InterfacePolicy::default();
+
+        for (name, ip, allowed) in [
+            ("eth0", "192.0.2.1", true),
+            ("eth0", "2001:db8::1", true),
+            ("lo", "127.0.0.1", false),
+            ("lo", "::1", false),
+            ("eth0", "169.254.10.1", false),
+            ("eth0", "fe80::1", false),
+            ("eth0", "0.0.0.0", false),
+            ("tun0", "10.8.0.2", false),
+            ("wg0", "10.0.0.2", false),
+            ("utun3", "fd00::2", false),
+        ] {
+            assert_eq!(
+                policy.allows(name, ip.parse().unwrap()),
+                allowed,
+                "{} on {}",
+                ip,
+                name
+            );
+        }
+
+        let policy = InterfacePolicy {
+            ipv6: false,
+            loopback: true,
+            link_local: true,
+            vpn: true,
+            ..InterfacePolicy::default()
+        };
+
+        assert!(policy.allows("lo", "127.0.0.1".parse().unwrap()));
+        assert!(policy.allows("eth0", "169.254.10.1".parse().unwrap()));
+        assert!(policy.allows("tun0", "10.8.0.2".parse().unwrap()));
+        assert!(!policy.allows("eth0", "2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn it_lists_the_allowed_interface_addresses() {
+        let policy = InterfacePolicy {
+            loopback: true,
+            ..InterfacePolicy::default()
+        };
+        let addresses = policy.host_addresses().unwrap();
+
+        assert!(addresses.contains(&"127.0.0.1".parse().unwrap()));
+        assert!(InterfacePolicy::default()
+            .host_addresses()
+            .unwrap()
+            .iter()
+            .all(|ip| !ip.is_loopback()));
+    }
+}
@@ -0,0 +1,373 @@
+//! An ICE-lite responder (RFC 8445 section 2.5).
+//!
+//! Lite agents only answer connectivity checks, and always take the
+//! controlled role, leaving nomination to the full agent on the other side.
+//! Each session is registered with the ufrag and password the lite agent put
+//! in its SDP, and the ufrag of the peer.  A check must carry
+//!
+//! * USERNAME `local_ufrag:remote_ufrag` of a registered session, and
+//!   MESSAGE-INTEGRITY keyed with its password (short-term credentials,
+//!   rejected with 400 or 401 like `protocol::handler`),
+//! * PRIORITY, and ICE-CONTROLLING rather than ICE-CONTROLLED, which a
+//!   controlled agent answers with 487 (Role Conflict),
+//! * and, if present, a FINGERPRINT that matches, or it's dropped.
+//!
+//! Responses are signed with the session's password and fingerprinted.  A
+//! successful check with USE-CANDIDATE nominates its pair, which is reported
+//! to the callback registered with `on_nomination`.
+
+use crate::{
+    error::{Error, Result},
+    message::attribute::Attribute,
+    message::class::Class,
+    message::fingerprint,
+    message::Message,
+    protocol::demux::Kind,
+    protocol::handler::{self, Credentials, Handled},
+};
+use bytes::Bytes;
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+/// A pair the controlling agent nominated with USE-CANDIDATE.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Nomination {
+    pub local_ufrag: String,
+    pub remote_ufrag: String,
+    /// The remote candidate, i.e. the source of the check.
+    pub source: SocketAddr,
+    /// The PRIORITY of the check.
+    pub priority: u32,
+}
+
+struct Session {
+    password: String,
+    remote_ufrag: String,
+}
+
+/// Answers connectivity checks for the registered sessions.
+#[derive(Default)]
+pub struct IceLite {
+    /// By local ufrag.
+    sessions: HashMap<String, Session>,
+    nominated: Option<Box<dyn FnMut(Nomination) + Send>>,
+}
+
+impl fmt::Debug for IceLite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.sessions.keys()).finish()
+    }
+}
+
+impl Credentials for IceLite {
+    fn password(&self, username: &str) -> Option<String> {
+        let (local_ufrag, remote_ufrag) = username.split_once(':')?;
+        let session = self.sessions.get(local_ufrag)?;
+
+        (session.remote_ufrag == remote_ufrag).then(|| session.password.clone())
+    }
+}
+
+impl IceLite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the checks of a session, replacing any with the same local
+    /// ufrag (e.g. after an ICE restart).
+    pub fn add_session(
+        &mut self,
+        local_ufrag: impl Into<String>,
+        local_password: impl Into<String>,
+        remote_ufrag: impl Into<String>,
+    ) {
+        self.sessions.insert(
+            local_ufrag.into(),
+            Session {
+                password: local_password.into(),
+                remote_ufrag: remote_ufrag.into(),
+            },
+        );
+    }
+
+    /// Stop answering the checks of a session, returning whether it existed.
+    pub fn remove_session(&mut self, local_ufrag: &str) -> bool {
+        self.sessions.remove(local_ufrag).is_some()
+    }
+
+    /// Call `callback` with each nomination.  It's called while the check is
+    /// being handled, so it should hand the nomination off rather than block.
+    pub fn on_nomination(&mut self, callback: impl FnMut(Nomination) + Send + 'static) {
+        self.nominated = Some(Box::new(callback));
+    }
+
+    /// Answer a datagram received from `source`.  Errors when the datagram
+    /// looks like STUN but can't be decoded or has a bad FINGERPRINT, in
+    /// which case it should be dropped.
+    pub fn handle(&mut self, datagram: &[u8], source: SocketAddr) -> Result<Handled> {
+        if Kind::of(datagram) != Kind::Stun {
+            return Ok(Handled::NotStun);
+        }
+
+        let mut bytes = Bytes::copy_from_slice(datagram);
+        let request = Message::decode(&mut bytes)?;
+
+        log::info!(
+            "received {} bytes from {}: {:?}",
+            datagram.len(),
+            source,
+            request
+        );
+
+        let has_fingerprint = request
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, Attribute::FingerPrint(_)));
+
+        if has_fingerprint && !fingerprint::verify(datagram) {
+            return Err(Error::Decode(format!(
+                "Invalid FINGERPRINT from {}",
+                source
+            )));
+        }
+
+        if request.class != Class::Request {
+            return Ok(Handled::NoReply);
+        }
+
+        let (key, destination, message) =
+            match handler::authenticate(datagram, &request, Some(&*self)) {
+                Ok(key) => {
+                    let (destination, message) = self.check(&request, source);
+                    (key, destination, message)
+                }
+                Err(message) => (None, source, message),
+            };
+
+        let mut buf = handler::encode(&message, key.as_deref())?;
+        fingerprint::append(&mut buf);
+
+        Ok(Handled::Reply {
+            destination,
+            contents: buf.freeze(),
+        })
+    }
+
+    /// Answer an authenticated check, and report its nomination.
+    fn check<'a>(&mut self, request: &Message, source: SocketAddr) -> (SocketAddr, Message<'a>) {
+        let mut username = None;
+        let mut priority = None;
+        let mut use_candidate = false;
+        let mut controlled = false;
+
+        for attribute in &request.attributes {
+            match attribute {
+                Attribute::Username(value) => username = Some(*value),
+                Attribute::Priority(value) => priority = Some(*value),
+                Attribute::UseCandidate => use_candidate = true,
+                Attribute::IceControlled(_) => controlled = true,
+                _ => {}
+            }
+        }
+
+        // a lite agent is always controlled, so it keeps its role whatever
+        // the tiebreakers and lets the peer switch
+        if controlled {
+            let message =
+                handler::error_response(request.transaction_id, 487, "Role Conflict", vec![]);
+
+            return (source, message);
+        }
+
+        let priority = match priority {
+            Some(priority) => priority,
+            None => {
+                let message =
+                    handler::error_response(request.transaction_id, 400, "Bad Request", vec![]);
+
+                return (source, message);
+            }
+        };
+
+        let (_, destination, message) = handler::binding_response(&[], 0, request, source);
+
+        if use_candidate && message.class == Class::SuccessResponse {
+            // authentication succeeded, so USERNAME is `local:remote`
+            let (local_ufrag, remote_ufrag) = username
+                .and_then(|username| username.split_once(':'))
+                .unwrap_or_default();
+            let nomination = Nomination {
+                local_ufrag: local_ufrag.to_string(),
+                remote_ufrag: remote_ufrag.to_string(),
+                source,
+                priority,
+            };
+
+            log::info!("nominated {:?}", nomination);
+
+            if let Some(nominated) = &mut self.nominated {
+                nominated(nomination);
+            }
+        }
+
+        (destination, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::integrity;
+    use bytes::BytesMut;
+    use std::sync::mpsc;
+
+    const PEER: &str = "198.51.100.7:40000";
+    const LOCAL_UFRAG: &str = "sfu1";
+    const LOCAL_PASSWORD: &str = "asd88fgpdd777uzjYhagZg";
+    const REMOTE_UFRAG: &str = "peer";
+
+    fn ice_lite() -> IceLite {
+        let mut ice_lite = IceLite::new();
+        ice_lite.add_session(LOCAL_UFRAG, LOCAL_PASSWORD, REMOTE_UFRAG);
+        ice_lite
+    }
+
+    /// A check as a full controlling agent sends it.
+    fn check<'a>(username: &'a str, mut attributes: Vec<Attribute<'a>>, key: &str) -> Vec<u8> {
+        attributes.insert(0, Attribute::Username(username));
+        let mut buf = BytesMut::new();
+        Message::binding_request(attributes)
+            .encode(&mut buf)
+            .unwrap();
+        integrity::sign(&mut buf, key.as_bytes());
+        fingerprint::append(&mut buf);
+        buf.to_vec()
+    }
+
+    fn controlling() -> Vec<Attribute<'static>> {
+        vec![
+            Attribute::Priority(0x6e0001ff),
+            Attribute::IceControlling(0x932ff9b151263b36),
+        ]
+    }
+
+    fn reply(handled: Handled) -> Bytes {
+        match handled {
+            Handled::Reply { contents, .. } => contents,
+            handled => panic!("expected a reply, but got {:?}", handled),
+        }
+    }
+
+    fn error_code(contents: &mut Bytes) -> u32 {
+        let message = Message::decode(contents).unwrap();
+
+        match message.attributes[0] {
+            Attribute::ErrorCode { code, .. } => code,
+            ref attribute => panic!("expected ERROR-CODE, but got {:?}", attribute),
+        }
+    }
+
+    #[test]
+    fn it_answers_a_check_with_a_signed_and_fingerprinted_response() {
+        let source = PEER.parse().unwrap();
+        let request = check("sfu1:peer", controlling(), LOCAL_PASSWORD);
+        let mut contents = reply(ice_lite().handle(&request, source).unwrap());
+
+        assert!(fingerprint::verify(&contents));
+        assert!(integrity::verify(&contents, LOCAL_PASSWORD.as_bytes()));
+
+        let message = Message::decode(&mut contents).unwrap();
+        assert_eq!(message.class, Class::SuccessResponse);
+        assert_eq!(message.mapped_address(), Some(source));
+    }
+
+    #[test]
+    fn it_reports_nominations() {
+        let source = PEER.parse().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut ice_lite = ice_lite();
+        ice_lite.on_nomination(move |nomination| sender.send(nomination).unwrap());
+
+        let mut attributes = controlling();
+        attributes.push(Attribute::UseCandidate);
+
+        for request in [
+            check("sfu1:peer", controlling(), LOCAL_PASSWORD),
+            check("sfu1:peer", attributes, LOCAL_PASSWORD),
+        ] {
+            ice_lite.handle(&request, source).unwrap();
+        }
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![Nomination {
+                local_ufrag: LOCAL_UFRAG.into(),
+                remote_ufrag: REMOTE_UFRAG.into(),
+                source,
+                priority: 0x6e0001ff,
+            }]
+        );
+    }
+
+    #[test]
+    fn it_rejects_checks_of_unknown_sessions() {
+        let source = PEER.parse().unwrap();
+        let mut ice_lite = ice_lite();
+
+        for (username, key, expected) in [
+            ("sfu1:other", LOCAL_PASSWORD, 401),
+            ("sfu2:peer", LOCAL_PASSWORD, 401),
+            ("sfu1", LOCAL_PASSWORD, 401),
+            ("sfu1:peer", "wrong", 401),
+        ] {
+            let request = check(username, controlling(), key);
+            let mut contents = reply(ice_lite.handle(&request, source).unwrap());
+
+            assert!(fingerprint::verify(&contents));
+            assert_eq!(error_code(&mut contents), expected, "{}", username);
+        }
+
+        assert!(ice_lite.remove_session(LOCAL_UFRAG));
+
+        let request = check("sfu1:peer", controlling(), LOCAL_PASSWORD);
+        let mut contents = reply(ice_lite.handle(&request, source).unwrap());
+        assert_eq!(error_code(&mut contents), 401);
+    }
+
+    #[test]
+    fn it_rejects_checks_without_priority_or_with_a_role_conflict() {
+        let source = PEER.parse().unwrap();
+        let mut ice_lite = ice_lite();
+
+        for (attributes, expected) in [
+            (vec![Attribute::IceControlling(1)], 400),
+            (
+                vec![Attribute::Priority(1), Attribute::IceControlled(1)],
+                487,
+            ),
+        ] {
+            let request = check("sfu1:peer", attributes, LOCAL_PASSWORD);
+            let mut contents = reply(ice_lite.handle(&request, source).unwrap());
+
+            // the check was authenticated, so the rejection is signed
+            assert!(integrity::verify(&contents, LOCAL_PASSWORD.as_bytes()));
+            assert_eq!(error_code(&mut contents), expected);
+        }
+    }
+
+    #[test]
+    fn it_drops_a_check_with_a_bad_fingerprint() {
+        let source = PEER.parse().unwrap();
+        let mut request = check("sfu1:peer", controlling(), LOCAL_PASSWORD);
+        let last = request.len() - 1;
+        request[last] ^= 1;
+
+        assert!(matches!(
+            ice_lite().handle(&request, source),
+            Err(Error::Decode(_))
+        ));
+        assert_eq!(
+            ice_lite().handle(&[22, 0xFE, 0xFD], source).unwrap(),
+            Handled::NotStun
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! Interactive Connectivity Establishment (RFC 8445).
+//!
+//! ICE finds a path between two peers behind NATs.  Each agent gathers
+//! candidate addresses and signals them to its peer, e.g. in SDP, and then
+//! both check which pairs of candidates reach each other by sending STUN
+//! Binding requests, the connectivity checks.
+//!
+//! A lite implementation only answers connectivity checks on its public
+//! addresses, which is all a server with a public IP (e.g. an SFU) needs,
+//! see `lite`.  A full implementation gathers candidates and runs checks
+//! itself, see `agent`, and then keeps consent to send, see `consent`.
+//! Checks and media can also run over TCP, see `tcp`.
+
+#[cfg(feature = "async")]
+pub mod agent;
+pub mod candidate;
+#[cfg(feature = "async")]
+pub(crate) mod check_list;
+#[cfg(feature = "async")]
+pub mod consent;
+#[cfg(feature = "async")]
+pub mod gather;
+pub mod lite;
+#[cfg(feature = "async")]
+pub mod tcp;
@@ -0,0 +1,519 @@
+//! ICE-TCP (RFC 6544), for clients that can only reach us over TCP.
+//!
+//! Over TCP, STUN and media share the connection, so each packet is framed
+//! with its length as in RFC 4571:
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! -----------------------------------------------------------------
+//! |             LENGTH            |  RTP or RTCP packet ...       |
+//! -----------------------------------------------------------------
+//! ```
+//!
+//! This differs from `protocol::stream`, which finds the end of each message
+//! from the STUN header alone.  An active candidate opens the connection to a
+//! passive one, or two simultaneous-open candidates open it to each other,
+//! see `Candidate::tcp_host`.
+//!
+//! `IceTcpListener` is a passive candidate's listener.  It answers the
+//! connectivity checks framed on each connection with the same `IceLite` as
+//! the UDP socket, and hands the other frames to a sink, as if they were
+//! datagrams from the connection's peer.  Only the passive side is
+//! implemented: a lite agent waits for its peer to connect, and `Agent`
+//! gathers and checks UDP candidates only.
+//!
+//! Anyone can connect, so a connection must start with a connectivity
+//! check: anything else before a check succeeds closes it, as does not
+//! having one succeed within the idle timeout.  After that, a connection
+//! that receives nothing for the idle timeout is closed, and the number of
+//! open connections is capped.
+
+use crate::{
+    error::{Error, Result},
+    ice::lite::IceLite,
+    message::{class::Class, Message},
+    protocol::demux::{Datagram, Kind, Sink},
+    protocol::handler::Handled,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+    time::{self, Instant},
+};
+
+/// The 16-bit LENGTH field caps frames at 65535 bytes.
+pub const MAX_FRAME_LENGTH: usize = u16::MAX as usize;
+
+/// How long a connection is kept without receiving anything.  A peer keeping
+/// consent (RFC 7675) sends a check every few seconds.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many connections a listener keeps open.
+pub const MAX_CONNECTIONS: usize = 1024;
+
+/// Prefix `contents` with its length.
+pub fn frame(contents: &[u8]) -> Result<Bytes> {
+    if contents.len() > MAX_FRAME_LENGTH {
+        return Err(Error::Send(format!(
+            "{} bytes don't fit in an RFC 4571 frame",
+            contents.len()
+        )));
+    }
+
+    let mut buf = BytesMut::with_capacity(2 + contents.len());
+    buf.put_u16(contents.len() as u16);
+    buf.put_slice(contents);
+
+    Ok(buf.freeze())
+}
+
+/// Splits what was read from the stream into frames, without touching any
+/// IO itself.
+#[derive(Debug, Default)]
+pub struct Framer {
+    buf: BytesMut,
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffer bytes read from the stream.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The contents of the next complete frame, if one has been buffered.
+    pub fn next_frame(&mut self) -> Option<Bytes> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+
+        let length = 2 + (&self.buf[..2]).get_u16() as usize;
+
+        if self.buf.len() < length {
+            return None;
+        }
+
+        let mut frame = self.buf.split_to(length);
+        frame.advance(2);
+
+        Some(frame.freeze())
+    }
+}
+
+/// The write side of each open connection, by peer.
+type Connections = Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Bytes>>>>;
+
+/// A passive ICE-TCP listener, answering checks with a shared `IceLite`.
+#[derive(Debug)]
+pub struct IceTcpListener {
+    listener: TcpListener,
+    ice_lite: Arc<Mutex<IceLite>>,
+    connections: Connections,
+    idle_timeout: Duration,
+    max_connections: usize,
+}
+
+impl IceTcpListener {
+    /// Listen on `address`, e.g. the address of a passive candidate.
+    pub async fn bind(address: SocketAddr, ice_lite: Arc<Mutex<IceLite>>) -> Result<Self> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| Error::Startup(e.to_string()))?;
+
+        Ok(Self {
+            listener,
+            ice_lite,
+            connections: Connections::default(),
+            idle_timeout: IDLE_TIMEOUT,
+            max_connections: MAX_CONNECTIONS,
+        })
+    }
+
+    /// Close connections that receive nothing for `idle_timeout`, or whose
+    /// first check doesn't succeed within it.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Close new connections while `max_connections` are open.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|e| Error::Startup(e.to_string()))
+    }
+
+    /// Accept connections until the future is dropped, which also closes the
+    /// open connections.  Frames that aren't STUN go to `sink`, with the
+    /// listener index 0.
+    pub async fn run(&self, sink: impl Sink + 'static) -> Result<()> {
+        let sink: Arc<Mutex<dyn Sink>> = Arc::new(Mutex::new(sink));
+        let mut connections = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        // e.g. out of file descriptors, which shouldn't stop the listener
+                        Err(e) => {
+                            log::warn!("error accepting an ICE-TCP connection: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    {
+                        let mut open = self.connections.lock().unwrap();
+
+                        if open.len() >= self.max_connections {
+                            log::warn!("too many ICE-TCP connections, closing the one from {}", peer);
+                            continue;
+                        }
+
+                        open.insert(peer, sender);
+                    }
+
+                    connections.spawn(connection(
+                        stream,
+                        peer,
+                        receiver,
+                        self.ice_lite.clone(),
+                        sink.clone(),
+                        self.connections.clone(),
+                        self.idle_timeout,
+                    ));
+                }
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+    }
+
+    /// Frame `contents` and send it on the connection from `destination`.
+    pub fn send_to(&self, contents: &[u8], destination: SocketAddr) -> Result<()> {
+        let frame = frame(contents)?;
+
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&destination)
+            .ok_or_else(|| Error::Send(format!("no ICE-TCP connection from {}", destination)))?
+            .send(frame)
+            .map_err(|_| Error::Send(format!("the connection from {} closed", destination)))
+    }
+}
+
+async fn connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    outgoing: mpsc::UnboundedReceiver<Bytes>,
+    ice_lite: Arc<Mutex<IceLite>>,
+    sink: Arc<Mutex<dyn Sink>>,
+    connections: Connections,
+    idle_timeout: Duration,
+) {
+    match serve(stream, peer, outgoing, ice_lite, sink, idle_timeout).await {
+        Ok(()) => log::debug!("ICE-TCP connection from {} closed", peer),
+        Err(e) => log::debug!("closing the ICE-TCP connection from {}: {}", peer, e),
+    }
+
+    connections.lock().unwrap().remove(&peer);
+}
+
+/// Answer the checks framed on the connection and write the application's
+/// frames, until it's closed or idle.
+async fn serve(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    mut outgoing: mpsc::UnboundedReceiver<Bytes>,
+    ice_lite: Arc<Mutex<IceLite>>,
+    sink: Arc<Mutex<dyn Sink>>,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let (mut reader, mut writer) = stream.split();
+    let mut framer = Framer::new();
+    let mut buf = [0u8; 4096];
+    let mut deadline = Instant::now() + idle_timeout;
+    let mut checked = false;
+
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                let bytes_received = read.map_err(|e| Error::Receive(e.to_string()))?;
+
+                if bytes_received == 0 {
+                    return Ok(());
+                }
+
+                framer.extend(&buf[..bytes_received]);
+
+                while let Some(contents) = framer.next_frame() {
+                    let handled = ice_lite.lock().unwrap().handle(&contents, peer);
+
+                    // a bad message doesn't lose the framing, so once a check
+                    // succeeded only it's dropped
+                    let handled = match (handled, checked) {
+                        (Ok(Handled::Reply { destination, contents }), false) => {
+                            checked = succeeded(&contents);
+                            Handled::Reply { destination, contents }
+                        }
+                        (Ok(handled), true) => handled,
+                        (Ok(_), false) => {
+                            return Err(Error::Receive("no connectivity check".into()))
+                        }
+                        (Err(e), false) => return Err(e),
+                        (Err(e), true) => {
+                            log::warn!("dropping a frame from {}: {}", peer, e);
+                            continue;
+                        }
+                    };
+
+                    if checked {
+                        deadline = Instant::now() + idle_timeout;
+                    }
+
+                    match handled {
+                        // responses always go back on the connection
+                        Handled::Reply { contents, .. } => writer
+                            .write_all(&frame(&contents)?)
+                            .await
+                            .map_err(|e| Error::Send(e.to_string()))?,
+                        Handled::NoReply => {}
+                        Handled::NotStun => sink.lock().unwrap().receive(Datagram {
+                            kind: Kind::of(&contents),
+                            listener: 0,
+                            source: peer,
+                            contents,
+                        }),
+                    }
+                }
+            }
+            Some(frame) = outgoing.recv() => {
+                writer
+                    .write_all(&frame)
+                    .await
+                    .map_err(|e| Error::Send(e.to_string()))?;
+            }
+            _ = time::sleep_until(deadline) => {
+                return Err(Error::Timeout(peer.to_string()));
+            }
+        }
+    }
+}
+
+/// Whether a response is a success, i.e. the check was authenticated.
+fn succeeded(response: &Bytes) -> bool {
+    Message::decode(&mut response.clone())
+        .map(|message| message.class == Class::SuccessResponse)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{attribute::Attribute, fingerprint, integrity, tests::BINDING_REQUEST};
+
+    #[test]
+    fn it_frames_split_and_coalesced_packets() {
+        let mut framer = Framer::new();
+        let mut stream = frame(BINDING_REQUEST).unwrap().repeat(2);
+        stream.extend_from_slice(&frame(&[128, 1, 2]).unwrap());
+        stream.extend_from_slice(&frame(&[]).unwrap());
+
+        framer.extend(&stream[..1]);
+        assert_eq!(framer.next_frame(), None);
+
+        framer.extend(&stream[1..30]);
+        assert_eq!(framer.next_frame().unwrap(), &BINDING_REQUEST[..]);
+        assert_eq!(framer.next_frame(), None);
+
+        framer.extend(&stream[30..]);
+        assert_eq!(framer.next_frame().unwrap(), &BINDING_REQUEST[..]);
+        assert_eq!(framer.next_frame().unwrap(), &[128, 1, 2][..]);
+        assert_eq!(framer.next_frame().unwrap(), &[][..]);
+        assert_eq!(framer.next_frame(), None);
+    }
+
+    #[test]
+    fn it_rejects_packets_too_long_to_frame() {
+        assert_eq!(frame(&[0; MAX_FRAME_LENGTH]).unwrap().len(), 65_537);
+        assert!(matches!(
+            frame(&[0; MAX_FRAME_LENGTH + 1]),
+            Err(Error::Send(_))
+        ));
+    }
+
+    const PASSWORD: &[u8] = b"asd88fgpdd777uzjYhagZg";
+
+    /// A running listener with one session, and the frames it passes on.
+    async fn listen(
+        idle_timeout: Duration,
+        max_connections: usize,
+    ) -> (Arc<IceTcpListener>, mpsc::UnboundedReceiver<Datagram>) {
+        let mut ice_lite = IceLite::new();
+        ice_lite.add_session("sfu1", "asd88fgpdd777uzjYhagZg", "peer");
+        let mut listener = IceTcpListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(Mutex::new(ice_lite)),
+        )
+        .await
+        .unwrap();
+        listener.set_idle_timeout(idle_timeout);
+        listener.set_max_connections(max_connections);
+
+        let listener = Arc::new(listener);
+        let (sender, media) = mpsc::unbounded_channel();
+        let running = listener.clone();
+        tokio::spawn(async move {
+            running
+                .run(move |datagram: Datagram| sender.send(datagram).unwrap())
+                .await
+        });
+
+        (listener, media)
+    }
+
+    /// A framed, authenticated connectivity check.
+    fn check() -> Vec<u8> {
+        let mut request = BytesMut::new();
+        Message::binding_request(vec![
+            Attribute::Username("sfu1:peer"),
+            Attribute::Priority(0x6e0001ff),
+            Attribute::IceControlling(1),
+        ])
+        .encode(&mut request)
+        .unwrap();
+        integrity::sign(&mut request, PASSWORD);
+        fingerprint::append(&mut request);
+
+        frame(&request).unwrap().to_vec()
+    }
+
+    async fn next_frame(stream: &mut TcpStream) -> Bytes {
+        let mut framer = Framer::new();
+        let mut buf = [0u8; 1024];
+
+        loop {
+            let bytes_received = stream.read(&mut buf).await.unwrap();
+            framer.extend(&buf[..bytes_received]);
+
+            if let Some(frame) = framer.next_frame() {
+                return frame;
+            }
+        }
+    }
+
+    /// Whether the listener closes the connection within a second.
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 1024];
+        let read = time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
+
+        matches!(read, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn it_answers_checks_and_passes_media_on_a_passive_listener() {
+        let (listener, mut media) = listen(IDLE_TIMEOUT, MAX_CONNECTIONS).await;
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let peer = stream.local_addr().unwrap();
+
+        let mut sent = check();
+        sent.extend_from_slice(&frame(&[128, 1, 2]).unwrap());
+        stream.write_all(&sent).await.unwrap();
+
+        let mut response = next_frame(&mut stream).await;
+        assert!(integrity::verify(&response, PASSWORD));
+
+        let message = Message::decode(&mut response).unwrap();
+        assert_eq!(message.class, Class::SuccessResponse);
+        assert_eq!(message.mapped_address(), Some(peer));
+
+        let datagram = media.recv().await.unwrap();
+        assert_eq!(datagram.kind, Kind::Rtp);
+        assert_eq!(datagram.source, peer);
+        assert_eq!(datagram.contents, &[128, 1, 2][..]);
+
+        listener.send_to(&[128, 3], peer).unwrap();
+        assert_eq!(next_frame(&mut stream).await, &[128, 3][..]);
+
+        assert!(matches!(
+            listener.send_to(&[128, 3], "127.0.0.1:9".parse().unwrap()),
+            Err(Error::Send(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_closes_connections_that_do_not_start_with_a_check() {
+        let (listener, _media) = listen(IDLE_TIMEOUT, MAX_CONNECTIONS).await;
+        let address = listener.local_addr().unwrap();
+
+        // a TURN Allocate request, then media
+        let mut allocate = BINDING_REQUEST.to_vec();
+        allocate[1] = 0x03;
+
+        for first in [frame(&allocate).unwrap(), frame(&[128, 1, 2]).unwrap()] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(&first).await.unwrap();
+
+            assert!(is_closed(&mut stream).await);
+        }
+
+        // which doesn't stop the next connection's checks being answered
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&check()).await.unwrap();
+        let mut response = next_frame(&mut stream).await;
+
+        assert_eq!(
+            Message::decode(&mut response).unwrap().class,
+            Class::SuccessResponse
+        );
+    }
+
+    #[tokio::test]
+    async fn it_closes_idle_connections() {
+        let (listener, _media) = listen(Duration::from_millis(100), MAX_CONNECTIONS).await;
+        let address = listener.local_addr().unwrap();
+
+        let mut silent = TcpStream::connect(address).await.unwrap();
+        assert!(is_closed(&mut silent).await);
+
+        let mut checked = TcpStream::connect(address).await.unwrap();
+        checked.write_all(&check()).await.unwrap();
+        next_frame(&mut checked).await;
+        assert!(is_closed(&mut checked).await);
+    }
+
+    #[tokio::test]
+    async fn it_caps_the_open_connections() {
+        let (listener, _media) = listen(IDLE_TIMEOUT, 1).await;
+        let address = listener.local_addr().unwrap();
+
+        let mut open = TcpStream::connect(address).await.unwrap();
+        open.write_all(&check()).await.unwrap();
+        next_frame(&mut open).await;
+
+        let mut refused = TcpStream::connect(address).await.unwrap();
+        assert!(is_closed(&mut refused).await);
+
+        open.write_all(&check()).await.unwrap();
+        next_frame(&mut open).await;
+    }
+}
@@ -1,5 +1,12 @@
+#[cfg(any(feature = "async", feature = "blocking"))]
+pub mod client;
 pub mod config;
 pub mod error;
+pub mod ice;
 pub mod message;
+#[cfg(feature = "async")]
+pub mod nat;
+pub mod protocol;
+#[cfg(feature = "async")]
 pub mod server;
 pub mod utils;
@@ -0,0 +1,574 @@
+//! The `stun-server` command line.
+//!
+//! Configuration is layered: a config file (`--config`, or .env) is read
+//! first, then environment variables, and finally command line flags, each
+//! overriding the last.  A `.toml`, `.yaml` or `.yml` file is read as a
+//! `ConfigFile` with any number of listeners, anything else as dotenv.
+//!
+//! On SIGHUP, `serve` re-reads a TOML or YAML config and applies what can
+//! change without rebinding: the external addresses, TLS and DTLS
+//! certificates and the log level.  The sockets are kept, and a config that
+//! is invalid or moves a listener is rejected with the running config left
+//! in place.
+
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand};
+use log::{Log, Metadata, Record};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+use stun_server::{
+    client::{RetransmissionPolicy, StunClient},
+    config::{
+        file::{ConfigFile, ListenerConfig, Overrides, Transport},
+        Config,
+    },
+    error::{Error, Result},
+    message::Message,
+    nat,
+    server::{ReloadHandle, Server},
+};
+use tokio::{net::UdpSocket, task::JoinSet};
+
+#[derive(Debug, Parser)]
+#[clap(name = "stun-server", version, about)]
+struct Cli {
+    /// Read configuration from this TOML, YAML or dotenv file instead of .env
+    #[clap(long, global = true, value_parser)]
+    config: Option<PathBuf>,
+
+    /// Log filter (e.g. info or stun_server=debug), overriding RUST_LOG
+    #[clap(long, global = true, value_parser)]
+    log_level: Option<String>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the STUN server
+    Serve(ServeArgs),
+    /// Query a server and print the mapped address
+    Client(ClientArgs),
+    /// Classify the NAT between this host and an RFC 5780 server
+    NatDetect(ClientArgs),
+    /// Decode a hex encoded STUN message
+    Decode {
+        #[clap(value_parser)]
+        hex: String,
+    },
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// The primary address to listen on.  Repeat it to run a listener on each
+    /// address instead of the configured ones
+    #[clap(long, value_parser)]
+    listen: Vec<SocketAddr>,
+
+    /// A second address for RFC 5780 NAT behavior discovery
+    #[clap(long, value_parser)]
+    alternate: Option<SocketAddr>,
+
+    /// The public IP of the primary address when behind 1:1 NAT
+    #[clap(long, value_parser)]
+    external_address: Option<IpAddr>,
+
+    /// The public IP of the alternate address when behind 1:1 NAT
+    #[clap(long, value_parser)]
+    alternate_external_address: Option<IpAddr>,
+
+    /// An address to serve STUN over TLS on (needs the tls feature)
+    #[clap(long, value_parser)]
+    tls_listen: Option<SocketAddr>,
+
+    /// The PEM certificate chain for --tls-listen
+    #[clap(long, value_parser)]
+    tls_certificate: Option<PathBuf>,
+
+    /// The PEM private key for --tls-listen
+    #[clap(long, value_parser)]
+    tls_private_key: Option<PathBuf>,
+
+    /// An address to serve STUN over DTLS on (needs the dtls feature)
+    #[clap(long, value_parser)]
+    dtls_listen: Option<SocketAddr>,
+
+    /// The PEM certificate chain for --dtls-listen
+    #[clap(long, value_parser)]
+    dtls_certificate: Option<PathBuf>,
+
+    /// The PKCS#8 PEM private key for --dtls-listen
+    #[clap(long, value_parser)]
+    dtls_private_key: Option<PathBuf>,
+
+    /// The transport to serve
+    #[clap(long, value_parser)]
+    transport: Option<Transport>,
+
+    /// Validate the configuration and exit without starting
+    #[clap(long)]
+    check_config: bool,
+}
+
+#[derive(Debug, Args)]
+struct ClientArgs {
+    /// The server to query, overriding the config's `server`
+    #[clap(long, value_parser)]
+    server: Option<SocketAddr>,
+
+    /// The local address to bind, overriding the config's `client`
+    #[clap(long, value_parser)]
+    bind: Option<SocketAddr>,
+
+    /// How long to wait for each response, in milliseconds
+    #[clap(long, value_parser, default_value = "3000")]
+    timeout: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut config = load(cli.config.as_deref())?;
+
+    match &cli.command {
+        Command::Serve(args) => {
+            apply_serve_args(&mut config, args)?;
+            config.validate()?;
+
+            if args.check_config {
+                println!("configuration ok: {} listener(s)", config.listeners.len());
+                return Ok(());
+            }
+
+            let logger = Logger::init(&filters(cli.log_level.as_deref(), &config));
+            serve(&cli, args, config, logger).await
+        }
+        Command::Client(args) => {
+            Logger::init(&filters(cli.log_level.as_deref(), &config));
+
+            let (bind, server) = client_addresses(&config, args)?;
+            let socket = UdpSocket::bind(bind)
+                .await
+                .map_err(|e| Error::Startup(e.to_string()))?;
+            let policy = RetransmissionPolicy::with_timeout(Duration::from_millis(args.timeout));
+            let client = StunClient::new(socket, policy);
+            let mapped_address = client.binding(server).await?;
+
+            println!("{}", mapped_address);
+            Ok(())
+        }
+        Command::NatDetect(args) => {
+            Logger::init(&filters(cli.log_level.as_deref(), &config));
+
+            let (bind, server) = client_addresses(&config, args)?;
+            let socket = UdpSocket::bind(bind)
+                .await
+                .map_err(|e| Error::Startup(e.to_string()))?;
+            let timeout = Duration::from_millis(args.timeout);
+            let report = nat::detect(&socket, server, timeout).await?;
+
+            println!("{:#?}", report);
+            Ok(())
+        }
+        Command::Decode { hex } => {
+            let mut bytes = Bytes::from(decode_hex(hex)?);
+
+            println!("{:#?}", Message::decode(&mut bytes)?);
+            Ok(())
+        }
+    }
+}
+
+/// Load the file layer and apply environment overrides on top of it.
+fn load(path: Option<&Path>) -> Result<ConfigFile> {
+    match path {
+        Some(path) if structured(path) => {
+            let mut config = ConfigFile::from_path(path)?;
+            config.apply(&Overrides::from_env()?)?;
+            Ok(config)
+        }
+        Some(path) => ConfigFile::try_from(&Config::from_dotenv_file(path)?),
+        None => ConfigFile::try_from(&Config::from_dotenv()?),
+    }
+}
+
+fn structured(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| matches!(extension, "toml" | "yaml" | "yml"))
+}
+
+/// `--log-level` wins over `RUST_LOG`, which wins over the config file.
+fn filters(log_level: Option<&str>, config: &ConfigFile) -> String {
+    log_level
+        .map(String::from)
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .unwrap_or_else(|| config.logging.level.clone())
+}
+
+/// A logger whose filters can be replaced while running.
+struct Logger(RwLock<Box<dyn Log>>);
+
+impl Logger {
+    fn init(filters: &str) -> &'static Logger {
+        let logger: &'static Logger =
+            Box::leak(Box::new(Logger(RwLock::new(Box::new(NoopLogger)))));
+
+        logger.reload(filters);
+        log::set_logger(logger).expect("the logger is only initialized once");
+
+        logger
+    }
+
+    fn reload(&self, filters: &str) {
+        let logger = pretty_env_logger::formatted_builder()
+            .parse_filters(filters)
+            .build();
+
+        log::set_max_level(logger.filter());
+        *self.0.write().unwrap() = Box::new(logger);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+struct NoopLogger;
+
+impl Log for NoopLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        false
+    }
+
+    fn log(&self, _: &Record) {}
+
+    fn flush(&self) {}
+}
+
+/// Start a server per listener, returning when the first one stops.  Each
+/// SIGHUP reloads the config.
+async fn serve(cli: &Cli, args: &ServeArgs, config: ConfigFile, logger: &Logger) -> Result<()> {
+    let mut servers = JoinSet::new();
+    let mut reload_handles = vec![];
+    let mut hangup = Hangup::new()?;
+    let mut config = config;
+
+    for config in config.configs() {
+        let server = Server::builder().config(config).start().await?;
+        reload_handles.push(server.reload_handle());
+        servers.spawn(server.join());
+    }
+
+    loop {
+        tokio::select! {
+            result = servers.join_next() => {
+                return match result {
+                    Some(result) => result.map_err(|e| Error::Receive(e.to_string()))?,
+                    None => Ok(()),
+                };
+            }
+            _ = hangup.recv() => {
+                let reloaded = reload(cli.config.as_deref(), args, &config).and_then(|reloaded| {
+                    ReloadHandle::reload_all(&reload_handles, &reloaded.configs())?;
+
+                    Ok(reloaded)
+                });
+
+                match reloaded {
+                    Ok(reloaded) => {
+                        logger.reload(&filters(cli.log_level.as_deref(), &reloaded));
+
+                        let changes = config.diff(&reloaded);
+                        log::info!("Reloaded the configuration, {} change(s)", changes.len());
+                        changes.iter().for_each(|change| log::info!("  {}", change));
+
+                        config = reloaded;
+                    }
+                    Err(e) => log::error!("Kept the running configuration: {}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Read the config again, checking it only changes what can be applied to
+/// the running listeners.
+fn reload(path: Option<&Path>, args: &ServeArgs, running: &ConfigFile) -> Result<ConfigFile> {
+    let path = path.filter(|path| structured(path)).ok_or_else(|| {
+        Error::Config("reloading needs a TOML or YAML file passed to --config".into())
+    })?;
+    let mut config = load(Some(path))?;
+    apply_serve_args(&mut config, args)?;
+    config.validate()?;
+
+    if config.listeners.len() != running.listeners.len() {
+        return Err(Error::Config(format!(
+            "listeners: changed from {} to {}, restart to apply",
+            running.listeners.len(),
+            config.listeners.len()
+        )));
+    }
+
+    let sockets = |listener: &ListenerConfig| {
+        let tls_address = listener.tls.as_ref().map(|tls| tls.address);
+        let dtls_address = listener.dtls.as_ref().map(|dtls| dtls.address);
+        (
+            listener.address,
+            listener.alternate,
+            listener.transport,
+            tls_address,
+            dtls_address,
+        )
+    };
+
+    for (index, (old, new)) in running.listeners.iter().zip(&config.listeners).enumerate() {
+        if sockets(old) != sockets(new) {
+            return Err(Error::Config(format!(
+                "listeners[{}]: the address, alternate, transport, tls.address or dtls.address changed, restart to apply",
+                index
+            )));
+        }
+    }
+
+    Ok(config)
+}
+
+/// Resolves on each SIGHUP.  Other platforms have no SIGHUP, so it never
+/// resolves there.
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|e| Error::Startup(e.to_string()))?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.signal.recv().await.is_some() {
+            return;
+        }
+
+        std::future::pending().await
+    }
+}
+
+/// Apply the serve flags on top of `config`.  Several --listen addresses
+/// replace the configured listeners, one each, and then the other listener
+/// flags can't apply.
+fn apply_serve_args(config: &mut ConfigFile, args: &ServeArgs) -> Result<()> {
+    if args.listen.len() > 1 {
+        config.listeners = args
+            .listen
+            .iter()
+            .copied()
+            .map(ListenerConfig::new)
+            .collect();
+    }
+
+    config.apply(&serve_overrides(args))
+}
+
+fn serve_overrides(args: &ServeArgs) -> Overrides {
+    let server = match args.listen.as_slice() {
+        [listen] => Some(*listen),
+        _ => None,
+    };
+
+    Overrides {
+        server,
+        alternate_server: args.alternate,
+        external_address: args.external_address,
+        alternate_external_address: args.alternate_external_address,
+        transport: args.transport,
+        tls_server: args.tls_listen,
+        tls_certificate: args.tls_certificate.clone(),
+        tls_private_key: args.tls_private_key.clone(),
+        dtls_server: args.dtls_listen,
+        dtls_certificate: args.dtls_certificate.clone(),
+        dtls_private_key: args.dtls_private_key.clone(),
+        ..Overrides::default()
+    }
+}
+
+/// The local address to bind and the server to query, which defaults to
+/// the first configured listener.
+fn client_addresses(config: &ConfigFile, args: &ClientArgs) -> Result<(SocketAddr, SocketAddr)> {
+    let server = args
+        .server
+        .or_else(|| config.listeners.first().map(|listener| listener.address))
+        .ok_or_else(|| Error::Arguments("--server is required without a listener".into()))?;
+
+    Ok((args.bind.unwrap_or(config.client), server))
+}
+
+/// Parse hex, ignoring whitespace and an optional 0x prefix.
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex: String = hex.split_whitespace().collect();
+    let hex = hex.strip_prefix("0x").unwrap_or(&hex);
+
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::Arguments(format!(
+            "hex must have an even number of digits, but got {}",
+            hex.len()
+        )));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .map_err(|e| Error::Arguments(format!("invalid hex: {}", e)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn it_parses_the_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn it_overrides_the_config_with_flags() {
+        let cli = Cli::parse_from([
+            "stun-server",
+            "serve",
+            "--listen",
+            "127.0.0.1:3478",
+            "--alternate",
+            "127.0.0.2:3479",
+        ]);
+        let mut config = ConfigFile::try_from(&Config::default()).unwrap();
+
+        match cli.command {
+            Command::Serve(args) => apply_serve_args(&mut config, &args).unwrap(),
+            command => panic!("unexpected command {:?}", command),
+        };
+
+        let configs = config.configs();
+        assert_eq!(configs[0].server, "127.0.0.1:3478");
+        assert_eq!(
+            configs[0].alternate_server.as_deref(),
+            Some("127.0.0.2:3479")
+        );
+    }
+
+    #[test]
+    fn it_runs_a_listener_per_listen_flag() {
+        let cli = Cli::parse_from([
+            "stun-server",
+            "serve",
+            "--listen",
+            "127.0.0.1:3478",
+            "--listen",
+            "127.0.0.1:3479",
+        ]);
+        let mut config = ConfigFile::try_from(&Config::default()).unwrap();
+
+        match &cli.command {
+            Command::Serve(args) => apply_serve_args(&mut config, args).unwrap(),
+            command => panic!("unexpected command {:?}", command),
+        };
+
+        let servers: Vec<_> = config.configs().into_iter().map(|c| c.server).collect();
+        assert_eq!(servers, ["127.0.0.1:3478", "127.0.0.1:3479"]);
+
+        let cli = Cli::parse_from([
+            "stun-server",
+            "serve",
+            "--listen",
+            "127.0.0.1:3478",
+            "--listen",
+            "127.0.0.1:3479",
+            "--alternate",
+            "127.0.0.2:3479",
+        ]);
+
+        match &cli.command {
+            Command::Serve(args) => assert!(apply_serve_args(&mut config, args).is_err()),
+            command => panic!("unexpected command {:?}", command),
+        };
+    }
+
+    #[test]
+    fn it_queries_the_first_listener_without_flags() {
+        let cli = Cli::parse_from(["stun-server", "client"]);
+        let config = ConfigFile::try_from(&Config::default()).unwrap();
+        let (bind, server) = match cli.command {
+            Command::Client(args) => client_addresses(&config, &args).unwrap(),
+            command => panic!("unexpected command {:?}", command),
+        };
+
+        assert_eq!(bind, config.client);
+        assert_eq!(server, config.listeners[0].address);
+    }
+
+    #[test]
+    fn it_loads_a_structured_config_file() {
+        let path = std::env::temp_dir().join(format!("stun-server-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[listeners]]\naddress = \"127.0.0.1:0\"\n").unwrap();
+
+        let config = load(Some(&path));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(config.unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn it_only_reloads_what_keeps_the_sockets() {
+        let path = std::env::temp_dir().join(format!("stun-server-{}.yaml", std::process::id()));
+        let args = match Cli::parse_from(["stun-server", "serve"]).command {
+            Command::Serve(args) => args,
+            command => panic!("unexpected command {:?}", command),
+        };
+        let running = ConfigFile::from_yaml("listeners: [{address: 127.0.0.1:3478}]").unwrap();
+
+        let reload_from = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            reload(Some(&path), &args, &running)
+        };
+
+        let reloaded =
+            reload_from("listeners: [{address: 127.0.0.1:3478, external_address: 203.0.113.7}]");
+        assert_eq!(reloaded.unwrap().diff(&running).len(), 1);
+        assert!(reload_from("listeners: [{address: 127.0.0.1:3479}]").is_err());
+        assert!(reload_from("listeners: []").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(reload(None, &args, &running).is_err());
+    }
+
+    #[test]
+    fn it_decodes_hex() {
+        assert_eq!(decode_hex("0x0001 0000").unwrap(), vec![0, 1, 0, 0]);
+        assert!(decode_hex("000").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+}
@@ -30,57 +30,504 @@
 //! attributes, which means that those attributes can be ignored by the STUN agent if
 //! it does not understand them.
 
-// use std::convert::{TryFrom, TryInto};
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::message::transaction_id::TransactionId;
-use crate::utils::Address;
-use bytes::{Bytes, BytesMut};
+use crate::message::MAGIC_COOKIE;
+use crate::utils::{Address, IPKind};
+use bytes::{BufMut, BytesMut};
+
+pub(crate) const MAPPED_ADDRESS: u16 = 0x0001;
+pub(crate) const CHANGE_REQUEST: u16 = 0x0003;
+pub(crate) const USERNAME: u16 = 0x0006;
+pub(crate) const PASSWORD: u16 = 0x0007;
+pub(crate) const MESSAGE_INTEGRITY: u16 = 0x0008;
+pub(crate) const ERROR_CODE: u16 = 0x0009;
+pub(crate) const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+pub(crate) const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub(crate) const PRIORITY: u16 = 0x0024;
+pub(crate) const USE_CANDIDATE: u16 = 0x0025;
+pub(crate) const PADDING: u16 = 0x0026;
+pub(crate) const RESPONSE_PORT: u16 = 0x0027;
+pub(crate) const FINGERPRINT: u16 = 0x8028;
+pub(crate) const ICE_CONTROLLED: u16 = 0x8029;
+pub(crate) const ICE_CONTROLLING: u16 = 0x802A;
+pub(crate) const RESPONSE_ORIGIN: u16 = 0x802B;
+pub(crate) const OTHER_ADDRESS: u16 = 0x802C;
+
+/// Types from here up are comprehension-optional, and can be ignored.
+pub(crate) const COMPREHENSION_OPTIONAL: u16 = 0x8000;
+
+/// Each attribute starts with a 16-bit type and a 16-bit length.
+pub(crate) const ATTRIBUTE_HEADER_LENGTH: usize = 4;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+// CHANGE-REQUEST flags (RFC 5780 section 7.2)
+const CHANGE_IP: u32 = 0x4;
+const CHANGE_PORT: u32 = 0x2;
 
 #[derive(Eq, PartialEq, Debug)]
 pub enum Attribute<'a> {
+    MappedAddress(Address),
+    ChangeRequest {
+        change_ip: bool,
+        change_port: bool,
+    },
     Username(&'a str),
     Password(&'a str),
-    ErrorCode { code: u32, reason: &'a str },
-    FingerPrint(&'a str),
+    /// The HMAC-SHA1 of the message, see `message::integrity`.
+    MessageIntegrity([u8; 20]),
+    ErrorCode {
+        code: u32,
+        reason: &'a str,
+    },
+    /// The CRC-32 of the message, see `message::fingerprint`.
+    FingerPrint(u32),
     XorMappedAddress(Address),
     UnknownAttributes(Vec<u16>),
+    ResponseOrigin(Address),
+    OtherAddress(Address),
+    ResponsePort(u16),
+    /// The number of free-form bytes used to pad the message.
+    Padding(u16),
+    /// The priority of the peer reflexive candidate a connectivity check
+    /// would discover (RFC 8445 section 7.1.1).
+    Priority(u32),
+    /// The controlling agent nominates the pair being checked.
+    UseCandidate,
+    /// The sender thinks it's the controlled agent, with its tiebreaker.
+    IceControlled(u64),
+    /// The sender thinks it's the controlling agent, with its tiebreaker.
+    IceControlling(u64),
+    /// An attribute of a type this crate doesn't know, with its value.
+    Unknown {
+        kind: u16,
+        value: &'a [u8],
+    },
 }
 
-// impl<'a> From<&mut BytesMut> for Attribute<'a> {
-//     fn from(buffer: &mut BytesMut) -> Attribute<'a> {
-//         let code = buffer.get_u16();
-//         let _message_length = buffer.get_u16();
-//         let value_32: &[u8] = &[
-//             buffer.get_u8().to_be_bytes(),
-//             buffer.get_u8().to_be_bytes(),
-//             buffer.get_u8().to_be_bytes(),
-//             buffer.get_u8().to_be_bytes(),
-//         ]
-//         .concat();
-//         let value = String::from_utf8(value_32.to_vec()).unwrap().clone();
-
-//         match code {
-//             0x0006 => Attribute::Username(&value.clone()),
-//             0x0007 => Attribute::Password(&value),
-//             0x0009 => Attribute::ErrorCode {
-//                 code: 0,
-//                 reason: &value,
-//             },
-//             0x000A => Attribute::UnknownAttributes(vec![code]),
-//             0x0020 => Attribute::XorMappedAddress(Address::try_from(value.as_str()).unwrap()),
-//             0x8028 => Attribute::FingerPrint(&value),
-//             _ => Attribute::UnknownAttributes(vec![code]),
-//         }
-//     }
-// }
-
-// TODO(ddimaria): implement
 impl<'a> Attribute<'a> {
-    pub(crate) fn encode(&self, _buffer: &mut BytesMut, _transaction_id: &TransactionId) -> u16 {
-        0
+    /// Encode the attribute (type, length, value and padding) into the buffer
+    /// and return the number of bytes written.  Errors, writing nothing, when
+    /// the value is too long for the 16-bit length.
+    pub(crate) fn encode(
+        &self,
+        buffer: &mut BytesMut,
+        transaction_id: &TransactionId,
+    ) -> Result<usize> {
+        let (kind, value) = self.value(transaction_id);
+
+        if value.len() > u16::MAX as usize {
+            return Err(Error::Encode(format!(
+                "a {:#06x} attribute of {} bytes is too long",
+                kind,
+                value.len()
+            )));
+        }
+
+        let padding = padding(value.len());
+
+        buffer.put_u16(kind);
+        buffer.put_u16(value.len() as u16);
+        buffer.put_slice(value.as_ref());
+        buffer.put_bytes(0, padding);
+
+        Ok(ATTRIBUTE_HEADER_LENGTH + value.len() + padding)
+    }
+
+    /// The number of bytes `encode` writes.
+    pub(crate) fn encoded_length(&self, transaction_id: &TransactionId) -> usize {
+        let (_, value) = self.value(transaction_id);
+
+        ATTRIBUTE_HEADER_LENGTH + value.len() + padding(value.len())
+    }
+
+    /// The type and the encoded value, without padding.
+    fn value(&self, transaction_id: &TransactionId) -> (u16, BytesMut) {
+        let mut value = BytesMut::new();
+
+        let kind = match self {
+            Attribute::MappedAddress(address) => {
+                encode_address(&mut value, address);
+                MAPPED_ADDRESS
+            }
+            Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            } => {
+                let mut flags = 0;
+
+                if *change_ip {
+                    flags |= CHANGE_IP;
+                }
+
+                if *change_port {
+                    flags |= CHANGE_PORT;
+                }
+
+                value.put_u32(flags);
+                CHANGE_REQUEST
+            }
+            Attribute::Username(username) => {
+                value.put_slice(username.as_bytes());
+                USERNAME
+            }
+            Attribute::Password(password) => {
+                value.put_slice(password.as_bytes());
+                PASSWORD
+            }
+            Attribute::MessageIntegrity(hmac) => {
+                value.put_slice(hmac);
+                MESSAGE_INTEGRITY
+            }
+            Attribute::ErrorCode { code, reason } => {
+                // the hundreds digit is the class and the rest is the number
+                value.put_u16(0);
+                value.put_u8((code / 100) as u8);
+                value.put_u8((code % 100) as u8);
+                value.put_slice(reason.as_bytes());
+                ERROR_CODE
+            }
+            Attribute::FingerPrint(crc) => {
+                value.put_u32(*crc);
+                FINGERPRINT
+            }
+            Attribute::XorMappedAddress(address) => {
+                encode_address(&mut value, &xor_address(address, transaction_id));
+                XOR_MAPPED_ADDRESS
+            }
+            Attribute::UnknownAttributes(kinds) => {
+                kinds.iter().for_each(|kind| value.put_u16(*kind));
+                UNKNOWN_ATTRIBUTES
+            }
+            Attribute::ResponseOrigin(address) => {
+                encode_address(&mut value, address);
+                RESPONSE_ORIGIN
+            }
+            Attribute::OtherAddress(address) => {
+                encode_address(&mut value, address);
+                OTHER_ADDRESS
+            }
+            Attribute::ResponsePort(port) => {
+                value.put_u16(*port);
+                value.put_u16(0);
+                RESPONSE_PORT
+            }
+            Attribute::Padding(length) => {
+                value.put_bytes(0, *length as usize);
+                PADDING
+            }
+            Attribute::Priority(priority) => {
+                value.put_u32(*priority);
+                PRIORITY
+            }
+            Attribute::UseCandidate => USE_CANDIDATE,
+            Attribute::IceControlled(tiebreaker) => {
+                value.put_u64(*tiebreaker);
+                ICE_CONTROLLED
+            }
+            Attribute::IceControlling(tiebreaker) => {
+                value.put_u64(*tiebreaker);
+                ICE_CONTROLLING
+            }
+            Attribute::Unknown { kind, value: bytes } => {
+                value.put_slice(bytes);
+                *kind
+            }
+        };
+
+        (kind, value)
     }
 
-    pub(crate) fn decode(_buffer: &Bytes, _transaction_id: &TransactionId) -> Result<Self> {
-        Ok(Attribute::UnknownAttributes(vec![]))
+    /// Decode a single attribute from the front of the buffer, advancing the
+    /// buffer past the attribute and its padding.
+    pub(crate) fn decode(buffer: &mut &'a [u8], transaction_id: &TransactionId) -> Result<Self> {
+        if buffer.len() < ATTRIBUTE_HEADER_LENGTH {
+            return Err(Error::Decode(format!(
+                "Not enough bytes in the attribute header.  Expected {}, but got {}",
+                ATTRIBUTE_HEADER_LENGTH,
+                buffer.len()
+            )));
+        }
+
+        let kind = u16::from_be_bytes([buffer[0], buffer[1]]);
+        let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        let padded_length = length + padding(length);
+
+        if buffer.len() < ATTRIBUTE_HEADER_LENGTH + length {
+            return Err(Error::Decode(format!(
+                "Not enough bytes in attribute {:#06x}.  Expected {}, but got {}",
+                kind,
+                length,
+                buffer.len() - ATTRIBUTE_HEADER_LENGTH
+            )));
+        }
+
+        let value: &'a [u8] = &buffer[ATTRIBUTE_HEADER_LENGTH..ATTRIBUTE_HEADER_LENGTH + length];
+        *buffer = &buffer[(ATTRIBUTE_HEADER_LENGTH + padded_length).min(buffer.len())..];
+
+        let attribute = match kind {
+            MAPPED_ADDRESS => Attribute::MappedAddress(decode_address(value)?),
+            CHANGE_REQUEST => {
+                let flags = u32::from_be_bytes(fixed::<4>(value, kind)?);
+
+                Attribute::ChangeRequest {
+                    change_ip: flags & CHANGE_IP != 0,
+                    change_port: flags & CHANGE_PORT != 0,
+                }
+            }
+            USERNAME => Attribute::Username(decode_str(value)?),
+            PASSWORD => Attribute::Password(decode_str(value)?),
+            MESSAGE_INTEGRITY => Attribute::MessageIntegrity(fixed::<20>(value, kind)?),
+            ERROR_CODE => {
+                if value.len() < 4 {
+                    return Err(Error::Decode(format!(
+                        "Invalid error code length {}",
+                        value.len()
+                    )));
+                }
+
+                Attribute::ErrorCode {
+                    code: (value[2] & 0x7) as u32 * 100 + value[3] as u32,
+                    reason: decode_str(&value[4..])?,
+                }
+            }
+            XOR_MAPPED_ADDRESS => {
+                Attribute::XorMappedAddress(xor_address(&decode_address(value)?, transaction_id))
+            }
+            UNKNOWN_ATTRIBUTES => Attribute::UnknownAttributes(
+                value
+                    .chunks_exact(2)
+                    .map(|kind| u16::from_be_bytes([kind[0], kind[1]]))
+                    .collect(),
+            ),
+            RESPONSE_ORIGIN => Attribute::ResponseOrigin(decode_address(value)?),
+            OTHER_ADDRESS => Attribute::OtherAddress(decode_address(value)?),
+            RESPONSE_PORT => {
+                let value = fixed::<4>(value, kind)?;
+                Attribute::ResponsePort(u16::from_be_bytes([value[0], value[1]]))
+            }
+            PADDING => Attribute::Padding(length as u16),
+            FINGERPRINT => Attribute::FingerPrint(u32::from_be_bytes(fixed::<4>(value, kind)?)),
+            PRIORITY => Attribute::Priority(u32::from_be_bytes(fixed::<4>(value, kind)?)),
+            USE_CANDIDATE => Attribute::UseCandidate,
+            ICE_CONTROLLED => {
+                Attribute::IceControlled(u64::from_be_bytes(fixed::<8>(value, kind)?))
+            }
+            ICE_CONTROLLING => {
+                Attribute::IceControlling(u64::from_be_bytes(fixed::<8>(value, kind)?))
+            }
+            _ => Attribute::Unknown { kind, value },
+        };
+
+        Ok(attribute)
+    }
+}
+
+/// Attributes are padded to a multiple of 4 bytes.
+pub(crate) fn padding(length: usize) -> usize {
+    (4 - length % 4) % 4
+}
+
+fn fixed<const N: usize>(value: &[u8], kind: u16) -> Result<[u8; N]> {
+    value.try_into().map_err(|_| {
+        Error::Decode(format!(
+            "Invalid length for attribute {:#06x}.  Expected {}, but got {}",
+            kind,
+            N,
+            value.len()
+        ))
+    })
+}
+
+fn decode_str(value: &[u8]) -> Result<&str> {
+    std::str::from_utf8(value).map_err(|e| Error::Decode(e.to_string()))
+}
+
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |0 0 0 0 0 0 0 0|    Family     |           Port                |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |                                                               |
+/// |                 Address (32 bits or 128 bits)                 |
+/// |                                                               |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+fn encode_address(buffer: &mut BytesMut, address: &Address) {
+    let family = match address.ip_kind {
+        IPKind::IPv4 => FAMILY_IPV4,
+        IPKind::IPv6 => FAMILY_IPV6,
+    };
+
+    buffer.put_u8(0);
+    buffer.put_u8(family);
+    buffer.put_u16(address.port);
+    buffer.put_slice(&address.address);
+}
+
+fn decode_address(value: &[u8]) -> Result<Address> {
+    if value.len() < 4 {
+        return Err(Error::Decode(format!(
+            "Invalid address length {}",
+            value.len()
+        )));
+    }
+
+    let port = u16::from_be_bytes([value[2], value[3]]);
+
+    match value[1] {
+        FAMILY_IPV4 => Ok(Address::ipv4(
+            fixed::<4>(&value[4..], value[1] as u16)?,
+            port,
+        )),
+        FAMILY_IPV6 => Ok(Address::ipv6(
+            fixed::<16>(&value[4..], value[1] as u16)?,
+            port,
+        )),
+        family => Err(Error::Decode(format!("Invalid address family {}", family))),
+    }
+}
+
+/// X-Port is computed by XOR'ing the mapped port with the most significant 16
+/// bits of the magic cookie. X-Address is the mapped IP address XOR'd with the
+/// magic cookie (IPv4) or the concatenation of the magic cookie and the
+/// transaction ID (IPv6). XOR'ing twice returns the original address.
+fn xor_address(address: &Address, transaction_id: &TransactionId) -> Address {
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(&transaction_id.0);
+
+    Address {
+        address: address
+            .address
+            .iter()
+            .zip(mask.iter())
+            .map(|(byte, mask)| byte ^ mask)
+            .collect(),
+        port: address.port ^ (MAGIC_COOKIE >> 16) as u16,
+        ip_kind: address.ip_kind.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const TRANSACTION_ID: TransactionId =
+        TransactionId([176, 184, 63, 0, 218, 12, 162, 195, 40, 225, 242, 133]);
+
+    fn round_trip(attribute: Attribute) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        let length = attribute.encode(&mut buffer, &TRANSACTION_ID).unwrap();
+        assert_eq!(length, buffer.len());
+        assert_eq!(length, attribute.encoded_length(&TRANSACTION_ID));
+        assert_eq!(buffer.len() % 4, 0);
+
+        let encoded = buffer.to_vec();
+        let mut slice = &encoded[..];
+        let decoded = Attribute::decode(&mut slice, &TRANSACTION_ID).unwrap();
+        assert_eq!(decoded, attribute);
+        assert!(slice.is_empty());
+
+        encoded
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_an_ipv4_xor_mapped_address() {
+        let address = Address::try_from("192.0.2.1:32853").unwrap();
+        let encoded = round_trip(Attribute::XorMappedAddress(address));
+
+        assert_eq!(
+            encoded,
+            vec![0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]
+        );
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_an_ipv6_xor_mapped_address() {
+        let address = Address::try_from("[2001:db8::1]:32853").unwrap();
+        round_trip(Attribute::XorMappedAddress(address));
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_rfc_5780_attributes() {
+        let address = Address::try_from("127.0.0.2:3479").unwrap();
+
+        round_trip(Attribute::ChangeRequest {
+            change_ip: true,
+            change_port: false,
+        });
+        round_trip(Attribute::ResponseOrigin(address.clone()));
+        round_trip(Attribute::OtherAddress(address.clone()));
+        round_trip(Attribute::MappedAddress(address));
+        round_trip(Attribute::ResponsePort(40000));
+        round_trip(Attribute::Padding(1500));
+        round_trip(Attribute::Padding(3));
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_padded_attributes() {
+        round_trip(Attribute::Username("alice"));
+        round_trip(Attribute::MessageIntegrity([7; 20]));
+        round_trip(Attribute::ErrorCode {
+            code: 420,
+            reason: "Unknown Attribute",
+        });
+        round_trip(Attribute::UnknownAttributes(vec![CHANGE_REQUEST]));
+    }
+
+    #[test]
+    fn it_rejects_a_value_too_long_for_its_length() {
+        let username = "a".repeat(u16::MAX as usize + 1);
+        let mut buffer = BytesMut::new();
+
+        assert!(matches!(
+            Attribute::Username(&username).encode(&mut buffer, &TRANSACTION_ID),
+            Err(Error::Encode(_))
+        ));
+        assert!(buffer.is_empty());
+
+        // the longest PADDING, whose value fits, but not with its header
+        let length = Attribute::Padding(u16::MAX)
+            .encode(&mut buffer, &TRANSACTION_ID)
+            .unwrap();
+        assert_eq!(length, 4 + 65_536);
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_ice_attributes() {
+        round_trip(Attribute::Priority(0x6e0001ff));
+        round_trip(Attribute::UseCandidate);
+        round_trip(Attribute::IceControlled(0x932ff9b151263b36));
+        round_trip(Attribute::IceControlling(1));
+        round_trip(Attribute::FingerPrint(0xe57a3bcf));
+
+        let encoded = round_trip(Attribute::UseCandidate);
+        assert_eq!(encoded, vec![0x00, 0x25, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn it_decodes_unknown_attributes() {
+        let encoded = [0x80, 0x22, 0x00, 0x01, 0x61, 0x00, 0x00, 0x00];
+        let mut slice = &encoded[..];
+        let decoded = Attribute::decode(&mut slice, &TRANSACTION_ID).unwrap();
+
+        assert_eq!(
+            decoded,
+            Attribute::Unknown {
+                kind: 0x8022,
+                value: b"a"
+            }
+        );
+        assert_eq!(round_trip(decoded), encoded);
+    }
+
+    #[test]
+    fn it_errors_on_a_truncated_attribute() {
+        let encoded = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01];
+        let mut slice = &encoded[..];
+
+        assert!(Attribute::decode(&mut slice, &TRANSACTION_ID).is_err());
     }
 }
@@ -0,0 +1,111 @@
+//! The FINGERPRINT attribute MAY be present in all STUN messages. The value
+//! of the attribute is computed as the CRC-32 of the STUN message up to (but
+//! excluding) the FINGERPRINT attribute itself, XOR'ed with the 32-bit value
+//! 0x5354554e (RFC 8489 section 14.7).
+//!
+//! When present, the FINGERPRINT attribute MUST be the last attribute in the
+//! message.  It aids in distinguishing STUN packets from packets of other
+//! protocols, and ICE requires it on every connectivity check.
+
+use crate::message::attribute::{ATTRIBUTE_HEADER_LENGTH, FINGERPRINT};
+use crate::message::MESSAGE_HEADER_LENGTH;
+use bytes::{BufMut, BytesMut};
+
+/// The length of the CRC-32.
+const CRC_LENGTH: usize = 4;
+
+/// XOR'ed with the CRC so it doesn't match other protocols' CRC-32s.
+const XOR: u32 = 0x5354_554e;
+
+/// Append FINGERPRINT to the single encoded message in `buf`, after any
+/// MESSAGE-INTEGRITY.
+pub fn append(buf: &mut BytesMut) {
+    let length = buf.len() - MESSAGE_HEADER_LENGTH + ATTRIBUTE_HEADER_LENGTH + CRC_LENGTH;
+    buf[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+
+    let crc = crc32fast::hash(buf) ^ XOR;
+
+    buf.put_u16(FINGERPRINT);
+    buf.put_u16(CRC_LENGTH as u16);
+    buf.put_u32(crc);
+}
+
+/// Whether the encoded `message` ends with a FINGERPRINT that matches it.
+pub fn verify(message: &[u8]) -> bool {
+    let attribute = match message
+        .len()
+        .checked_sub(ATTRIBUTE_HEADER_LENGTH + CRC_LENGTH)
+    {
+        Some(attribute) if attribute >= MESSAGE_HEADER_LENGTH => attribute,
+        _ => return false,
+    };
+
+    let kind = u16::from_be_bytes([message[attribute], message[attribute + 1]]);
+    let length = u16::from_be_bytes([message[attribute + 2], message[attribute + 3]]) as usize;
+    let value = attribute + ATTRIBUTE_HEADER_LENGTH;
+
+    if kind != FINGERPRINT || length != CRC_LENGTH {
+        return false;
+    }
+
+    let expected = u32::from_be_bytes([
+        message[value],
+        message[value + 1],
+        message[value + 2],
+        message[value + 3],
+    ]);
+
+    crc32fast::hash(&message[..attribute]) ^ XOR == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{attribute::Attribute, integrity, Message};
+    use bytes::Bytes;
+
+    /// The sample request of RFC 5769 section 2.1.
+    const SAMPLE_REQUEST: &[u8] = b"\x00\x01\x00\x58\x21\x12\xa4\x42\xb7\xe7\xa7\x01\xbc\x34\xd6\x86\xfa\x87\xdf\xae\x80\x22\x00\x10STUN test client\x00\x24\x00\x04\x6e\x00\x01\xff\x80\x29\x00\x08\x93\x2f\xf9\xb1\x51\x26\x3b\x36\x00\x06\x00\x09\x65\x76\x74\x6a\x3a\x68\x36\x76\x59\x20\x20\x20\x00\x08\x00\x14\x9a\xea\xa7\x0c\xbf\xd8\xcb\x56\x78\x1e\xf2\xb5\xb2\xd3\xf2\x49\xc1\xb5\x71\xa2\x80\x28\x00\x04\xe5\x7a\x3b\xcf";
+
+    #[test]
+    fn it_verifies_the_rfc_5769_sample_request() {
+        assert!(verify(SAMPLE_REQUEST));
+
+        let mut corrupted = SAMPLE_REQUEST.to_vec();
+        corrupted[30] ^= 1;
+        assert!(!verify(&corrupted));
+    }
+
+    #[test]
+    fn it_appends_a_fingerprint_after_message_integrity() {
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![Attribute::Username("evtj:h6vY")])
+            .encode(&mut buf)
+            .unwrap();
+        integrity::sign(&mut buf, b"VOkJxbRl1RmTxUk/WvJxBt");
+        append(&mut buf);
+
+        assert!(verify(&buf));
+        assert!(integrity::verify(&buf, b"VOkJxbRl1RmTxUk/WvJxBt"));
+
+        let mut bytes = Bytes::from(buf.to_vec());
+        let message = Message::decode(&mut bytes).unwrap();
+        assert!(matches!(
+            message.attributes[..],
+            [
+                Attribute::Username(_),
+                Attribute::MessageIntegrity(_),
+                Attribute::FingerPrint(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn it_rejects_a_message_without_a_fingerprint() {
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+
+        assert!(!verify(&buf));
+        assert!(!verify(&[]));
+    }
+}
@@ -0,0 +1,123 @@
+//! The MESSAGE-INTEGRITY attribute contains an HMAC-SHA1 [RFC2104] of the STUN
+//! message. The MESSAGE-INTEGRITY attribute can be present in any STUN message
+//! type (RFC 8489 section 14.5).
+//!
+//! The text used as input to HMAC is the STUN message, up to and including the
+//! attribute preceding the MESSAGE-INTEGRITY attribute. The Length field of the
+//! STUN message header is adjusted to point to the end of the
+//! MESSAGE-INTEGRITY attribute.  With short-term credentials the key is the
+//! password itself (RFC 8489 section 9.1.1).
+//!
+//! Both functions work on encoded messages, since the HMAC covers the exact
+//! bytes on the wire.
+
+use crate::message::attribute::{padding, ATTRIBUTE_HEADER_LENGTH, MESSAGE_INTEGRITY};
+use crate::message::MESSAGE_HEADER_LENGTH;
+use bytes::{BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// The length of an HMAC-SHA1.
+pub(crate) const HMAC_LENGTH: usize = 20;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Append MESSAGE-INTEGRITY, keyed with `key`, to the single encoded message
+/// in `buf`.
+pub fn sign(buf: &mut BytesMut, key: &[u8]) {
+    let length = buf.len() - MESSAGE_HEADER_LENGTH + ATTRIBUTE_HEADER_LENGTH + HMAC_LENGTH;
+    buf[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+
+    let mut mac = mac(key);
+    mac.update(buf);
+    let hmac = mac.finalize().into_bytes();
+
+    buf.put_u16(MESSAGE_INTEGRITY);
+    buf.put_u16(HMAC_LENGTH as u16);
+    buf.put_slice(&hmac);
+}
+
+/// Whether the encoded `message` has a MESSAGE-INTEGRITY keyed with `key`.
+/// Attributes after it aren't covered, and are ignored.
+pub fn verify(message: &[u8], key: &[u8]) -> bool {
+    let mut offset = MESSAGE_HEADER_LENGTH;
+
+    while offset + ATTRIBUTE_HEADER_LENGTH <= message.len() {
+        let kind = u16::from_be_bytes([message[offset], message[offset + 1]]);
+        let length = u16::from_be_bytes([message[offset + 2], message[offset + 3]]) as usize;
+        let value = offset + ATTRIBUTE_HEADER_LENGTH;
+
+        if kind == MESSAGE_INTEGRITY {
+            if length != HMAC_LENGTH || value + HMAC_LENGTH > message.len() {
+                return false;
+            }
+
+            let covered = (value + HMAC_LENGTH - MESSAGE_HEADER_LENGTH) as u16;
+            let mut mac = mac(key);
+            mac.update(&message[..2]);
+            mac.update(&covered.to_be_bytes());
+            mac.update(&message[4..offset]);
+
+            // constant time, so the HMAC can't be guessed byte by byte
+            return mac
+                .verify_slice(&message[value..value + HMAC_LENGTH])
+                .is_ok();
+        }
+
+        offset = value + length + padding(length);
+    }
+
+    false
+}
+
+fn mac(key: &[u8]) -> HmacSha1 {
+    HmacSha1::new_from_slice(key).expect("HMAC takes keys of any length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{attribute::Attribute, Message};
+    use bytes::Bytes;
+
+    /// The sample request of RFC 5769 section 2.1.
+    const SAMPLE_REQUEST: &[u8] = b"\x00\x01\x00\x58\x21\x12\xa4\x42\xb7\xe7\xa7\x01\xbc\x34\xd6\x86\xfa\x87\xdf\xae\x80\x22\x00\x10STUN test client\x00\x24\x00\x04\x6e\x00\x01\xff\x80\x29\x00\x08\x93\x2f\xf9\xb1\x51\x26\x3b\x36\x00\x06\x00\x09\x65\x76\x74\x6a\x3a\x68\x36\x76\x59\x20\x20\x20\x00\x08\x00\x14\x9a\xea\xa7\x0c\xbf\xd8\xcb\x56\x78\x1e\xf2\xb5\xb2\xd3\xf2\x49\xc1\xb5\x71\xa2\x80\x28\x00\x04\xe5\x7a\x3b\xcf";
+
+    const SAMPLE_PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    #[test]
+    fn it_verifies_the_rfc_5769_sample_request() {
+        assert!(verify(SAMPLE_REQUEST, SAMPLE_PASSWORD));
+        assert!(!verify(SAMPLE_REQUEST, b"VOkJxbRl1RmTxUk/WvJxBT"));
+    }
+
+    #[test]
+    fn it_signs_a_message_it_can_verify() {
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![Attribute::Username("evtj:h6vY")])
+            .encode(&mut buf)
+            .unwrap();
+        sign(&mut buf, SAMPLE_PASSWORD);
+
+        assert!(verify(&buf, SAMPLE_PASSWORD));
+
+        let mut bytes = Bytes::from(buf.to_vec());
+        let message = Message::decode(&mut bytes).unwrap();
+        assert!(matches!(
+            message.attributes[..],
+            [Attribute::Username(_), Attribute::MessageIntegrity(_)]
+        ));
+
+        // any change to the covered bytes invalidates it
+        buf[25] ^= 1;
+        assert!(!verify(&buf, SAMPLE_PASSWORD));
+    }
+
+    #[test]
+    fn it_rejects_a_message_without_integrity() {
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+
+        assert!(!verify(&buf, SAMPLE_PASSWORD));
+    }
+}
@@ -16,6 +16,12 @@
 //! orthogonal, so that for each method, a request, success response, error response,
 //! and indication are possible for that method. Extensions defining new methods
 //! MUST indicate which classes are permitted for that method.
+//!
+//! Binding is the only method this crate knows, so messages of any other
+//! method, e.g. a TURN Allocate, fail to decode and are dropped.
+
+use crate::error::{Error, Result};
+use std::convert::{TryFrom, TryInto};
 
 #[derive(Debug, PartialEq)]
 pub enum Method {
@@ -33,21 +39,23 @@ impl Method {
         method_part_0_3 + method_part_4_6 + method_part_7_11
     }
 
-    pub(crate) fn decode(value: u16) -> Self {
+    pub(crate) fn decode(value: u16) -> Result<Self> {
         let method_part_0_3 = value & 0xf; // M0-M3
         let method_part_4_6 = (value >> 1) & 0x70; // M4-M6
         let method_part_7_11 = (value >> 2) & 0xf80; // M7-M11
         let method = method_part_0_3 + method_part_4_6 + method_part_7_11;
 
-        method.into()
+        method.try_into()
     }
 }
 
-impl From<u16> for Method {
-    fn from(value: u16) -> Method {
+impl TryFrom<u16> for Method {
+    type Error = Error;
+
+    fn try_from(value: u16) -> Result<Method> {
         match value {
-            0x001 => Method::Binding,
-            _ => unimplemented!("Only binding methods are allowed"),
+            0x001 => Ok(Method::Binding),
+            _ => Err(Error::Decode(format!("Unknown method {:#05x}", value))),
         }
     }
 }
@@ -72,14 +80,14 @@ mod tests {
 
     #[test]
     fn it_decodes_all_methods() {
-        let decoded = Method::decode((&Method::Binding).into());
+        let decoded = Method::decode((&Method::Binding).into()).unwrap();
         assert_eq!(decoded, Method::Binding);
     }
 
     #[test]
-    #[should_panic]
-    fn it_panics_when_decoding_a_non_binding_method() {
-        let method = 0x0002;
-        let _: Method = method.into();
+    fn it_errors_when_decoding_a_non_binding_method() {
+        // an Allocate request
+        assert!(matches!(Method::decode(0x0003), Err(Error::Decode(_))));
+        assert!(Method::try_from(0x0002).is_err());
     }
 }
@@ -23,9 +23,12 @@ use crate::message::class::Class;
 use crate::message::method::Method;
 use crate::message::transaction_id::TransactionId;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::net::SocketAddr;
 
 pub mod attribute;
 pub mod class;
+pub mod fingerprint;
+pub mod integrity;
 pub mod method;
 pub mod transaction_id;
 
@@ -61,31 +64,92 @@ impl<'a> Message<'a> {
         }
     }
 
-    pub fn binding_response(attributes: Vec<Attribute>) -> Message {
+    /// Indications aren't answered, e.g. keepalives.
+    pub fn binding_indication(attributes: Vec<Attribute<'a>>) -> Message<'a> {
         Message {
-            class: Class::SuccessResponse,
+            class: Class::Indication,
             method: Method::Binding,
             transaction_id: TransactionId::new(),
             attributes,
         }
     }
 
-    pub fn encode(&self, buf: &mut BytesMut) {
+    /// Responses MUST carry the same transaction ID as their request.
+    pub fn binding_response(
+        transaction_id: TransactionId,
+        attributes: Vec<Attribute<'a>>,
+    ) -> Message<'a> {
+        Message {
+            class: Class::SuccessResponse,
+            method: Method::Binding,
+            transaction_id,
+            attributes,
+        }
+    }
+
+    pub fn binding_error_response(
+        transaction_id: TransactionId,
+        attributes: Vec<Attribute<'a>>,
+    ) -> Message<'a> {
+        Message {
+            class: Class::FailureResponse,
+            method: Method::Binding,
+            transaction_id,
+            attributes,
+        }
+    }
+
+    /// The reflexive address of a response, preferring XOR-MAPPED-ADDRESS over
+    /// the legacy MAPPED-ADDRESS.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let find = |xor: bool| {
+            self.attributes
+                .iter()
+                .find_map(|attribute| match attribute {
+                    Attribute::XorMappedAddress(address) if xor => Some(address.socket_addr()),
+                    Attribute::MappedAddress(address) if !xor => Some(address.socket_addr()),
+                    _ => None,
+                })
+        };
+
+        find(true).or_else(|| find(false))
+    }
+
+    /// The OTHER-ADDRESS of an RFC 5780 response.
+    pub fn other_address(&self) -> Option<SocketAddr> {
+        self.attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::OtherAddress(address) => Some(address.socket_addr()),
+                _ => None,
+            })
+    }
+
+    /// Encode the message into the buffer.  Errors, writing nothing, when the
+    /// attributes are too long for the 16-bit message length.
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<()> {
         let transaction_id = &self.transaction_id.0;
         let class = self.class.encode();
         let method = self.method.encode();
 
-        // add class and body to the buffer
-        buf.put_u16(class + method);
-
-        // encode the body length
+        // encode the body first, to know its length
         let mut body = BytesMut::with_capacity(256);
-        let mut message_length: u16 = 0;
+        let mut message_length: usize = 0;
 
         for attribute in &self.attributes {
-            message_length += attribute.encode(&mut body, &self.transaction_id);
+            message_length += attribute.encode(&mut body, &self.transaction_id)?;
         }
 
+        let message_length = u16::try_from(message_length).map_err(|_| {
+            Error::Encode(format!(
+                "{} bytes of attributes don't fit in a message",
+                message_length
+            ))
+        })?;
+
+        // add class and body to the buffer
+        buf.put_u16(class + method);
+
         // add message length to the buffer
         buf.put_u16(message_length);
 
@@ -97,9 +161,11 @@ impl<'a> Message<'a> {
 
         // add the encoded body to the buffer
         buf.put_slice(body.as_ref());
+
+        Ok(())
     }
 
-    pub fn decode(buffer: &mut Bytes) -> Result<Message> {
+    pub fn decode(buffer: &'a mut Bytes) -> Result<Message<'a>> {
         let mut attributes: Vec<Attribute> = Vec::new();
 
         // All STUN messages MUST start with a 20-byte header followed by zero or
@@ -115,7 +181,7 @@ impl<'a> Message<'a> {
 
         let message_type = buffer.get_u16();
         let class = Class::decode(message_type)?;
-        let method = Method::decode(message_type);
+        let method = Method::decode(message_type)?;
         let message_length = buffer.get_u16() as usize;
         let magic_cookie = buffer.get_u32();
 
@@ -130,11 +196,21 @@ impl<'a> Message<'a> {
             )));
         }
 
-        // decode attributes (if they're are any)
-        let attributes_length = buffer.remaining() - message_length;
+        if buffer.remaining() < message_length {
+            return Err(Error::Decode(format!(
+                "Not enough bytes in the body.  Expected {}, but got {}",
+                message_length,
+                buffer.remaining()
+            )));
+        }
+
+        // attributes borrow from the buffer for the lifetime of the message
+        let buffer: &'a Bytes = buffer;
+        let mut body: &'a [u8] = &buffer[..message_length];
 
-        while buffer.remaining() > attributes_length {
-            let attribute = Attribute::decode(buffer, &transaction_id)?;
+        // decode attributes (if they're are any)
+        while !body.is_empty() {
+            let attribute = Attribute::decode(&mut body, &transaction_id)?;
             attributes.push(attribute);
         }
 
@@ -153,6 +229,8 @@ impl<'a> Message<'a> {
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::utils::Address;
+    use std::convert::TryFrom;
 
     pub(crate) const BINDING_REQUEST: &[u8; 20] =
         b"\0\x01\0\0!\x12\xa4B\xb0\xb8?\0\xda\x0c\xa2\xc3(\xe1\xf2\x85";
@@ -181,7 +259,7 @@ pub(crate) mod tests {
     fn it_encodes_a_binding_request() {
         let mut buffer = BytesMut::new();
         let message = binding_request();
-        message.encode(&mut buffer);
+        message.encode(&mut buffer).unwrap();
 
         let mut expected_buffer = BytesMut::with_capacity(0);
         expected_buffer.extend_from_slice(BINDING_REQUEST);
@@ -193,7 +271,7 @@ pub(crate) mod tests {
     fn it_encodes_a_binding_response() {
         let mut buffer = BytesMut::new();
         let message = binding_response();
-        message.encode(&mut buffer);
+        message.encode(&mut buffer).unwrap();
 
         let mut expected_buffer = BytesMut::with_capacity(0);
         expected_buffer.extend_from_slice(BINDING_RESPONSE);
@@ -218,4 +296,43 @@ pub(crate) mod tests {
 
         assert_eq!(message, expected);
     }
+
+    #[test]
+    fn it_encodes_and_decodes_a_binding_response_with_attributes() {
+        let address = Address::try_from("192.0.2.1:32853").unwrap();
+        let message = Message::binding_response(
+            binding_response().transaction_id,
+            vec![
+                Attribute::XorMappedAddress(address.clone()),
+                Attribute::OtherAddress(address),
+            ],
+        );
+
+        let mut buffer = BytesMut::new();
+        message.encode(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), MESSAGE_HEADER_LENGTH + 24);
+
+        let mut encoded = buffer.freeze();
+        assert_eq!(Message::decode(&mut encoded).unwrap(), message);
+    }
+
+    #[test]
+    fn it_rejects_attributes_too_long_for_a_message() {
+        let message = Message::binding_response(
+            binding_response().transaction_id,
+            vec![Attribute::Padding(40_000), Attribute::Padding(40_000)],
+        );
+        let mut buffer = BytesMut::new();
+
+        assert!(matches!(message.encode(&mut buffer), Err(Error::Encode(_))));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn it_errors_on_a_truncated_body() {
+        let mut encoded = BytesMut::from(&BINDING_REQUEST[..]);
+        encoded[3] = 8;
+
+        assert!(Message::decode(&mut encoded.freeze()).is_err());
+    }
 }
@@ -17,10 +17,10 @@
 //! the transaction IDs in requests received by the agent.
 
 use crate::error::Result;
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
 use rand::Rng;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct TransactionId(pub [u8; 12]);
 
 impl TransactionId {
@@ -31,6 +31,7 @@ impl TransactionId {
     pub(crate) fn decode(buffer: &mut Bytes) -> Result<Self> {
         let mut transaction_id = [0u8; 12];
         transaction_id.copy_from_slice(&buffer[0..12]);
+        buffer.advance(12);
 
         Ok(Self(transaction_id))
     }
@@ -0,0 +1,741 @@
+//! NAT behavior discovery as described in RFC 5780 section 4.
+//!
+//! The client runs a sequence of Binding Requests against a server that has
+//! an alternate IP address and port (see `config::Config::alternate_server`).
+//! The server advertises its alternate address in OTHER-ADDRESS, and can be
+//! asked to answer from it with CHANGE-REQUEST.
+//!
+//! Mapping behavior is determined by comparing the XOR-MAPPED-ADDRESS that
+//! is returned when sending from the same local socket to the primary
+//! address, the alternate IP and the alternate IP and port.  Filtering
+//! behavior is determined by asking the server to respond from a different
+//! IP and port, and then from a different port only, and noting which of
+//! those responses make it through.  These run on a fresh socket, whose
+//! mapping hasn't sent to the alternate address yet.  Hairpinning is
+//! determined by sending a request from a second local socket to the first
+//! socket's mapped address.
+//!
+//! The binding lifetime is discovered by letting a mapping go idle, then
+//! asking the server (via RESPONSE-PORT on a request from a second socket)
+//! to send to the mapped port.  A binary search over the idle period finds
+//! the point where the mapping expires.
+
+use crate::{
+    client,
+    error::{Error, Result},
+    message::{attribute::Attribute, class::Class, transaction_id::TransactionId, Message},
+    utils::MAX_DATAGRAM_SIZE,
+};
+use bytes::{Bytes, BytesMut};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::UdpSocket, time};
+
+/// Requests are sent this many times before the test is considered to have
+/// received no response.
+const ATTEMPTS: u32 = 3;
+
+/// How a NAT maps internal addresses to external addresses (RFC 4787).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MappingBehavior {
+    /// The client's local address is reachable directly.
+    NoNat,
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+/// Which external endpoints a NAT lets through to a mapped address (RFC 4787).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilteringBehavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NatReport {
+    pub local_address: SocketAddr,
+    pub mapped_address: SocketAddr,
+    pub mapping: MappingBehavior,
+    pub filtering: FilteringBehavior,
+    pub hairpinning: bool,
+}
+
+/// Bounds for the binding lifetime search.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LifetimeOptions {
+    /// The longest idle period to test.  Lifetimes beyond it are reported as `max`.
+    pub max: Duration,
+    /// The search stops once the lifetime is known to within this interval.
+    pub resolution: Duration,
+    /// Bounds each individual request, including retransmissions.
+    pub timeout: Duration,
+}
+
+impl Default for LifetimeOptions {
+    fn default() -> Self {
+        Self {
+            max: Duration::from_secs(120),
+            resolution: Duration::from_secs(5),
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BindingLifetime {
+    /// The longest idle period the mapping was observed to survive.
+    pub lifetime: Duration,
+    /// How often to send keepalives to hold the mapping open.
+    pub keepalive: Duration,
+}
+
+/// Run the RFC 5780 mapping, filtering and hairpinning tests from `socket`
+/// against a behavior discovery `server`.  `timeout` bounds each individual
+/// test, including retransmissions.
+pub async fn detect(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<NatReport> {
+    let local_address = socket
+        .local_addr()
+        .map_err(|e| Error::NatDetection(e.to_string()))?;
+
+    // Test I: a plain Binding Request to the primary address
+    let (mut bytes, _) = transaction(socket, server, vec![], timeout)
+        .await?
+        .ok_or_else(|| Error::Timeout(server.to_string()))?;
+    let response = success(Message::decode(&mut bytes)?)?;
+    let mapped_address = mapped_address(&response)?;
+    let other_address = response.other_address().ok_or_else(|| {
+        Error::NatDetection("the server did not include OTHER-ADDRESS".to_string())
+    })?;
+
+    let mapping = mapping_behavior(
+        socket,
+        server,
+        local_address,
+        mapped_address,
+        other_address,
+        timeout,
+    )
+    .await?;
+    let filtering =
+        filtering_behavior(&fresh_socket(local_address).await?, server, timeout).await?;
+    let hairpinning = hairpinning(socket, local_address, mapped_address, timeout).await?;
+
+    Ok(NatReport {
+        local_address,
+        mapped_address,
+        mapping,
+        filtering,
+        hairpinning,
+    })
+}
+
+/// Binary search the longest idle period a NAT mapping on `socket` survives,
+/// using a second `probe` socket and RESPONSE-PORT (RFC 5780 section 4.6).
+/// Both sockets must sit behind the same NAT, and the server must accept the
+/// mapped port as a RESPONSE-PORT.  A mapping that doesn't survive an idle
+/// period of `resolution` is an error, since no keepalive interval would hold
+/// it open.
+pub async fn binding_lifetime(
+    socket: &UdpSocket,
+    probe: &UdpSocket,
+    server: SocketAddr,
+    options: &LifetimeOptions,
+) -> Result<BindingLifetime> {
+    let mut alive = Duration::ZERO;
+    let mut expired = options.max;
+
+    if mapping_alive(socket, probe, server, options.max, options.timeout).await? {
+        alive = options.max;
+    }
+
+    while expired.saturating_sub(alive) > options.resolution {
+        let idle = alive + (expired - alive) / 2;
+
+        match mapping_alive(socket, probe, server, idle, options.timeout).await? {
+            true => alive = idle,
+            false => expired = idle,
+        }
+
+        log::debug!("binding lifetime between {:?} and {:?}", alive, expired);
+    }
+
+    if alive.is_zero() {
+        return Err(Error::NatDetection(format!(
+            "the mapping expired within {:?} of idling",
+            expired
+        )));
+    }
+
+    Ok(BindingLifetime {
+        lifetime: alive,
+        keepalive: alive / 2,
+    })
+}
+
+/// Tests II and III of RFC 5780 section 4.3.
+async fn mapping_behavior(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    local_address: SocketAddr,
+    mapped_address: SocketAddr,
+    other_address: SocketAddr,
+    timeout: Duration,
+) -> Result<MappingBehavior> {
+    if mapped_address == local_address {
+        return Ok(MappingBehavior::NoNat);
+    }
+
+    let alternate_ip = SocketAddr::new(other_address.ip(), server.port());
+    let mapped_address_2 = mapped_address_from(socket, alternate_ip, timeout).await?;
+
+    if mapped_address_2 == mapped_address {
+        return Ok(MappingBehavior::EndpointIndependent);
+    }
+
+    let mapped_address_3 = mapped_address_from(socket, other_address, timeout).await?;
+
+    Ok(classify_mapping(mapped_address_2, mapped_address_3))
+}
+
+fn classify_mapping(mapped_address_2: SocketAddr, mapped_address_3: SocketAddr) -> MappingBehavior {
+    match mapped_address_2 == mapped_address_3 {
+        true => MappingBehavior::AddressDependent,
+        false => MappingBehavior::AddressAndPortDependent,
+    }
+}
+
+/// Tests II and III of RFC 5780 section 4.4, on a socket that hasn't sent
+/// anything yet.  The mapping tests sent to the alternate IP and port, which
+/// opens the filters of an address-dependent NAT to them.
+async fn filtering_behavior(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<FilteringBehavior> {
+    let change_ip_and_port = Attribute::ChangeRequest {
+        change_ip: true,
+        change_port: true,
+    };
+    let received_2 = transaction(socket, server, vec![change_ip_and_port], timeout)
+        .await?
+        .is_some();
+
+    if received_2 {
+        return Ok(FilteringBehavior::EndpointIndependent);
+    }
+
+    let change_port = Attribute::ChangeRequest {
+        change_ip: false,
+        change_port: true,
+    };
+    let received_3 = transaction(socket, server, vec![change_port], timeout)
+        .await?
+        .is_some();
+
+    Ok(classify_filtering(received_2, received_3))
+}
+
+fn classify_filtering(received_2: bool, received_3: bool) -> FilteringBehavior {
+    match (received_2, received_3) {
+        (true, _) => FilteringBehavior::EndpointIndependent,
+        (false, true) => FilteringBehavior::AddressDependent,
+        (false, false) => FilteringBehavior::AddressAndPortDependent,
+    }
+}
+
+/// RFC 5780 section 4.5: send a request from a second socket to the mapped
+/// address of the first and see if the NAT loops it back.
+async fn hairpinning(
+    socket: &UdpSocket,
+    local_address: SocketAddr,
+    mapped_address: SocketAddr,
+    timeout: Duration,
+) -> Result<bool> {
+    let hairpin_socket = fresh_socket(local_address).await?;
+
+    received_on(socket, &hairpin_socket, mapped_address, vec![], timeout).await
+}
+
+/// A second socket on the IP of `local_address`, whose mapping is new.
+async fn fresh_socket(local_address: SocketAddr) -> Result<UdpSocket> {
+    UdpSocket::bind(SocketAddr::new(local_address.ip(), 0))
+        .await
+        .map_err(|e| Error::NatDetection(e.to_string()))
+}
+
+/// RFC 5780 section 4.6: keep a mapping idle on `socket` for `idle`, then ask
+/// the server to answer a request sent from `probe` to the mapped port.  The
+/// response only reaches `socket` if the mapping is still alive.
+async fn mapping_alive(
+    socket: &UdpSocket,
+    probe: &UdpSocket,
+    server: SocketAddr,
+    idle: Duration,
+    timeout: Duration,
+) -> Result<bool> {
+    let mapped_address = mapped_address_from(socket, server, timeout).await?;
+
+    time::sleep(idle).await;
+
+    let response_port = Attribute::ResponsePort(mapped_address.port());
+
+    received_on(socket, probe, server, vec![response_port], timeout).await
+}
+
+/// Send a Binding Request from `sender` and report whether the matching
+/// message arrived on `receiver`.
+async fn received_on(
+    receiver: &UdpSocket,
+    sender: &UdpSocket,
+    destination: SocketAddr,
+    attributes: Vec<Attribute<'_>>,
+    timeout: Duration,
+) -> Result<bool> {
+    let request = Message::binding_request(attributes);
+
+    for _ in 0..ATTEMPTS {
+        send(sender, destination, &request).await?;
+
+        let received = time::timeout(
+            timeout / ATTEMPTS,
+            receive(receiver, &request.transaction_id),
+        );
+
+        if let Ok(received) = received.await {
+            return received.map(|_| true);
+        }
+    }
+
+    Ok(false)
+}
+
+async fn mapped_address_from(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<SocketAddr> {
+    let (mut bytes, _) = transaction(socket, server, vec![], timeout)
+        .await?
+        .ok_or_else(|| Error::Timeout(server.to_string()))?;
+
+    mapped_address(&success(Message::decode(&mut bytes)?)?)
+}
+
+fn mapped_address(response: &Message) -> Result<SocketAddr> {
+    response.mapped_address().ok_or_else(|| {
+        Error::NatDetection("the server did not include XOR-MAPPED-ADDRESS".to_string())
+    })
+}
+
+fn success(response: Message) -> Result<Message> {
+    match response.class {
+        Class::SuccessResponse => Ok(response),
+        _ => Err(Error::NatDetection(format!(
+            "expected a success response, but got {:?}",
+            response
+        ))),
+    }
+}
+
+/// Send a Binding Request, retransmitting until a response with the same
+/// transaction ID arrives.  Returns `None` if every attempt timed out, which
+/// is an expected outcome of the filtering tests.
+async fn transaction(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    attributes: Vec<Attribute<'_>>,
+    timeout: Duration,
+) -> Result<Option<(Bytes, SocketAddr)>> {
+    let request = Message::binding_request(attributes);
+
+    for _ in 0..ATTEMPTS {
+        send(socket, server, &request).await?;
+
+        let received = time::timeout(timeout / ATTEMPTS, receive(socket, &request.transaction_id));
+
+        if let Ok(received) = received.await {
+            return received.map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+async fn send(socket: &UdpSocket, destination: SocketAddr, request: &Message<'_>) -> Result<()> {
+    let mut buf = BytesMut::new();
+    request.encode(&mut buf)?;
+
+    socket
+        .send_to(buf.as_ref(), destination)
+        .await
+        .map_err(|e| Error::BindingRequest(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Receive until a message with the given transaction ID arrives, dropping
+/// late responses to earlier tests.
+async fn receive(
+    socket: &UdpSocket,
+    transaction_id: &TransactionId,
+) -> Result<(Bytes, SocketAddr)> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (bytes_received, address) = socket
+            .recv_from(&mut buf)
+            .await
+            .map_err(|e| Error::Receive(e.to_string()))?;
+
+        if client::transaction_id(&buf[..bytes_received]).as_ref() == Some(transaction_id) {
+            return Ok((Bytes::copy_from_slice(&buf[..bytes_received]), address));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::tests::{config, start},
+        utils::Address,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tokio::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn it_classifies_mapping_behavior() {
+        let address_2: SocketAddr = "203.0.113.1:50000".parse().unwrap();
+        let address_3: SocketAddr = "203.0.113.1:50001".parse().unwrap();
+
+        assert_eq!(
+            classify_mapping(address_2, address_2),
+            MappingBehavior::AddressDependent
+        );
+        assert_eq!(
+            classify_mapping(address_2, address_3),
+            MappingBehavior::AddressAndPortDependent
+        );
+    }
+
+    #[test]
+    fn it_classifies_filtering_behavior() {
+        assert_eq!(
+            classify_filtering(true, false),
+            FilteringBehavior::EndpointIndependent
+        );
+        assert_eq!(
+            classify_filtering(false, true),
+            FilteringBehavior::AddressDependent
+        );
+        assert_eq!(
+            classify_filtering(false, false),
+            FilteringBehavior::AddressAndPortDependent
+        );
+    }
+
+    #[tokio::test]
+    async fn it_detects_no_nat_over_loopback() {
+        let addresses = start(config(Some("127.0.0.2:0"))).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let report = detect(&socket, addresses[0], TIMEOUT).await.unwrap();
+
+        assert_eq!(report.mapped_address, socket.local_addr().unwrap());
+        assert_eq!(report.mapping, MappingBehavior::NoNat);
+        assert_eq!(report.filtering, FilteringBehavior::EndpointIndependent);
+        assert!(report.hairpinning);
+    }
+
+    #[tokio::test]
+    async fn it_detects_the_filtering_behavior_of_a_nat() {
+        let servers = start(config(Some("127.0.0.2:0"))).await;
+
+        for filtering in [
+            FilteringBehavior::EndpointIndependent,
+            FilteringBehavior::AddressDependent,
+            FilteringBehavior::AddressAndPortDependent,
+        ] {
+            let gateways = filtering_nat(&servers, filtering).await;
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let report = detect(&socket, gateways[0], TIMEOUT).await.unwrap();
+
+            assert_eq!(report.mapping, MappingBehavior::EndpointIndependent);
+            assert_eq!(report.filtering, filtering, "{:?}", filtering);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_requires_a_behavior_discovery_server() {
+        let addresses = start(config(None)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let report = detect(&socket, addresses[0], TIMEOUT).await;
+
+        assert!(matches!(report, Err(Error::NatDetection(_))));
+    }
+
+    /// Mappings of internal addresses onto external sockets, with the time of
+    /// the last outbound packet.
+    type Mappings = Arc<Mutex<HashMap<SocketAddr, (Arc<UdpSocket>, Instant)>>>;
+
+    /// An endpoint-independent NAT in front of `server` whose mappings expire
+    /// after `lifetime` without outbound traffic.  Clients send to the returned
+    /// gateway address as if it were the server.
+    async fn simulated_nat(server: SocketAddr, lifetime: Duration) -> SocketAddr {
+        let gateway = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let gateway_address = gateway.local_addr().unwrap();
+        let mappings: Mappings = Arc::default();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+            loop {
+                let (size, internal) = gateway.recv_from(&mut buf).await.unwrap();
+                let existing = mappings.lock().unwrap().get(&internal).cloned();
+                let external = match existing {
+                    Some((external, last)) if last.elapsed() < lifetime => external,
+                    _ => {
+                        let external = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+                        tokio::spawn(inbound(
+                            gateway.clone(),
+                            external.clone(),
+                            internal,
+                            mappings.clone(),
+                            lifetime,
+                        ));
+                        external
+                    }
+                };
+
+                mappings
+                    .lock()
+                    .unwrap()
+                    .insert(internal, (external.clone(), Instant::now()));
+                external.send_to(&buf[..size], server).await.unwrap();
+            }
+        });
+
+        gateway_address
+    }
+
+    /// Forward packets arriving on `external` to `internal` while the mapping
+    /// is current and alive.
+    async fn inbound(
+        gateway: Arc<UdpSocket>,
+        external: Arc<UdpSocket>,
+        internal: SocketAddr,
+        mappings: Mappings,
+        lifetime: Duration,
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (size, _) = external.recv_from(&mut buf).await.unwrap();
+            let alive = match mappings.lock().unwrap().get(&internal) {
+                Some((current, last)) => {
+                    Arc::ptr_eq(current, &external) && last.elapsed() < lifetime
+                }
+                None => false,
+            };
+
+            if alive {
+                gateway.send_to(&buf[..size], internal).await.unwrap();
+            }
+        }
+    }
+
+    /// The server addresses each external socket of a `filtering_nat` has
+    /// sent to.
+    type Permitted = Arc<Mutex<Vec<SocketAddr>>>;
+
+    /// Mappings of internal addresses onto the external sockets of a
+    /// `filtering_nat`.
+    type FilteringMappings = Arc<Mutex<HashMap<SocketAddr, (Arc<UdpSocket>, Permitted)>>>;
+
+    /// An endpoint-independent NAT in front of the four listeners of a
+    /// behavior discovery server, whose inbound packets pass per `filtering`.
+    /// The returned gateways are laid out like the listeners, and clients
+    /// send to a gateway as if it were the listener at the same index.
+    async fn filtering_nat(
+        servers: &[SocketAddr],
+        filtering: FilteringBehavior,
+    ) -> Vec<SocketAddr> {
+        let primary = UdpSocket::bind("127.0.0.3:0").await.unwrap();
+        let alternate_port = UdpSocket::bind("127.0.0.3:0").await.unwrap();
+        let (port, other_port) = (
+            primary.local_addr().unwrap().port(),
+            alternate_port.local_addr().unwrap().port(),
+        );
+        let gateways = Arc::new(vec![
+            primary,
+            alternate_port,
+            UdpSocket::bind(("127.0.0.4", port)).await.unwrap(),
+            UdpSocket::bind(("127.0.0.4", other_port)).await.unwrap(),
+        ]);
+        let addresses: Vec<SocketAddr> = gateways
+            .iter()
+            .map(|gateway| gateway.local_addr().unwrap())
+            .collect();
+        let mappings: FilteringMappings = Arc::default();
+
+        for index in 0..gateways.len() {
+            let (gateways, mappings) = (gateways.clone(), mappings.clone());
+            let (servers, addresses) = (servers.to_vec(), addresses.clone());
+
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+                loop {
+                    let (size, internal) = gateways[index].recv_from(&mut buf).await.unwrap();
+                    let existing = mappings.lock().unwrap().get(&internal).cloned();
+                    let (external, permitted) = match existing {
+                        Some(mapping) => mapping,
+                        None => {
+                            let external = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+                            let permitted = Permitted::default();
+                            tokio::spawn(filtered(
+                                gateways.clone(),
+                                external.clone(),
+                                internal,
+                                permitted.clone(),
+                                filtering,
+                                (servers.clone(), addresses.clone()),
+                            ));
+                            mappings
+                                .lock()
+                                .unwrap()
+                                .insert(internal, (external.clone(), permitted.clone()));
+                            (external, permitted)
+                        }
+                    };
+
+                    permitted.lock().unwrap().push(servers[index]);
+                    external
+                        .send_to(&buf[..size], servers[index])
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+
+        addresses
+    }
+
+    /// Forward the packets arriving on `external` that `filtering` lets
+    /// through to `internal`, from the gateway of the listener that sent them.
+    /// OTHER-ADDRESS and RESPONSE-ORIGIN are rewritten to the gateways.
+    async fn filtered(
+        gateways: Arc<Vec<UdpSocket>>,
+        external: Arc<UdpSocket>,
+        internal: SocketAddr,
+        permitted: Permitted,
+        filtering: FilteringBehavior,
+        (servers, addresses): (Vec<SocketAddr>, Vec<SocketAddr>),
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let gateway_of = |server: SocketAddr| {
+            let index = servers.iter().position(|s| *s == server);
+            index.map(|index| addresses[index])
+        };
+
+        loop {
+            let (size, source) = external.recv_from(&mut buf).await.unwrap();
+            let allowed = permitted.lock().unwrap().iter().any(|p| match filtering {
+                FilteringBehavior::EndpointIndependent => true,
+                FilteringBehavior::AddressDependent => p.ip() == source.ip(),
+                FilteringBehavior::AddressAndPortDependent => *p == source,
+            });
+
+            if !allowed {
+                continue;
+            }
+
+            let mut bytes = Bytes::copy_from_slice(&buf[..size]);
+            let mut message = Message::decode(&mut bytes).unwrap();
+            message.attributes = std::mem::take(&mut message.attributes)
+                .into_iter()
+                .map(|attribute| match attribute {
+                    Attribute::OtherAddress(address) => Attribute::OtherAddress(
+                        Address::parse_address(gateway_of(address.socket_addr()).unwrap()),
+                    ),
+                    Attribute::ResponseOrigin(address) => Attribute::ResponseOrigin(
+                        Address::parse_address(gateway_of(address.socket_addr()).unwrap()),
+                    ),
+                    attribute => attribute,
+                })
+                .collect();
+
+            let mut rewritten = BytesMut::new();
+            message.encode(&mut rewritten).unwrap();
+
+            let index = servers.iter().position(|s| *s == source).unwrap_or(0);
+            gateways[index].send_to(&rewritten, internal).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn it_discovers_the_binding_lifetime() {
+        let lifetime = Duration::from_millis(200);
+        let addresses = start(config(None)).await;
+        let gateway = simulated_nat(addresses[0], lifetime).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = LifetimeOptions {
+            max: Duration::from_millis(400),
+            resolution: Duration::from_millis(25),
+            timeout: Duration::from_millis(90),
+        };
+
+        let discovered = binding_lifetime(&socket, &probe, gateway, &options)
+            .await
+            .unwrap();
+
+        assert!(discovered.lifetime <= lifetime);
+        assert!(discovered.lifetime >= lifetime - options.resolution);
+        assert_eq!(discovered.keepalive, discovered.lifetime / 2);
+    }
+
+    #[tokio::test]
+    async fn it_fails_on_a_lifetime_below_the_resolution() {
+        let addresses = start(config(None)).await;
+        let gateway = simulated_nat(addresses[0], Duration::from_millis(10)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = LifetimeOptions {
+            max: Duration::from_millis(400),
+            resolution: Duration::from_millis(25),
+            timeout: Duration::from_millis(90),
+        };
+
+        let discovered = binding_lifetime(&socket, &probe, gateway, &options).await;
+
+        assert!(matches!(discovered, Err(Error::NatDetection(_))));
+    }
+
+    #[tokio::test]
+    async fn it_caps_the_binding_lifetime_at_max() {
+        let addresses = start(config(None)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = LifetimeOptions {
+            max: Duration::from_millis(50),
+            resolution: Duration::from_millis(25),
+            timeout: Duration::from_millis(90),
+        };
+
+        let discovered = binding_lifetime(&socket, &probe, addresses[0], &options)
+            .await
+            .unwrap();
+
+        assert_eq!(discovered.lifetime, options.max);
+    }
+}
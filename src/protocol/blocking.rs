@@ -0,0 +1,128 @@
+//! A std driver for `Protocol` that runs a thread per listener, blocking on
+//! `std::net::UdpSocket`, for embedding without an async runtime.
+
+use crate::{
+    error::{Error, Result},
+    protocol::{Protocol, Transmit},
+    utils::MAX_DATAGRAM_SIZE,
+};
+use std::{
+    io::ErrorKind,
+    net::UdpSocket,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+/// Serve `sockets`, bound in the listener order `protocol` was created with,
+/// until one of them fails.
+pub fn run(sockets: Vec<UdpSocket>, protocol: Protocol) -> Result<()> {
+    let sockets = Arc::new(sockets);
+    let protocol = Arc::new(Mutex::new(protocol));
+    let (sender, receiver) = mpsc::channel();
+
+    for index in 0..sockets.len() {
+        let (sockets, protocol, sender) = (sockets.clone(), protocol.clone(), sender.clone());
+
+        thread::spawn(move || sender.send(serve(&sockets, &protocol, index)));
+    }
+
+    receiver.recv().map_err(|e| Error::Receive(e.to_string()))?
+}
+
+fn serve(sockets: &[UdpSocket], protocol: &Mutex<Protocol>, index: usize) -> Result<()> {
+    let socket = &sockets[index];
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let timeout = protocol.lock().unwrap().poll_timeout();
+        let timeout = timeout.map(|timeout| timeout.saturating_duration_since(Instant::now()));
+
+        socket
+            .set_read_timeout(timeout.filter(|timeout| !timeout.is_zero()))
+            .map_err(|e| Error::Receive(e.to_string()))?;
+
+        let transmits: Vec<_> = match socket.recv_from(&mut buf) {
+            Ok((bytes_received, client_address)) => {
+                let mut protocol = protocol.lock().unwrap();
+
+                // a datagram that isn't valid mustn't stop the listener
+                if let Err(e) = protocol.handle_datagram(
+                    &buf[..bytes_received],
+                    index,
+                    client_address,
+                    Instant::now(),
+                ) {
+                    log::debug!("dropping a datagram from {}: {}", client_address, e);
+                }
+
+                std::iter::from_fn(|| protocol.poll_transmit()).collect()
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                let mut protocol = protocol.lock().unwrap();
+                protocol.handle_timeout(Instant::now());
+
+                std::iter::from_fn(|| protocol.poll_transmit()).collect()
+            }
+            Err(e) => return Err(Error::Receive(e.to_string())),
+        };
+
+        send(sockets, transmits);
+    }
+}
+
+/// Send the encoded responses to the clients.  A response that can't be sent
+/// (e.g. after an ICMP unreachable) is dropped, without stopping the
+/// listener.
+fn send(sockets: &[UdpSocket], transmits: Vec<Transmit>) {
+    for transmit in transmits {
+        if let Err(e) =
+            sockets[transmit.listener].send_to(transmit.contents.as_ref(), transmit.destination)
+        {
+            log::warn!("dropping a response to {}: {}", transmit.destination, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{blocking::StunClient, tests::policy};
+
+    #[test]
+    fn it_serves_binding_requests() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let protocol = Protocol::new(vec![server]).unwrap();
+        thread::spawn(move || run(vec![socket], protocol));
+
+        let mut client = StunClient::new(UdpSocket::bind("127.0.0.1:0").unwrap(), policy());
+        let mapped_address = client.binding(server).unwrap();
+
+        assert_eq!(mapped_address, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn it_drops_a_response_that_cannot_be_sent() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transmit = |destination| Transmit {
+            listener: 0,
+            destination,
+            contents: bytes::Bytes::from_static(b"response"),
+        };
+
+        // an IPv6 destination can't be reached from an IPv4 socket
+        send(
+            &[socket],
+            vec![
+                transmit("[2001:db8::1]:3478".parse().unwrap()),
+                transmit(client.local_addr().unwrap()),
+            ],
+        );
+
+        let mut buf = [0u8; 16];
+        let (bytes_received, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..bytes_received], b"response");
+    }
+}
@@ -0,0 +1,167 @@
+//! Demultiplexing STUN from the other protocols sharing its UDP port.
+//!
+//! A media server commonly carries STUN, DTLS, SRTP and TURN ChannelData on
+//! a single port.  RFC 7983 section 7 tells them apart by the first byte of
+//! each datagram:
+//!
+//! ```text
+//!                  +----------------+
+//!                  |        [0..3] -+--> forward to STUN
+//!                  |                |
+//!                  |      [16..19] -+--> forward to ZRTP
+//!                  |                |
+//!      packet -->  |      [20..63] -+--> forward to DTLS
+//!                  |                |
+//!                  |      [64..79] -+--> forward to TURN Channel
+//!                  |                |
+//!                  |    [128..191] -+--> forward to RTP/RTCP
+//!                  +----------------+
+//! ```
+//!
+//! `Protocol` answers the STUN datagrams itself and hands every other kind to
+//! the `Sink` registered for it, dropping the kinds nobody registered for.
+
+use bytes::Bytes;
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+/// The protocol a datagram belongs to, by its first byte (RFC 7983).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Kind {
+    Stun,
+    Zrtp,
+    Dtls,
+    /// TURN ChannelData (RFC 8656 section 12.4).
+    ChannelData,
+    /// RTP or RTCP, e.g. SRTP media.
+    Rtp,
+    /// Outside every range RFC 7983 assigns, which must be dropped.
+    Unknown,
+}
+
+impl Kind {
+    pub fn of(datagram: &[u8]) -> Self {
+        match datagram.first() {
+            Some(0..=3) => Kind::Stun,
+            Some(16..=19) => Kind::Zrtp,
+            Some(20..=63) => Kind::Dtls,
+            Some(64..=79) => Kind::ChannelData,
+            Some(128..=191) => Kind::Rtp,
+            _ => Kind::Unknown,
+        }
+    }
+}
+
+/// A datagram that isn't STUN, handed to the sink registered for its kind.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Datagram {
+    pub kind: Kind,
+    /// The index of the listener it was received on.
+    pub listener: usize,
+    pub source: SocketAddr,
+    pub contents: Bytes,
+}
+
+/// Receives the datagrams of one kind.  It's called while the protocol is
+/// handling the datagram, so it should hand the datagram off (e.g. to a
+/// channel) rather than block.
+pub trait Sink: Send {
+    fn receive(&mut self, datagram: Datagram);
+}
+
+impl<F: FnMut(Datagram) + Send> Sink for F {
+    fn receive(&mut self, datagram: Datagram) {
+        self(datagram)
+    }
+}
+
+/// The sink of each kind that isn't answered as STUN.
+#[derive(Default)]
+pub(crate) struct Sinks(HashMap<Kind, Box<dyn Sink>>);
+
+impl fmt::Debug for Sinks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl Sinks {
+    pub(crate) fn insert(&mut self, kind: Kind, sink: Box<dyn Sink>) {
+        self.0.insert(kind, sink);
+    }
+
+    pub(crate) fn contains(&self, kind: Kind) -> bool {
+        self.0.contains_key(&kind)
+    }
+
+    /// Hand `datagram` to the sink of its kind, or drop it if there's none.
+    pub(crate) fn dispatch(&mut self, datagram: Datagram) {
+        match self.0.get_mut(&datagram.kind) {
+            Some(sink) => sink.receive(datagram),
+            None => log::debug!(
+                "dropping a {:?} datagram of {} bytes from {}",
+                datagram.kind,
+                datagram.contents.len(),
+                datagram.source
+            ),
+        }
+    }
+
+    pub(crate) fn extend(&mut self, sinks: Sinks) {
+        self.0.extend(sinks.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::tests::BINDING_REQUEST;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn it_classifies_by_the_first_byte() {
+        for (first, kind) in [
+            (0, Kind::Stun),
+            (3, Kind::Stun),
+            (4, Kind::Unknown),
+            (16, Kind::Zrtp),
+            (19, Kind::Zrtp),
+            (20, Kind::Dtls),
+            (63, Kind::Dtls),
+            (64, Kind::ChannelData),
+            (79, Kind::ChannelData),
+            (80, Kind::Unknown),
+            (128, Kind::Rtp),
+            (191, Kind::Rtp),
+            (192, Kind::Unknown),
+        ] {
+            assert_eq!(Kind::of(&[first, 0, 0, 0]), kind, "first byte {}", first);
+        }
+
+        assert_eq!(Kind::of(BINDING_REQUEST), Kind::Stun);
+        assert_eq!(Kind::of(&[]), Kind::Unknown);
+    }
+
+    #[test]
+    fn it_dispatches_to_the_sink_of_the_kind() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut sinks = Sinks::default();
+        let sink = received.clone();
+        sinks.insert(
+            Kind::Rtp,
+            Box::new(move |datagram: Datagram| sink.lock().unwrap().push(datagram)),
+        );
+
+        for first in [128, 22] {
+            sinks.dispatch(Datagram {
+                kind: Kind::of(&[first]),
+                listener: 0,
+                source: "127.0.0.1:5000".parse().unwrap(),
+                contents: Bytes::from(vec![first]),
+            });
+        }
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].contents, &[128][..]);
+    }
+}
@@ -0,0 +1,474 @@
+//! Answering STUN on a socket the application owns.
+//!
+//! An ICE-lite SFU or media server already reads every datagram of its
+//! socket and only needs the Binding responses.  `handle` takes a received
+//! datagram and its source address, and returns the encoded response (or
+//! says the datagram isn't STUN), leaving the application in charge of
+//! sending.  It's the same code `Protocol` answers the server's own sockets
+//! with.
+//!
+//! Given `Credentials`, requests must use the short-term credential mechanism
+//! (RFC 8489 section 9.1.3): a request without both USERNAME and
+//! MESSAGE-INTEGRITY is rejected with 400, one with an unknown username or a
+//! MESSAGE-INTEGRITY that doesn't match its password with 401.  Responses to
+//! authenticated requests carry MESSAGE-INTEGRITY with the same password.
+
+use crate::{
+    error::Result,
+    message::attribute::{
+        Attribute, ATTRIBUTE_HEADER_LENGTH, CHANGE_REQUEST, COMPREHENSION_OPTIONAL,
+    },
+    message::class::Class,
+    message::integrity,
+    message::transaction_id::TransactionId,
+    message::{Message, MESSAGE_HEADER_LENGTH},
+    protocol::demux::Kind,
+    utils::Address,
+};
+use bytes::{Bytes, BytesMut};
+use std::net::SocketAddr;
+
+/// RESPONSE-PORT may not direct responses at well-known ports, which keeps the
+/// server from being used to reflect traffic at other services on the client.
+const MIN_RESPONSE_PORT: u16 = 1024;
+
+/// Padded responses are kept within a 1500-byte path MTU, less the IPv6 and
+/// UDP headers.
+const MAX_PADDED_RESPONSE_LENGTH: usize = 1500 - 40 - 8;
+
+/// Room left in a padded response for MESSAGE-INTEGRITY and FINGERPRINT,
+/// which are added after it's built.
+const SIGNATURE_LENGTH: usize = 24 + 8;
+
+/// Looks up short-term credentials.
+pub trait Credentials {
+    /// The password of `username`, or `None` if it's unknown.
+    fn password(&self, username: &str) -> Option<String>;
+}
+
+impl<F: Fn(&str) -> Option<String>> Credentials for F {
+    fn password(&self, username: &str) -> Option<String> {
+        self(username)
+    }
+}
+
+/// What to do about a received datagram.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Handled {
+    /// Send `contents` to `destination`, which is the source unless the
+    /// request had a RESPONSE-PORT.
+    Reply {
+        destination: SocketAddr,
+        contents: Bytes,
+    },
+    /// STUN that isn't answered, i.e. indications and responses.
+    NoReply,
+    /// Not STUN (RFC 7983), e.g. DTLS or media sharing the socket.
+    NotStun,
+}
+
+/// Answer a datagram received from `source`, authenticating requests with
+/// `credentials` if given.  Errors when the datagram looks like STUN but
+/// can't be decoded, e.g. a request of a method other than Binding, in which
+/// case it should be dropped.
+pub fn handle(
+    datagram: &[u8],
+    source: SocketAddr,
+    credentials: Option<&dyn Credentials>,
+) -> Result<Handled> {
+    if Kind::of(datagram) != Kind::Stun {
+        return Ok(Handled::NotStun);
+    }
+
+    Ok(match respond(datagram, &[], 0, source, credentials)? {
+        Some(response) => Handled::Reply {
+            destination: response.destination,
+            contents: response.contents,
+        },
+        None => Handled::NoReply,
+    })
+}
+
+/// An encoded response, with the index of the listener to send it from and
+/// the address to send it to.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) listener: usize,
+    pub(crate) destination: SocketAddr,
+    pub(crate) contents: Bytes,
+}
+
+/// Decode and answer a STUN datagram received on `listeners[index]`.
+/// `listeners` holds the advertised addresses of the four RFC 5780
+/// listeners, or of a single one (or none, when it isn't known).
+pub(crate) fn respond(
+    datagram: &[u8],
+    listeners: &[SocketAddr],
+    index: usize,
+    source: SocketAddr,
+    credentials: Option<&dyn Credentials>,
+) -> Result<Option<Response>> {
+    let mut bytes = Bytes::copy_from_slice(datagram);
+    let request = Message::decode(&mut bytes)?;
+
+    log::info!(
+        "received {} bytes from {}: {:?}",
+        datagram.len(),
+        source,
+        request
+    );
+
+    // Binding is the only method, and only its requests are answered
+    if request.class != Class::Request {
+        return Ok(None);
+    }
+
+    let (key, (listener, destination, message)) =
+        match authenticate(datagram, &request, credentials) {
+            Ok(key) => (key, binding_response(listeners, index, &request, source)),
+            Err(message) => (None, (index, source, message)),
+        };
+
+    Ok(Some(Response {
+        listener,
+        destination,
+        contents: encode(&message, key.as_deref())?.freeze(),
+    }))
+}
+
+/// Encode a response, signed with `key` if the request was authenticated.
+pub(crate) fn encode(message: &Message, key: Option<&str>) -> Result<BytesMut> {
+    log::info!("sending message to client: {:?}", message);
+
+    let mut buf = BytesMut::new();
+    message.encode(&mut buf)?;
+
+    if let Some(key) = key {
+        integrity::sign(&mut buf, key.as_bytes());
+    }
+
+    Ok(buf)
+}
+
+/// The password to sign the response with, or the error response that
+/// rejects the request.
+pub(crate) fn authenticate<'a>(
+    datagram: &[u8],
+    request: &Message,
+    credentials: Option<&dyn Credentials>,
+) -> std::result::Result<Option<String>, Message<'a>> {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+    let username = request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::Username(username) => Some(*username),
+            _ => None,
+        });
+    let has_integrity = request
+        .attributes
+        .iter()
+        .any(|attribute| matches!(attribute, Attribute::MessageIntegrity(_)));
+
+    let username = match (username, has_integrity) {
+        (Some(username), true) => username,
+        _ => {
+            return Err(error_response(
+                request.transaction_id,
+                400,
+                "Bad Request",
+                vec![],
+            ))
+        }
+    };
+
+    match credentials.password(username) {
+        Some(password) if integrity::verify(datagram, password.as_bytes()) => Ok(Some(password)),
+        _ => Err(error_response(
+            request.transaction_id,
+            401,
+            "Unauthorized",
+            vec![],
+        )),
+    }
+}
+
+/// Build the response to a binding request received on `listeners[index]`,
+/// along with the index of the listener the response must be sent from and
+/// the address it must be sent to.
+pub(crate) fn binding_response<'a>(
+    listeners: &[SocketAddr],
+    index: usize,
+    request: &Message,
+    client_address: SocketAddr,
+) -> (usize, SocketAddr, Message<'a>) {
+    let transaction_id = request.transaction_id;
+    let mut change_request = None;
+    let mut response_port = None;
+    let mut padding = None;
+    let mut unknown = vec![];
+
+    for attribute in &request.attributes {
+        match attribute {
+            Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            } => change_request = Some((*change_ip, *change_port)),
+            Attribute::ResponsePort(port) => response_port = Some(*port),
+            Attribute::Padding(length) => padding = Some(*length),
+            Attribute::Unknown { kind, .. } if *kind < COMPREHENSION_OPTIONAL => {
+                unknown.push(*kind)
+            }
+            _ => {}
+        }
+    }
+
+    // without an alternate address CHANGE-REQUEST can't be honoured
+    if listeners.len() < 4 && change_request.is_some() {
+        unknown.push(CHANGE_REQUEST);
+    }
+
+    // comprehension-required attributes the server doesn't understand
+    if !unknown.is_empty() {
+        let message = error_response(
+            transaction_id,
+            420,
+            "Unknown Attribute",
+            vec![Attribute::UnknownAttributes(unknown)],
+        );
+
+        return (index, client_address, message);
+    }
+
+    // RESPONSE-PORT only ever changes the port, never the IP the request came from
+    let destination = match response_port {
+        Some(port) if port < MIN_RESPONSE_PORT => {
+            let message = error_response(transaction_id, 400, "Bad Request", vec![]);
+
+            return (index, client_address, message);
+        }
+        Some(port) => SocketAddr::new(client_address.ip(), port),
+        None => client_address,
+    };
+
+    let (change_ip, change_port) = change_request.unwrap_or((false, false));
+    let reply = index ^ ((change_ip as usize) << 1) ^ (change_port as usize);
+    let mut attributes = vec![Attribute::XorMappedAddress(Address::parse_address(
+        client_address,
+    ))];
+
+    if listeners.len() == 4 {
+        let other = index ^ 0b11;
+
+        attributes.push(Attribute::ResponseOrigin(Address::parse_address(
+            listeners[reply],
+        )));
+        attributes.push(Attribute::OtherAddress(Address::parse_address(
+            listeners[other],
+        )));
+    }
+
+    // pad the response by the request's PADDING, less the other attributes so
+    // the response is never bigger than the request, which would turn the
+    // server into an amplifier, and within a path MTU whatever the request
+    if let Some(length) = padding {
+        let others: usize = attributes
+            .iter()
+            .map(|attribute| attribute.encoded_length(&transaction_id))
+            .sum();
+        let unpadded = MESSAGE_HEADER_LENGTH + others + ATTRIBUTE_HEADER_LENGTH;
+        let length = (length as usize)
+            .saturating_sub(others)
+            .min(MAX_PADDED_RESPONSE_LENGTH.saturating_sub(unpadded + SIGNATURE_LENGTH));
+
+        attributes.push(Attribute::Padding(length as u16));
+    }
+
+    let message = Message::binding_response(transaction_id, attributes);
+
+    (reply, destination, message)
+}
+
+pub(crate) fn error_response<'a>(
+    transaction_id: TransactionId,
+    code: u32,
+    reason: &'a str,
+    mut attributes: Vec<Attribute<'a>>,
+) -> Message<'a> {
+    attributes.insert(0, Attribute::ErrorCode { code, reason });
+
+    Message::binding_error_response(transaction_id, attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "198.51.100.7:40000";
+    const USERNAME: &str = "evtj:h6vY";
+    const PASSWORD: &str = "VOkJxbRl1RmTxUk/WvJxBt";
+
+    fn credentials(username: &str) -> Option<String> {
+        (username == USERNAME).then(|| PASSWORD.to_string())
+    }
+
+    fn request(attributes: Vec<Attribute>, key: Option<&str>) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        Message::binding_request(attributes)
+            .encode(&mut buf)
+            .unwrap();
+
+        if let Some(key) = key {
+            integrity::sign(&mut buf, key.as_bytes());
+        }
+
+        buf.to_vec()
+    }
+
+    fn reply(handled: Handled) -> (SocketAddr, Bytes) {
+        match handled {
+            Handled::Reply {
+                destination,
+                contents,
+            } => (destination, contents),
+            handled => panic!("expected a reply, but got {:?}", handled),
+        }
+    }
+
+    #[test]
+    fn it_answers_a_binding_request() {
+        let source = CLIENT.parse().unwrap();
+        let handled = handle(&request(vec![], None), source, None).unwrap();
+        let (destination, mut contents) = reply(handled);
+        let message = Message::decode(&mut contents).unwrap();
+
+        assert_eq!(destination, source);
+        assert_eq!(message.class, Class::SuccessResponse);
+        assert_eq!(message.mapped_address(), Some(source));
+    }
+
+    #[test]
+    fn it_says_when_a_datagram_is_not_stun() {
+        let source = CLIENT.parse().unwrap();
+
+        assert_eq!(
+            handle(&[22, 0xFE, 0xFD], source, None).unwrap(),
+            Handled::NotStun
+        );
+        assert!(handle(&[0x00, 0x01], source, None).is_err());
+    }
+
+    #[test]
+    fn it_rejects_unknown_comprehension_required_attributes() {
+        let source = CLIENT.parse().unwrap();
+        let required = request(
+            vec![
+                Attribute::Unknown {
+                    kind: 0x0030,
+                    value: &[1, 2, 3, 4],
+                },
+                Attribute::Unknown {
+                    kind: 0x8030,
+                    value: &[],
+                },
+            ],
+            None,
+        );
+        let (_, mut contents) = reply(handle(&required, source, None).unwrap());
+        let message = Message::decode(&mut contents).unwrap();
+
+        assert_eq!(message.class, Class::FailureResponse);
+        assert!(matches!(
+            message.attributes[0],
+            Attribute::ErrorCode { code: 420, .. }
+        ));
+        assert_eq!(
+            message.attributes[1],
+            Attribute::UnknownAttributes(vec![0x0030])
+        );
+
+        // comprehension-optional ones are ignored
+        let optional = request(
+            vec![Attribute::Unknown {
+                kind: 0x8030,
+                value: &[],
+            }],
+            None,
+        );
+        let (_, mut contents) = reply(handle(&optional, source, None).unwrap());
+
+        assert_eq!(
+            Message::decode(&mut contents).unwrap().class,
+            Class::SuccessResponse
+        );
+    }
+
+    #[test]
+    fn it_drops_requests_of_other_methods() {
+        // an Allocate request, which a STUN server doesn't know
+        let mut allocate = request(vec![], None);
+        allocate[1] = 0x03;
+
+        assert!(handle(&allocate, CLIENT.parse().unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn it_does_not_answer_a_response() {
+        let mut buf = BytesMut::new();
+        Message::binding_response(TransactionId::new(), vec![])
+            .encode(&mut buf)
+            .unwrap();
+
+        assert_eq!(
+            handle(&buf, CLIENT.parse().unwrap(), None).unwrap(),
+            Handled::NoReply
+        );
+    }
+
+    #[test]
+    fn it_signs_the_response_to_an_authenticated_request() {
+        let request = request(vec![Attribute::Username(USERNAME)], Some(PASSWORD));
+        let handled = handle(&request, CLIENT.parse().unwrap(), Some(&credentials)).unwrap();
+        let (_, mut contents) = reply(handled);
+
+        assert!(integrity::verify(&contents, PASSWORD.as_bytes()));
+        assert_eq!(
+            Message::decode(&mut contents).unwrap().class,
+            Class::SuccessResponse
+        );
+    }
+
+    #[test]
+    fn it_rejects_requests_that_are_not_authenticated() {
+        let source = CLIENT.parse().unwrap();
+
+        for (request, expected) in [
+            (request(vec![], None), 400),
+            (request(vec![Attribute::Username(USERNAME)], None), 400),
+            (
+                request(vec![Attribute::Username(USERNAME)], Some("wrong")),
+                401,
+            ),
+            (
+                request(vec![Attribute::Username("nobody")], Some(PASSWORD)),
+                401,
+            ),
+        ] {
+            let handled = handle(&request, source, Some(&credentials)).unwrap();
+            let (_, mut contents) = reply(handled);
+
+            assert!(!integrity::verify(&contents, PASSWORD.as_bytes()));
+
+            let message = Message::decode(&mut contents).unwrap();
+
+            assert_eq!(message.class, Class::FailureResponse);
+            assert!(matches!(
+                message.attributes[0],
+                Attribute::ErrorCode { code, .. } if code == expected
+            ));
+        }
+    }
+}
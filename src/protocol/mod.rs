@@ -0,0 +1,338 @@
+//! The server's protocol logic as a sans-IO state machine.
+//!
+//! `Protocol` never touches a socket or a clock.  A driver feeds it each
+//! received datagram with the index of the listener it arrived on, its
+//! source address and the current time, then drains the packets it wants
+//! sent with `poll_transmit`, and wakes it at `poll_timeout`.  This keeps the
+//! decoding and response policy deterministic and testable, and lets it be
+//! embedded in an existing event loop.  The tokio driver lives in
+//! `server`, and a std driver is available with the `blocking` feature.
+//!
+//! Listeners are indexed by IP (bit 1) and port (bit 0), so with the four
+//! RFC 5780 listeners a CHANGE-REQUEST only needs to flip bits of the index.
+//!
+//! Datagrams that aren't STUN, e.g. DTLS or SRTP sharing the port, are
+//! demultiplexed to the sinks registered with `set_sink`, see `demux`.
+
+use crate::error::{Error, Result};
+use bytes::Bytes;
+use demux::{Datagram, Kind, Sink, Sinks};
+use std::{collections::VecDeque, net::SocketAddr, time::Instant};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod demux;
+pub mod handler;
+pub mod stream;
+
+/// A datagram the driver must send.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Transmit {
+    /// The index of the listener to send from.
+    pub listener: usize,
+    pub destination: SocketAddr,
+    pub contents: Bytes,
+}
+
+#[derive(Debug)]
+pub struct Protocol {
+    /// The advertised address of each listener.
+    listeners: Vec<SocketAddr>,
+    transmits: VecDeque<Transmit>,
+    sinks: Sinks,
+}
+
+impl Protocol {
+    /// `listeners` holds the advertised address of either a single listener,
+    /// or the four RFC 5780 listeners in index order.
+    pub fn new(listeners: Vec<SocketAddr>) -> Result<Self> {
+        if listeners.len() != 1 && listeners.len() != 4 {
+            return Err(Error::Arguments(format!(
+                "expected 1 or 4 listeners, but got {}",
+                listeners.len()
+            )));
+        }
+
+        Ok(Self {
+            listeners,
+            transmits: VecDeque::new(),
+            sinks: Sinks::default(),
+        })
+    }
+
+    /// Replace the advertised addresses, e.g. after the external address of a
+    /// 1:1 NAT changed.  The number of listeners can't change.
+    pub fn set_advertised(&mut self, listeners: Vec<SocketAddr>) -> Result<()> {
+        if listeners.len() != self.listeners.len() {
+            return Err(Error::Arguments(format!(
+                "expected {} listeners, but got {}",
+                self.listeners.len(),
+                listeners.len()
+            )));
+        }
+
+        self.listeners = listeners;
+
+        Ok(())
+    }
+
+    /// Hand the datagrams of `kind` to `sink` instead of dropping them.  STUN
+    /// is always answered by the protocol, so it can't have a sink.
+    pub fn set_sink(&mut self, kind: Kind, sink: impl Sink + 'static) -> Result<()> {
+        let mut sinks = Sinks::default();
+        sinks.insert(kind, Box::new(sink));
+
+        self.set_sinks(sinks)
+    }
+
+    pub(crate) fn set_sinks(&mut self, sinks: Sinks) -> Result<()> {
+        if sinks.contains(Kind::Stun) {
+            return Err(Error::Arguments(
+                "STUN datagrams are answered by the protocol".into(),
+            ));
+        }
+
+        self.sinks.extend(sinks);
+
+        Ok(())
+    }
+
+    /// Handle a datagram received on `listeners[listener]` from `source`.
+    /// Errors only concern this datagram, which the driver should drop
+    /// before carrying on with the next one.
+    pub fn handle_datagram(
+        &mut self,
+        datagram: &[u8],
+        listener: usize,
+        source: SocketAddr,
+        _now: Instant,
+    ) -> Result<()> {
+        let kind = Kind::of(datagram);
+
+        if kind != Kind::Stun {
+            self.sinks.dispatch(Datagram {
+                kind,
+                listener,
+                source,
+                contents: Bytes::copy_from_slice(datagram),
+            });
+
+            return Ok(());
+        }
+
+        if let Some(response) = handler::respond(datagram, &self.listeners, listener, source, None)?
+        {
+            self.transmits.push_back(Transmit {
+                listener: response.listener,
+                destination: response.destination,
+                contents: response.contents,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The next datagram to send, if any.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// When `handle_timeout` must next be called.  Binding is a single
+    /// request/response exchange, so nothing is timer driven yet.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        None
+    }
+
+    pub fn handle_timeout(&mut self, _now: Instant) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{attribute::Attribute, tests::BINDING_REQUEST, Message};
+    use crate::utils::Address;
+    use bytes::BytesMut;
+
+    const CLIENT: &str = "198.51.100.7:40000";
+
+    fn listeners() -> Vec<SocketAddr> {
+        [
+            "192.0.2.1:3478",
+            "192.0.2.1:3479",
+            "192.0.2.2:3478",
+            "192.0.2.2:3479",
+        ]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect()
+    }
+
+    fn request(attributes: Vec<Attribute>) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        Message::binding_request(attributes)
+            .encode(&mut buf)
+            .unwrap();
+        buf.to_vec()
+    }
+
+    fn handle(protocol: &mut Protocol, datagram: &[u8], listener: usize) -> Transmit {
+        let source = CLIENT.parse().unwrap();
+        protocol
+            .handle_datagram(datagram, listener, source, Instant::now())
+            .unwrap();
+
+        let transmit = protocol.poll_transmit().unwrap();
+        assert_eq!(protocol.poll_transmit(), None);
+
+        transmit
+    }
+
+    #[test]
+    fn it_requires_one_or_four_listeners() {
+        assert!(Protocol::new(vec![]).is_err());
+        assert!(Protocol::new(listeners()[..2].to_vec()).is_err());
+        assert!(Protocol::new(listeners()).is_ok());
+    }
+
+    #[test]
+    fn it_responds_to_a_binding_request() {
+        let mut protocol = Protocol::new(listeners()[..1].to_vec()).unwrap();
+        let mut transmit = handle(&mut protocol, BINDING_REQUEST, 0);
+        let message = Message::decode(&mut transmit.contents).unwrap();
+
+        assert_eq!(transmit.listener, 0);
+        assert_eq!(transmit.destination, CLIENT.parse().unwrap());
+        assert_eq!(
+            message.transaction_id,
+            crate::message::tests::binding_request().transaction_id
+        );
+        assert_eq!(message.mapped_address(), Some(CLIENT.parse().unwrap()));
+        assert_eq!(protocol.poll_timeout(), None);
+    }
+
+    #[test]
+    fn it_replies_from_the_requested_listener() {
+        let mut protocol = Protocol::new(listeners()).unwrap();
+
+        for (listener, change_ip, change_port, expected) in [
+            (0, true, true, 3),
+            (3, true, false, 1),
+            (2, false, true, 3),
+            (1, false, false, 1),
+        ] {
+            let change_request = Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            };
+            let mut transmit = handle(&mut protocol, &request(vec![change_request]), listener);
+            let message = Message::decode(&mut transmit.contents).unwrap();
+
+            assert_eq!(transmit.listener, expected);
+            assert_eq!(
+                message.attributes[1],
+                Attribute::ResponseOrigin(Address::parse_address(listeners()[expected]))
+            );
+            assert_eq!(message.other_address(), Some(listeners()[listener ^ 0b11]));
+        }
+    }
+
+    #[test]
+    fn it_advertises_replaced_addresses() {
+        let mut protocol = Protocol::new(listeners()).unwrap();
+        let mut replaced = listeners();
+        replaced.reverse();
+
+        assert!(protocol.set_advertised(replaced[..1].to_vec()).is_err());
+        protocol.set_advertised(replaced.clone()).unwrap();
+
+        let mut transmit = handle(&mut protocol, BINDING_REQUEST, 0);
+        let message = Message::decode(&mut transmit.contents).unwrap();
+
+        assert_eq!(message.other_address(), Some(replaced[3]));
+    }
+
+    #[test]
+    fn it_sends_to_the_response_port_on_the_source_ip() {
+        let mut protocol = Protocol::new(listeners()[..1].to_vec()).unwrap();
+        let transmit = handle(
+            &mut protocol,
+            &request(vec![Attribute::ResponsePort(50000)]),
+            0,
+        );
+
+        assert_eq!(transmit.destination, "198.51.100.7:50000".parse().unwrap());
+    }
+
+    #[test]
+    fn it_ignores_binding_indications() {
+        let mut protocol = Protocol::new(listeners()[..1].to_vec()).unwrap();
+        let source = CLIENT.parse().unwrap();
+        let mut buf = BytesMut::new();
+        Message::binding_indication(vec![])
+            .encode(&mut buf)
+            .unwrap();
+
+        protocol
+            .handle_datagram(&buf, 0, source, Instant::now())
+            .unwrap();
+
+        assert_eq!(protocol.poll_transmit(), None);
+    }
+
+    #[test]
+    fn it_errors_on_an_invalid_datagram() {
+        let mut protocol = Protocol::new(listeners()[..1].to_vec()).unwrap();
+        let source = CLIENT.parse().unwrap();
+        let result = protocol.handle_datagram(&[0x00, 0x01], 0, source, Instant::now());
+
+        assert!(matches!(result, Err(Error::Decode(_))));
+        assert_eq!(protocol.poll_transmit(), None);
+    }
+
+    #[test]
+    fn it_drops_a_request_of_another_method() {
+        let mut protocol = Protocol::new(listeners()[..1].to_vec()).unwrap();
+        let source = CLIENT.parse().unwrap();
+
+        // a TURN Allocate request
+        let mut allocate = BINDING_REQUEST.to_vec();
+        allocate[1] = 0x03;
+        let result = protocol.handle_datagram(&allocate, 0, source, Instant::now());
+
+        assert!(matches!(result, Err(Error::Decode(_))));
+        assert_eq!(protocol.poll_transmit(), None);
+
+        // and carries on answering Binding requests
+        let transmit = handle(&mut protocol, BINDING_REQUEST, 0);
+        assert_eq!(transmit.destination, source);
+    }
+
+    #[test]
+    fn it_demultiplexes_datagrams_that_are_not_stun() {
+        let mut protocol = Protocol::new(listeners()[..1].to_vec()).unwrap();
+        let source = CLIENT.parse().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        protocol
+            .set_sink(Kind::Dtls, move |datagram| sender.send(datagram).unwrap())
+            .unwrap();
+
+        // a DTLS ClientHello record, and an RTP packet nobody sinks
+        for datagram in [&[22, 0xFE, 0xFD][..], &[0x80, 0x00]] {
+            protocol
+                .handle_datagram(datagram, 0, source, Instant::now())
+                .unwrap();
+        }
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![Datagram {
+                kind: Kind::Dtls,
+                listener: 0,
+                source,
+                contents: Bytes::from_static(&[22, 0xFE, 0xFD]),
+            }]
+        );
+        assert_eq!(protocol.poll_transmit(), None);
+        assert!(protocol.set_sink(Kind::Stun, |_| {}).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Framing STUN messages on a stream transport, e.g. TCP or TLS-over-TCP.
+//!
+//! For TCP and TLS-over-TCP, the client opens a TCP connection to the server.
+//! STUN messages are sent on the stream without any additional framing: the
+//! Message Length field of the 20-byte header says where each one ends
+//! (RFC 8489 section 6.2.2).  The `Framer` buffers what was read from the
+//! stream and splits it into messages, without touching any IO itself.
+
+use crate::{
+    error::{Error, Result},
+    message::{MAGIC_COOKIE, MESSAGE_HEADER_LENGTH},
+};
+use bytes::{Buf, Bytes, BytesMut};
+
+#[derive(Debug, Default)]
+pub struct Framer {
+    buf: BytesMut,
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffer bytes read from the stream.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The next complete message, if one has been buffered.  Errors when the
+    /// stream doesn't carry STUN, after which the stream should be closed as
+    /// there's no way to find the next message.
+    pub fn next_frame(&mut self) -> Result<Option<Bytes>> {
+        if self.buf.first().is_some_and(|byte| byte & 0xC0 != 0) {
+            return Err(Error::Decode(
+                "the first two bits of a STUN message must be zero".into(),
+            ));
+        }
+
+        if self.buf.len() < MESSAGE_HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let magic_cookie = (&self.buf[4..8]).get_u32();
+
+        if magic_cookie != MAGIC_COOKIE {
+            return Err(Error::Decode(format!(
+                "expected the magic cookie {:#x}, but got {:#x}",
+                MAGIC_COOKIE, magic_cookie
+            )));
+        }
+
+        let length = MESSAGE_HEADER_LENGTH + (&self.buf[2..4]).get_u16() as usize;
+
+        if self.buf.len() < length {
+            return Ok(None);
+        }
+
+        Ok(Some(self.buf.split_to(length).freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::tests::BINDING_REQUEST;
+
+    #[test]
+    fn it_frames_split_and_coalesced_messages() {
+        let mut framer = Framer::new();
+        let mut stream = BINDING_REQUEST.repeat(2);
+        stream.extend_from_slice(&BINDING_REQUEST[..7]);
+
+        framer.extend(&stream[..15]);
+        assert_eq!(framer.next_frame().unwrap(), None);
+
+        framer.extend(&stream[15..]);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), &BINDING_REQUEST[..]);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), &BINDING_REQUEST[..]);
+        assert_eq!(framer.next_frame().unwrap(), None);
+
+        framer.extend(&BINDING_REQUEST[7..]);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), &BINDING_REQUEST[..]);
+    }
+
+    #[test]
+    fn it_rejects_a_stream_that_is_not_stun() {
+        let mut framer = Framer::new();
+        framer.extend(b"GET / HTTP/1.1\r\n\r\n");
+
+        assert!(matches!(framer.next_frame(), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn it_rejects_a_missing_magic_cookie() {
+        let mut framer = Framer::new();
+        let mut message = BINDING_REQUEST.to_vec();
+        message[4] = 0;
+        framer.extend(&message);
+
+        assert!(matches!(framer.next_frame(), Err(Error::Decode(_))));
+    }
+}
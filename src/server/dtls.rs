@@ -0,0 +1,670 @@
+//! STUN over DTLS, for `stuns:` URIs over UDP (RFC 7350), on port 5349 by
+//! default.
+//!
+//! The DTLS association is established with the `dtls_server` address and
+//! each STUN message is sent in its own DTLS record, so no framing is needed
+//! beyond what DTLS does.  Sessions are kept per 5-tuple: the first
+//! handshake record from a new client address starts a session, datagrams
+//! from an established client are handed to its session, and anything else
+//! is dropped.  A session that doesn't complete its handshake within the
+//! handshake timeout, or that stays quiet for the idle timeout, is dropped
+//! so its client has to handshake again.
+//!
+//! The number of sessions is capped.  Each session answers the first
+//! ClientHello with a HelloVerifyRequest cookie, and its client is only
+//! known to own its address once the handshake completes, which it can't
+//! without echoing that cookie.  Until then the session is unverified, and
+//! a new client evicts an unverified session when the listener is full, so
+//! spoofed ClientHellos can't crowd out verified clients.
+//!
+//! Decrypted messages are answered by a single-listener `Protocol` shared by
+//! the DTLS listener, exactly as the TLS listener does, and responses are
+//! always sent back on the session they arrived on.
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    protocol::Protocol,
+    utils::MAX_DATAGRAM_SIZE,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+    task::{AbortHandle, JoinSet},
+    time,
+};
+use webrtc_dtls::{
+    config::{Config as DtlsConfig, ExtendedMasterSecretType},
+    conn::DTLSConn,
+    crypto::{Certificate, CryptoPrivateKey},
+};
+use webrtc_util::Conn;
+
+/// How long a session is kept without receiving anything.
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The content type of DTLS handshake records (RFC 6347 section 4.1), the
+/// only records that can start a session.
+const HANDSHAKE: u8 = 22;
+
+/// How many datagrams are queued for a session before more are dropped.
+const SESSION_BACKLOG: usize = 32;
+
+/// How many sessions a listener keeps, verified or not.
+pub(crate) const MAX_SESSIONS: usize = 1024;
+
+/// A bound DTLS listener, with the protocol answering its sessions.
+#[derive(Debug)]
+pub(crate) struct DtlsListener {
+    pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) acceptor: Acceptor,
+    pub(crate) protocol: Arc<Mutex<Protocol>>,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max_sessions: usize,
+}
+
+/// The certificate of a running listener, which can be swapped on reload.
+/// New sessions use the latest one.
+#[derive(Clone)]
+pub(crate) struct Acceptor(Arc<RwLock<DtlsConfig>>);
+
+impl fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Acceptor").finish_non_exhaustive()
+    }
+}
+
+impl Acceptor {
+    fn current(&self) -> DtlsConfig {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn replace(&self, config: DtlsConfig) {
+        *self.0.write().unwrap() = config;
+    }
+}
+
+/// Bind the `dtls_server` listener, if one is configured.
+pub(crate) async fn bind(
+    config: &Config,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    max_sessions: usize,
+) -> Result<Option<DtlsListener>> {
+    let address: SocketAddr = match &config.dtls_server {
+        Some(dtls_server) => dtls_server.parse()?,
+        None => return Ok(None),
+    };
+    let acceptor = acceptor(config)?;
+    let socket = UdpSocket::bind(address)
+        .await
+        .map_err(|e| Error::Startup(e.to_string()))?;
+    let local_addr = socket
+        .local_addr()
+        .map_err(|e| Error::Startup(e.to_string()))?;
+    let advertised = config.advertised_addresses(&[local_addr])?[0];
+
+    log::info!(
+        "Started stun server on dtls://{}, advertised as {}",
+        local_addr,
+        advertised
+    );
+
+    Ok(Some(DtlsListener {
+        socket: Arc::new(socket),
+        acceptor: Acceptor(Arc::new(RwLock::new(acceptor))),
+        protocol: Arc::new(Mutex::new(Protocol::new(vec![advertised])?)),
+        handshake_timeout,
+        idle_timeout,
+        max_sessions,
+    }))
+}
+
+/// Load the PEM certificate chain and PKCS#8 private key of `config`.
+pub(crate) fn acceptor(config: &Config) -> Result<DtlsConfig> {
+    let (certificate, private_key) = match (&config.dtls_certificate, &config.dtls_private_key) {
+        (Some(certificate), Some(private_key)) => (certificate, private_key),
+        _ => {
+            return Err(Error::Config(
+                "dtls_server needs both dtls_certificate and dtls_private_key".into(),
+            ))
+        }
+    };
+
+    let certificates = rustls_pemfile::certs(&mut super::pem(certificate)?)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| Error::Config(format!("{}: {}", certificate, e)))?;
+
+    if certificates.is_empty() {
+        return Err(Error::Config(format!(
+            "{}: no PEM certificates found",
+            certificate
+        )));
+    }
+
+    let key = rustls_pemfile::pkcs8_private_keys(&mut super::pem(private_key)?)
+        .next()
+        .ok_or_else(|| Error::Config(format!("{}: no PKCS#8 PEM private key found", private_key)))?
+        .map_err(|e| Error::Config(format!("{}: {}", private_key, e)))?;
+    let key_pair = rcgen::KeyPair::try_from(key.secret_pkcs8_der())
+        .map_err(|e| Error::Config(format!("{}: {}", private_key, e)))?;
+    let key = CryptoPrivateKey::from_key_pair(&key_pair)
+        .map_err(|e| Error::Config(format!("{}: {}", private_key, e)))?;
+
+    Ok(DtlsConfig {
+        certificates: vec![Certificate {
+            certificate: certificates,
+            private_key: key,
+        }],
+        extended_master_secret: ExtendedMasterSecretType::Request,
+        ..DtlsConfig::default()
+    })
+}
+
+/// The channel to a running session, and whether its handshake completed,
+/// which the session sets.
+struct Session {
+    sender: mpsc::Sender<Bytes>,
+    task: AbortHandle,
+    verified: Arc<AtomicBool>,
+}
+
+/// Receive datagrams and hand them to their session until the server is
+/// shut down, which also drops the open sessions.
+pub(crate) async fn run(listener: DtlsListener, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    while !*shutdown.borrow() {
+        tokio::select! {
+            received = listener.socket.recv_from(&mut buf) => {
+                let (bytes_received, peer) = match received {
+                    Ok(received) => received,
+                    // e.g. an ICMP error for an earlier send, which shouldn't
+                    // stop the listener
+                    Err(e) => {
+                        log::warn!("error receiving on the DTLS listener: {}", e);
+                        continue;
+                    }
+                };
+                let datagram = Bytes::copy_from_slice(&buf[..bytes_received]);
+
+                // a finished session is replaced by the next handshake
+                if let Some(session) = sessions
+                    .get_mut(&peer)
+                    .filter(|session| !session.sender.is_closed())
+                {
+                    // like any UDP socket, drop what can't be queued
+                    let _ = session.sender.try_send(datagram);
+                    continue;
+                }
+
+                if datagram.first() != Some(&HANDSHAKE) {
+                    log::debug!("dropping a datagram from {} without a DTLS session", peer);
+                    continue;
+                }
+
+                if sessions.len() >= listener.max_sessions && !evict(&mut sessions) {
+                    log::debug!("dropping a handshake from {}: too many DTLS sessions", peer);
+                    continue;
+                }
+
+                let (sender, receiver) = mpsc::channel(SESSION_BACKLOG);
+                let verified = Arc::new(AtomicBool::new(false));
+                let _ = sender.try_send(datagram);
+                let task = tasks.spawn(session(
+                    SessionConn {
+                        socket: listener.socket.clone(),
+                        peer,
+                        receiver: tokio::sync::Mutex::new(receiver),
+                    },
+                    listener.acceptor.current(),
+                    listener.protocol.clone(),
+                    listener.handshake_timeout,
+                    listener.idle_timeout,
+                    verified.clone(),
+                ));
+                sessions.insert(peer, Session { sender, task, verified });
+            }
+            Some(Ok(peer)) = tasks.join_next(), if !tasks.is_empty() => {
+                if sessions.get(&peer).is_some_and(|session| session.sender.is_closed()) {
+                    sessions.remove(&peer);
+                }
+            }
+            Ok(()) = shutdown.changed() => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Make room for a new session by dropping a finished or unverified one.
+/// Returns false if every session is verified and running.
+fn evict(sessions: &mut HashMap<SocketAddr, Session>) -> bool {
+    let evicted = sessions
+        .iter()
+        .find(|(_, session)| session.sender.is_closed())
+        .or_else(|| {
+            sessions
+                .iter()
+                .find(|(_, session)| !session.verified.load(Ordering::Relaxed))
+        })
+        .map(|(peer, _)| *peer);
+
+    match evicted.and_then(|peer| sessions.remove(&peer)) {
+        Some(session) => {
+            session.task.abort();
+            true
+        }
+        None => false,
+    }
+}
+
+/// The datagrams of one client, as a connection the DTLS session runs on.
+struct SessionConn {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<Bytes>>,
+}
+
+#[async_trait]
+impl Conn for SessionConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc_util::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        let datagram = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+        let length = datagram.len().min(buf.len());
+        buf[..length].copy_from_slice(&datagram[..length]);
+
+        Ok(length)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        Ok((self.recv(buf).await?, self.peer))
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        Ok(self.socket.send_to(buf, self.peer).await?)
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> webrtc_util::Result<usize> {
+        self.send(buf).await
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer)
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.receiver.lock().await.close();
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+async fn session(
+    conn: SessionConn,
+    config: DtlsConfig,
+    protocol: Arc<Mutex<Protocol>>,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    verified: Arc<AtomicBool>,
+) -> SocketAddr {
+    let peer = conn.peer;
+    let served = serve(
+        conn,
+        config,
+        protocol,
+        handshake_timeout,
+        idle_timeout,
+        verified,
+    );
+
+    match served.await {
+        Ok(()) => log::debug!("DTLS session with {} closed", peer),
+        Err(e) => log::debug!("closing the DTLS session with {}: {}", peer, e),
+    }
+
+    peer
+}
+
+/// Answer each STUN message received on the session, until it's closed or
+/// idle.  `verified` is set once the handshake completes.
+async fn serve(
+    conn: SessionConn,
+    config: DtlsConfig,
+    protocol: Arc<Mutex<Protocol>>,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    verified: Arc<AtomicBool>,
+) -> Result<()> {
+    let peer = conn.peer;
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(conn);
+    let session = match time::timeout(
+        handshake_timeout,
+        DTLSConn::new(conn.clone(), config, false, None),
+    )
+    .await
+    {
+        Ok(session) => session.map_err(|e| Error::Receive(e.to_string())),
+        Err(_) => Err(Error::Receive(format!(
+            "no DTLS handshake within {:?}",
+            handshake_timeout
+        ))),
+    };
+    let session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            // also stops the half-done handshake
+            let _ = conn.close().await;
+            return Err(e);
+        }
+    };
+
+    log::debug!("DTLS session with {}", peer);
+    verified.store(true, Ordering::Relaxed);
+
+    let answered = answer(&session, peer, protocol, idle_timeout).await;
+    let _ = session.close().await;
+
+    answered
+}
+
+async fn answer(
+    session: &DTLSConn,
+    peer: SocketAddr,
+    protocol: Arc<Mutex<Protocol>>,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let bytes_received = match time::timeout(idle_timeout, session.read(&mut buf, None)).await {
+            Ok(read) => read.map_err(|e| Error::Receive(e.to_string()))?,
+            Err(_) => return Ok(()),
+        };
+
+        for transmit in super::respond(&protocol, &buf[..bytes_received], peer)? {
+            session
+                .write(&transmit.contents, None)
+                .await
+                .map_err(|e| Error::BindingResponse(e.to_string()))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{class::Class, Message},
+        server::{
+            tests::{identity, Identity},
+            Server,
+        },
+    };
+    use bytes::BytesMut;
+
+    async fn start(identity: &Identity, idle_timeout: Duration, max_sessions: usize) -> Server {
+        Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .dtls(
+                "127.0.0.1:0".parse().unwrap(),
+                &identity.certificate,
+                &identity.private_key,
+            )
+            .dtls_idle_timeout(idle_timeout)
+            .dtls_max_sessions(max_sessions)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    async fn connect(server: SocketAddr) -> (DTLSConn, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let config = DtlsConfig {
+            insecure_skip_verify: true,
+            server_name: "localhost".into(),
+            extended_master_secret: ExtendedMasterSecretType::Require,
+            ..DtlsConfig::default()
+        };
+        let session = time::timeout(
+            Duration::from_secs(5),
+            DTLSConn::new(Arc::new(socket), config, true, None),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        (session, local_addr)
+    }
+
+    /// Send a Binding request on the session, returning the mapped address
+    /// of the success response.
+    async fn binding(session: &DTLSConn) -> Option<SocketAddr> {
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+        session.write(&buf, None).await.unwrap();
+
+        let mut read = vec![0u8; MAX_DATAGRAM_SIZE];
+        let bytes_received = time::timeout(Duration::from_secs(1), session.read(&mut read, None))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut response = Bytes::copy_from_slice(&read[..bytes_received]);
+        let message = Message::decode(&mut response).unwrap();
+
+        assert_eq!(message.class, Class::SuccessResponse);
+
+        message.mapped_address()
+    }
+
+    #[tokio::test]
+    async fn it_answers_binding_requests_over_dtls() {
+        let identity = identity();
+        let server = start(&identity, IDLE_TIMEOUT, MAX_SESSIONS).await;
+        let (session, local_addr) = connect(server.dtls_local_addr().unwrap()).await;
+
+        for _ in 0..2 {
+            assert_eq!(binding(&session).await, Some(local_addr));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_a_session_per_client() {
+        let identity = identity();
+        let server = start(&identity, IDLE_TIMEOUT, MAX_SESSIONS).await;
+        let (first, first_addr) = connect(server.dtls_local_addr().unwrap()).await;
+        let (second, second_addr) = connect(server.dtls_local_addr().unwrap()).await;
+
+        assert_eq!(binding(&second).await, Some(second_addr));
+        assert_eq!(binding(&first).await, Some(first_addr));
+    }
+
+    /// The first ClientHello of a client, without a cookie.
+    async fn client_hello() -> Bytes {
+        let capture = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let handshake = tokio::spawn(connect(capture.local_addr().unwrap()));
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let (bytes_received, _) = capture.recv_from(&mut buf).await.unwrap();
+        handshake.abort();
+
+        Bytes::copy_from_slice(&buf[..bytes_received])
+    }
+
+    /// Whether a ClientHello sent from a new socket is answered.
+    async fn answered(server: SocketAddr, client_hello: &[u8]) -> bool {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(client_hello, server).await.unwrap();
+        let received = time::timeout(
+            Duration::from_millis(200),
+            socket.recv_from(&mut [0u8; 1024]),
+        )
+        .await;
+
+        received.is_ok()
+    }
+
+    #[tokio::test]
+    async fn it_keeps_verified_sessions_when_full() {
+        let identity = identity();
+        let server = start(&identity, IDLE_TIMEOUT, 1).await;
+        let dtls_local_addr = server.dtls_local_addr().unwrap();
+        let (session, local_addr) = connect(dtls_local_addr).await;
+
+        // answered once the server finished its side of the handshake
+        assert_eq!(binding(&session).await, Some(local_addr));
+        assert!(!answered(dtls_local_addr, &client_hello().await).await);
+        assert_eq!(binding(&session).await, Some(local_addr));
+    }
+
+    #[tokio::test]
+    async fn it_evicts_unverified_sessions_when_full() {
+        let identity = identity();
+        let server = start(&identity, IDLE_TIMEOUT, 1).await;
+        let dtls_local_addr = server.dtls_local_addr().unwrap();
+        let client_hello = client_hello().await;
+
+        assert!(answered(dtls_local_addr, &client_hello).await);
+
+        let (session, local_addr) = connect(dtls_local_addr).await;
+        assert_eq!(binding(&session).await, Some(local_addr));
+    }
+
+    /// Add a one-byte cookie to a ClientHello without one, fixing up the
+    /// record, handshake and fragment lengths.
+    fn with_cookie(client_hello: &[u8]) -> Bytes {
+        // the record header is 13 bytes and the handshake header 12, followed
+        // by the client version and random (34 bytes) and the session ID
+        let cookie_length = 60 + client_hello[59] as usize;
+        assert_eq!(client_hello[cookie_length], 0);
+
+        let mut forged = BytesMut::from(&client_hello[..cookie_length]);
+        forged.extend_from_slice(&[1, 0x42]);
+        forged.extend_from_slice(&client_hello[cookie_length + 1..]);
+
+        let record_length = u16::from_be_bytes([forged[11], forged[12]]) + 1;
+        forged[11..13].copy_from_slice(&record_length.to_be_bytes());
+
+        for field in [14, 22] {
+            let length =
+                u32::from_be_bytes([0, forged[field], forged[field + 1], forged[field + 2]]);
+            forged[field..field + 3].copy_from_slice(&(length + 1).to_be_bytes()[1..]);
+        }
+
+        forged.freeze()
+    }
+
+    #[tokio::test]
+    async fn it_does_not_verify_a_session_by_a_forged_cookie() {
+        let identity = identity();
+        let server = start(&identity, IDLE_TIMEOUT, 1).await;
+        let dtls_local_addr = server.dtls_local_addr().unwrap();
+
+        // a spoofed ClientHello carrying a cookie the server never issued
+        // still gets a HelloVerifyRequest, and its session can be evicted
+        assert!(answered(dtls_local_addr, &with_cookie(&client_hello().await)).await);
+
+        let (session, local_addr) = connect(dtls_local_addr).await;
+        assert_eq!(binding(&session).await, Some(local_addr));
+    }
+
+    #[tokio::test]
+    async fn it_drops_plaintext_stun() {
+        let identity = identity();
+        let server = start(&identity, IDLE_TIMEOUT, MAX_SESSIONS).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+
+        socket
+            .send_to(&buf, server.dtls_local_addr().unwrap())
+            .await
+            .unwrap();
+        let received = time::timeout(
+            Duration::from_millis(100),
+            socket.recv_from(&mut [0u8; 1024]),
+        )
+        .await;
+
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_drops_an_idle_session() {
+        let identity = identity();
+        let server = start(&identity, Duration::from_millis(100), MAX_SESSIONS).await;
+        let (session, _) = connect(server.dtls_local_addr().unwrap()).await;
+
+        time::sleep(Duration::from_millis(300)).await;
+
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+        let _ = session.write(&buf, None).await;
+        let received = time::timeout(
+            Duration::from_millis(300),
+            session.read(&mut [0u8; 1024], None),
+        )
+        .await;
+
+        // either closed by the server's close_notify, or never answered
+        assert!(!matches!(received, Ok(Ok(_))));
+
+        // and a client can handshake again
+        let (session, local_addr) = connect(server.dtls_local_addr().unwrap()).await;
+        assert_eq!(binding(&session).await, Some(local_addr));
+    }
+
+    #[tokio::test]
+    async fn it_reloads_the_certificate() {
+        let (old, new) = (identity(), identity());
+        let server = start(&old, IDLE_TIMEOUT, MAX_SESSIONS).await;
+        let dtls_local_addr = server.dtls_local_addr().unwrap();
+        let config = Config {
+            server: "127.0.0.1:0".into(),
+            dtls_server: Some(dtls_local_addr.to_string()),
+            dtls_certificate: Some(new.certificate.display().to_string()),
+            dtls_private_key: Some(new.private_key.display().to_string()),
+            ..Config::default()
+        };
+
+        server.reload_handle().reload(&config).unwrap();
+
+        let (session, _) = connect(dtls_local_addr).await;
+        let certificates = session.connection_state().await.peer_certificates;
+
+        assert_eq!(certificates, vec![new.der.clone()]);
+    }
+}
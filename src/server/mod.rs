@@ -0,0 +1,1064 @@
+//! An embeddable tokio STUN server.
+//!
+//! `Server::builder()` binds the listeners (or takes an existing socket) and
+//! spawns a thin tokio driver around the sans-IO `Protocol`.  The running
+//! `Server` reports its bound addresses, hands out a `ShutdownHandle`, and
+//! can be joined to wait for it to stop and surface any error.  On shutdown
+//! the listeners stop receiving, and responses to datagrams that were already
+//! received are sent before the server completes.
+//!
+//! A `ReloadHandle` swaps the advertised addresses (and TLS and DTLS
+//! certificates) of a running server from a new `Config` without rebinding,
+//! so its sockets are kept.
+//!
+//! Datagrams on the UDP sockets that aren't STUN, e.g. DTLS or SRTP of a
+//! media server sharing the port, are handed to the sinks registered with
+//! `ServerBuilder::sink` (see `protocol::demux`), and `Server::send_to`
+//! answers them from the same socket.
+//!
+//! With the `tls` feature, a `tls_server` listener serves STUN over
+//! TLS-over-TCP alongside the UDP sockets, see the `tls` module.  With the
+//! `dtls` feature, a `dtls_server` listener serves STUN over DTLS, see the
+//! `dtls` module.
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    protocol::{
+        demux::{Kind, Sink, Sinks},
+        Protocol, Transmit,
+    },
+    utils::MAX_DATAGRAM_SIZE,
+};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    task::{JoinHandle, JoinSet},
+    time,
+};
+
+#[cfg(feature = "dtls")]
+pub mod dtls;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+/// How long a TLS or DTLS client has to complete its handshake.
+#[cfg(any(feature = "tls", feature = "dtls"))]
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open a PEM file of a TLS or DTLS listener.
+#[cfg(any(feature = "tls", feature = "dtls"))]
+fn pem(path: &str) -> Result<std::io::BufReader<std::fs::File>> {
+    std::fs::File::open(path)
+        .map(std::io::BufReader::new)
+        .map_err(|e| Error::Config(format!("{}: {}", path, e)))
+}
+
+/// Answer a message received on a TLS connection or DTLS session, which only
+/// has the one listener.  The message is handled and the responses drained
+/// under one lock, so only this message's responses are drained.
+#[cfg(any(feature = "tls", feature = "dtls"))]
+fn respond(protocol: &Mutex<Protocol>, message: &[u8], peer: SocketAddr) -> Result<Vec<Transmit>> {
+    let mut protocol = protocol.lock().unwrap();
+    protocol.handle_datagram(message, 0, peer, Instant::now())?;
+
+    Ok(std::iter::from_fn(|| protocol.poll_transmit()).collect())
+}
+
+/// A bound socket and the address it is advertised to clients as.
+#[derive(Debug)]
+pub(crate) struct Listener {
+    pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) advertised: SocketAddr,
+}
+
+/// Run a server from `config` until it fails.
+pub async fn server(config: Config) -> Result<()> {
+    Server::builder().config(config).start().await?.join().await
+}
+
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    config: Config,
+    socket: Option<UdpSocket>,
+    handshake_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_sessions: Option<usize>,
+    tls_idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    sinks: Sinks,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The primary address to bind, overriding the config's `server`.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.config.server = address.to_string();
+        self
+    }
+
+    /// Enable RFC 5780 behavior discovery with a second IP and port.
+    pub fn alternate_address(mut self, address: SocketAddr) -> Self {
+        self.config.alternate_server = Some(address.to_string());
+        self
+    }
+
+    /// Serve STUN over TLS on `address` with a PEM certificate chain and
+    /// private key, overriding the config's `tls_*` values.
+    pub fn tls<P: AsRef<Path>>(
+        mut self,
+        address: SocketAddr,
+        certificate: P,
+        private_key: P,
+    ) -> Self {
+        self.config.tls_server = Some(address.to_string());
+        self.config.tls_certificate = Some(certificate.as_ref().display().to_string());
+        self.config.tls_private_key = Some(private_key.as_ref().display().to_string());
+        self
+    }
+
+    /// How long a TLS or DTLS client has to complete its handshake, 10s by
+    /// default.
+    pub fn tls_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// How long a TLS connection is kept without receiving anything, 60s by
+    /// default.
+    pub fn tls_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.tls_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How many TLS connections are kept open at once, 1024 by default.  New
+    /// connections past the limit are closed as they're accepted.
+    pub fn tls_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Serve STUN over DTLS on `address` with a PEM certificate chain and
+    /// PKCS#8 private key, overriding the config's `dtls_*` values.
+    pub fn dtls<P: AsRef<Path>>(
+        mut self,
+        address: SocketAddr,
+        certificate: P,
+        private_key: P,
+    ) -> Self {
+        self.config.dtls_server = Some(address.to_string());
+        self.config.dtls_certificate = Some(certificate.as_ref().display().to_string());
+        self.config.dtls_private_key = Some(private_key.as_ref().display().to_string());
+        self
+    }
+
+    /// How long a DTLS session is kept without receiving anything, 60s by
+    /// default.
+    pub fn dtls_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How many DTLS sessions are kept at once, 1024 by default.  Unverified
+    /// sessions are evicted to make room for new clients.
+    pub fn dtls_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// Hand the datagrams of `kind` received on the UDP sockets to `sink`,
+    /// instead of dropping them.
+    pub fn sink(mut self, kind: Kind, sink: impl Sink + 'static) -> Self {
+        self.sinks.insert(kind, Box::new(sink));
+        self
+    }
+
+    /// Serve on an already bound socket instead of binding the primary address.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Bind the listeners and start serving in the background.
+    pub async fn start(self) -> Result<Server> {
+        let listeners = bind(&self.config, self.socket).await?;
+        let local_addrs = listeners
+            .iter()
+            .map(|listener| listener.socket.local_addr().map_err(startup))
+            .collect::<Result<Vec<_>>>()?;
+        let sockets = listeners
+            .iter()
+            .map(|listener| listener.socket.clone())
+            .collect();
+        let advertised = listeners.iter().map(|listener| listener.advertised);
+        let mut protocol = Protocol::new(advertised.collect())?;
+        protocol.set_sinks(self.sinks)?;
+        let protocol = Arc::new(Mutex::new(protocol));
+        #[cfg_attr(not(any(feature = "tls", feature = "dtls")), allow(unused_mut))]
+        let mut reload = ReloadHandle {
+            protocols: vec![(local_addrs.clone(), protocol.clone())],
+            #[cfg(feature = "tls")]
+            acceptor: None,
+            #[cfg(feature = "dtls")]
+            dtls_acceptor: None,
+        };
+        #[cfg(any(feature = "tls", feature = "dtls"))]
+        let handshake_timeout = self.handshake_timeout.unwrap_or(HANDSHAKE_TIMEOUT);
+
+        #[cfg(feature = "tls")]
+        let tls = match tls::bind(
+            &self.config,
+            handshake_timeout,
+            self.tls_idle_timeout.unwrap_or(tls::IDLE_TIMEOUT),
+            self.max_connections.unwrap_or(tls::MAX_CONNECTIONS),
+        )
+        .await?
+        {
+            Some(listener) => {
+                let tls_local_addr = listener.listener.local_addr().map_err(startup)?;
+                reload
+                    .protocols
+                    .push((vec![tls_local_addr], listener.protocol.clone()));
+                reload.acceptor = Some(listener.acceptor.clone());
+
+                Some((tls_local_addr, listener))
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if self.config.tls_server.is_some() {
+            return Err(Error::Startup(
+                "tls_server needs the tls feature".to_string(),
+            ));
+        }
+
+        #[cfg(feature = "dtls")]
+        let dtls = match dtls::bind(
+            &self.config,
+            handshake_timeout,
+            self.idle_timeout.unwrap_or(dtls::IDLE_TIMEOUT),
+            self.max_sessions.unwrap_or(dtls::MAX_SESSIONS),
+        )
+        .await?
+        {
+            Some(listener) => {
+                let dtls_local_addr = listener.socket.local_addr().map_err(startup)?;
+                reload
+                    .protocols
+                    .push((vec![dtls_local_addr], listener.protocol.clone()));
+                reload.dtls_acceptor = Some(listener.acceptor.clone());
+
+                Some((dtls_local_addr, listener))
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "dtls"))]
+        if self.config.dtls_server.is_some() {
+            return Err(Error::Startup(
+                "dtls_server needs the dtls feature".to_string(),
+            ));
+        }
+
+        // spawn the drivers once everything is bound, so a failed bind
+        // doesn't leave a half started server behind
+        let (shutdown, receiver) = watch::channel(false);
+        let mut drivers = JoinSet::new();
+        drivers.spawn(run(listeners, protocol, receiver.clone()));
+
+        #[cfg(feature = "tls")]
+        let tls_local_addr = tls.map(|(tls_local_addr, listener)| {
+            drivers.spawn(tls::run(listener, receiver.clone()));
+            tls_local_addr
+        });
+        #[cfg(not(feature = "tls"))]
+        let tls_local_addr = None;
+
+        #[cfg(feature = "dtls")]
+        let dtls_local_addr = dtls.map(|(dtls_local_addr, listener)| {
+            drivers.spawn(dtls::run(listener, receiver.clone()));
+            dtls_local_addr
+        });
+        #[cfg(not(feature = "dtls"))]
+        let dtls_local_addr = None;
+
+        // stop on the first driver that fails, which drops the others
+        let task = tokio::spawn(async move {
+            while let Some(result) = drivers.join_next().await {
+                result.map_err(|e| Error::Receive(e.to_string()))??;
+            }
+
+            Ok(())
+        });
+
+        Ok(Server {
+            local_addrs,
+            sockets,
+            tls_local_addr,
+            dtls_local_addr,
+            shutdown: ShutdownHandle(Arc::new(shutdown)),
+            reload,
+            task,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Server {
+    local_addrs: Vec<SocketAddr>,
+    sockets: Vec<Arc<UdpSocket>>,
+    tls_local_addr: Option<SocketAddr>,
+    dtls_local_addr: Option<SocketAddr>,
+    shutdown: ShutdownHandle,
+    reload: ReloadHandle,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address of the primary listener.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// The addresses of every listener, in RFC 5780 index order.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The address of the TLS listener, if one is configured.
+    pub fn tls_local_addr(&self) -> Option<SocketAddr> {
+        self.tls_local_addr
+    }
+
+    /// The address of the DTLS listener, if one is configured.
+    pub fn dtls_local_addr(&self) -> Option<SocketAddr> {
+        self.dtls_local_addr
+    }
+
+    /// Send `contents` from the UDP socket of `listeners[listener]`, e.g. to
+    /// answer a datagram handed to a sink.
+    pub async fn send_to(
+        &self,
+        listener: usize,
+        contents: &[u8],
+        destination: SocketAddr,
+    ) -> Result<()> {
+        let socket = self.sockets.get(listener).ok_or_else(|| {
+            Error::Arguments(format!(
+                "expected a listener below {}, but got {}",
+                self.sockets.len(),
+                listener
+            ))
+        })?;
+
+        socket
+            .send_to(contents, destination)
+            .await
+            .map_err(|e| Error::Send(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+
+    /// Wait for the server to stop, returning the error that stopped it (if any).
+    pub async fn join(self) -> Result<()> {
+        self.task.await.map_err(|e| Error::Receive(e.to_string()))?
+    }
+}
+
+/// Stops a running `Server`.  Clones control the same server.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// Reconfigures a running `Server`.  Clones control the same server.
+#[derive(Clone, Debug)]
+pub struct ReloadHandle {
+    /// Each protocol, with the local addresses of its listeners.
+    protocols: Vec<(Vec<SocketAddr>, Arc<Mutex<Protocol>>)>,
+    #[cfg(feature = "tls")]
+    acceptor: Option<tls::Acceptor>,
+    #[cfg(feature = "dtls")]
+    dtls_acceptor: Option<dtls::Acceptor>,
+}
+
+impl ReloadHandle {
+    /// Advertise the listeners per the external addresses of `config`, and
+    /// load its TLS and DTLS certificates for new connections.  The listeners
+    /// aren't rebound, so `config` should describe the same `server`,
+    /// `alternate_server`, `tls_server` and `dtls_server` the server was
+    /// started with.
+    /// Either everything is replaced, or nothing is.
+    pub fn reload(&self, config: &Config) -> Result<()> {
+        self.prepare(config)?.apply();
+
+        Ok(())
+    }
+
+    /// Reload several servers, e.g. one per listener of a config file, with
+    /// the config of the same index.  Every config is loaded before any
+    /// server changes, so either all of them are replaced, or none is.
+    pub fn reload_all(handles: &[ReloadHandle], configs: &[Config]) -> Result<()> {
+        if handles.len() != configs.len() {
+            return Err(Error::Arguments(format!(
+                "expected {} configs, but got {}",
+                handles.len(),
+                configs.len()
+            )));
+        }
+
+        let prepared = handles
+            .iter()
+            .zip(configs)
+            .map(|(handle, config)| handle.prepare(config))
+            .collect::<Result<Vec<_>>>()?;
+
+        prepared.into_iter().for_each(Prepared::apply);
+
+        Ok(())
+    }
+
+    /// Load everything `config` would replace, without replacing it yet.
+    fn prepare(&self, config: &Config) -> Result<Prepared> {
+        let advertised = self
+            .protocols
+            .iter()
+            .map(|(local_addrs, _)| config.advertised_addresses(local_addrs))
+            .collect::<Result<Vec<_>>>()?;
+
+        #[cfg(feature = "tls")]
+        let acceptor = match &self.acceptor {
+            Some(_) => Some(tls::acceptor(config)?),
+            None => None,
+        };
+
+        #[cfg(feature = "dtls")]
+        let dtls_acceptor = match &self.dtls_acceptor {
+            Some(_) => Some(dtls::acceptor(config)?),
+            None => None,
+        };
+
+        Ok(Prepared {
+            handle: self.clone(),
+            advertised,
+            #[cfg(feature = "tls")]
+            acceptor,
+            #[cfg(feature = "dtls")]
+            dtls_acceptor,
+        })
+    }
+}
+
+/// A reload that's been loaded, and can no longer fail.
+struct Prepared {
+    handle: ReloadHandle,
+    advertised: Vec<Vec<SocketAddr>>,
+    #[cfg(feature = "tls")]
+    acceptor: Option<tokio_rustls::TlsAcceptor>,
+    #[cfg(feature = "dtls")]
+    dtls_acceptor: Option<webrtc_dtls::config::Config>,
+}
+
+impl Prepared {
+    fn apply(self) {
+        let protocols = self.handle.protocols.iter();
+
+        for ((local_addrs, protocol), advertised) in protocols.zip(self.advertised) {
+            for (local_addr, advertised) in local_addrs.iter().zip(&advertised) {
+                log::info!("Reloaded {}, advertised as {}", local_addr, advertised);
+            }
+
+            // one advertised address per local address, so it fits
+            protocol
+                .lock()
+                .unwrap()
+                .set_advertised(advertised)
+                .expect("an advertised address per listener");
+        }
+
+        #[cfg(feature = "tls")]
+        if let (Some(current), Some(acceptor)) = (&self.handle.acceptor, self.acceptor) {
+            current.replace(acceptor);
+            log::info!("Reloaded the TLS certificate");
+        }
+
+        #[cfg(feature = "dtls")]
+        if let (Some(current), Some(acceptor)) = (&self.handle.dtls_acceptor, self.dtls_acceptor) {
+            current.replace(acceptor);
+            log::info!("Reloaded the DTLS certificate");
+        }
+    }
+}
+
+/// Bind the `server` socket (or use `socket`).  When an `alternate_server` is
+/// configured, bind the four RFC 5780 sockets instead.  Listeners are indexed
+/// by IP (bit 1) and port (bit 0) so a CHANGE-REQUEST only needs to flip bits
+/// of the index.
+pub(crate) async fn bind(config: &Config, socket: Option<UdpSocket>) -> Result<Vec<Listener>> {
+    let primary = match socket {
+        Some(socket) => socket,
+        None => UdpSocket::bind(config.server.parse::<SocketAddr>()?)
+            .await
+            .map_err(startup)?,
+    };
+    let server_addr = primary.local_addr().map_err(startup)?;
+
+    let alternate_addr: SocketAddr = match &config.alternate_server {
+        Some(alternate_server) => alternate_server.parse()?,
+        None => return listeners(config, vec![primary]),
+    };
+
+    if alternate_addr.ip() == server_addr.ip() || alternate_addr.port() == server_addr.port() {
+        return Err(Error::Startup(format!(
+            "alternate_server {} must differ from server {} in both IP and port",
+            alternate_addr, server_addr
+        )));
+    }
+
+    // resolve the alternate port first in case it was configured as 0
+    let alternate_port =
+        bind_socket(SocketAddr::new(server_addr.ip(), alternate_addr.port())).await?;
+    let alternate_port_number = alternate_port.local_addr().map_err(startup)?.port();
+    let sockets = vec![
+        primary,
+        alternate_port,
+        bind_socket(SocketAddr::new(alternate_addr.ip(), server_addr.port())).await?,
+        bind_socket(SocketAddr::new(alternate_addr.ip(), alternate_port_number)).await?,
+    ];
+
+    listeners(config, sockets)
+}
+
+async fn bind_socket(address: SocketAddr) -> Result<UdpSocket> {
+    UdpSocket::bind(address).await.map_err(startup)
+}
+
+/// The listeners of the bound `sockets`, in index order, each advertised per
+/// its position rather than its IP.
+fn listeners(config: &Config, sockets: Vec<UdpSocket>) -> Result<Vec<Listener>> {
+    let local_addrs = sockets
+        .iter()
+        .map(|socket| socket.local_addr().map_err(startup))
+        .collect::<Result<Vec<_>>>()?;
+    let advertised = config.advertised_addresses(&local_addrs)?;
+
+    for (local_addr, advertised) in local_addrs.iter().zip(&advertised) {
+        log::info!(
+            "Started stun server on {}, advertised as {}",
+            local_addr,
+            advertised
+        );
+    }
+
+    Ok(sockets
+        .into_iter()
+        .zip(advertised)
+        .map(|(socket, advertised)| Listener {
+            socket: Arc::new(socket),
+            advertised,
+        })
+        .collect())
+}
+
+fn startup(error: std::io::Error) -> Error {
+    Error::Startup(error.to_string())
+}
+
+/// Receive on every listener until one of them fails or the server is shut
+/// down.  This is a thin tokio driver around the sans-IO `Protocol`.
+pub(crate) async fn run(
+    listeners: Vec<Listener>,
+    protocol: Arc<Mutex<Protocol>>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let listeners = Arc::new(listeners);
+    let mut tasks = JoinSet::new();
+
+    for index in 0..listeners.len() {
+        let (listeners, protocol) = (listeners.clone(), protocol.clone());
+        let shutdown = shutdown.clone();
+        tasks.spawn(async move { serve(listeners, protocol, index, shutdown).await });
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        result.map_err(|e| Error::Receive(e.to_string()))??;
+    }
+
+    log::info!("Stopped stun server");
+
+    Ok(())
+}
+
+async fn serve(
+    listeners: Arc<Vec<Listener>>,
+    protocol: Arc<Mutex<Protocol>>,
+    index: usize,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let socket = &listeners[index].socket;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    while !*shutdown.borrow() {
+        let timeout = protocol.lock().unwrap().poll_timeout();
+        let sleep = async {
+            match timeout {
+                Some(timeout) => time::sleep_until(timeout.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let transmits: Vec<Transmit> = tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (bytes_received, client_address) =
+                    received.map_err(|e| Error::Receive(e.to_string()))?;
+                let mut protocol = protocol.lock().unwrap();
+
+                // a datagram that isn't valid mustn't stop the listener
+                if let Err(e) = protocol.handle_datagram(
+                    &buf[..bytes_received],
+                    index,
+                    client_address,
+                    Instant::now(),
+                ) {
+                    log::debug!("dropping a datagram from {}: {}", client_address, e);
+                }
+
+                std::iter::from_fn(|| protocol.poll_transmit()).collect()
+            }
+            _ = sleep => {
+                let mut protocol = protocol.lock().unwrap();
+                protocol.handle_timeout(Instant::now());
+
+                std::iter::from_fn(|| protocol.poll_transmit()).collect()
+            }
+            // a dropped handle can't shut the server down anymore, so only
+            // stop on an explicit shutdown
+            Ok(()) = shutdown.changed() => Vec::new(),
+        };
+
+        send(&listeners, transmits).await;
+    }
+
+    // drain anything the protocol still has queued
+    let transmits: Vec<Transmit> = {
+        let mut protocol = protocol.lock().unwrap();
+        std::iter::from_fn(|| protocol.poll_transmit()).collect()
+    };
+
+    send(&listeners, transmits).await;
+
+    Ok(())
+}
+
+/// Send the encoded responses to the clients.  A response that can't be sent
+/// (e.g. after an ICMP unreachable) is dropped, without stopping the
+/// listener.
+async fn send(listeners: &[Listener], transmits: Vec<Transmit>) {
+    for transmit in transmits {
+        if let Err(e) = listeners[transmit.listener]
+            .socket
+            .send_to(transmit.contents.as_ref(), transmit.destination)
+            .await
+        {
+            log::warn!("dropping a response to {}: {}", transmit.destination, e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::message::{
+        attribute::{Attribute, CHANGE_REQUEST},
+        class::Class,
+        Message, MESSAGE_HEADER_LENGTH,
+    };
+    use crate::utils::Address;
+    use bytes::{Bytes, BytesMut};
+    use tokio::time::timeout;
+
+    pub(crate) fn config(alternate_server: Option<&str>) -> Config {
+        Config {
+            client: "127.0.0.1:0".into(),
+            server: "127.0.0.1:0".into(),
+            alternate_server: alternate_server.map(Into::into),
+            ..Config::default()
+        }
+    }
+
+    pub(crate) async fn start(config: Config) -> Vec<SocketAddr> {
+        let server = Server::builder().config(config).start().await.unwrap();
+
+        server.local_addrs().to_vec()
+    }
+
+    /// A self-signed certificate for localhost, written to temp files.
+    #[cfg(any(feature = "tls", feature = "dtls"))]
+    pub(crate) struct Identity {
+        pub(crate) certificate: std::path::PathBuf,
+        pub(crate) private_key: std::path::PathBuf,
+        /// The certificate in DER, for clients to trust or compare with.
+        pub(crate) der: Vec<u8>,
+    }
+
+    #[cfg(any(feature = "tls", feature = "dtls"))]
+    impl Drop for Identity {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.certificate);
+            let _ = std::fs::remove_file(&self.private_key);
+        }
+    }
+
+    #[cfg(any(feature = "tls", feature = "dtls"))]
+    pub(crate) fn identity() -> Identity {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // tests run in parallel, so every identity gets its own files
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let index = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = |kind| {
+            std::env::temp_dir().join(format!(
+                "stun-server-{}-{}-{}.pem",
+                std::process::id(),
+                index,
+                kind
+            ))
+        };
+        let identity = Identity {
+            certificate: path("cert"),
+            private_key: path("key"),
+            der: cert.der().to_vec(),
+        };
+
+        std::fs::write(&identity.certificate, cert.pem()).unwrap();
+        std::fs::write(&identity.private_key, key_pair.serialize_pem()).unwrap();
+
+        identity
+    }
+
+    async fn request(server: SocketAddr, attributes: Vec<Attribute<'_>>) -> (SocketAddr, Bytes) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        send(&socket, server, attributes).await;
+        receive(&socket).await
+    }
+
+    async fn send(socket: &UdpSocket, server: SocketAddr, attributes: Vec<Attribute<'_>>) {
+        let mut buf = BytesMut::new();
+        Message::binding_request(attributes)
+            .encode(&mut buf)
+            .unwrap();
+        socket.send_to(buf.as_ref(), server).await.unwrap();
+    }
+
+    async fn receive(socket: &UdpSocket) -> (SocketAddr, Bytes) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let (bytes_received, from) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        (from, Bytes::copy_from_slice(&buf[..bytes_received]))
+    }
+
+    #[tokio::test]
+    async fn it_responds_with_the_xor_mapped_address() {
+        let addresses = start(config(None)).await;
+        let (from, mut bytes) = request(addresses[0], vec![]).await;
+        let message = Message::decode(&mut bytes).unwrap();
+
+        assert_eq!(from, addresses[0]);
+        assert_eq!(message.class, Class::SuccessResponse);
+        assert!(matches!(
+            message.attributes[..],
+            [Attribute::XorMappedAddress(_)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_change_request_without_an_alternate_server() {
+        let addresses = start(config(None)).await;
+        let change_request = Attribute::ChangeRequest {
+            change_ip: true,
+            change_port: true,
+        };
+        let (_, mut bytes) = request(addresses[0], vec![change_request]).await;
+        let message = Message::decode(&mut bytes).unwrap();
+
+        assert_eq!(message.class, Class::FailureResponse);
+        assert_eq!(
+            message.attributes[1],
+            Attribute::UnknownAttributes(vec![CHANGE_REQUEST])
+        );
+    }
+
+    #[tokio::test]
+    async fn it_honours_a_change_request() {
+        let addresses = start(config(Some("127.0.0.2:0"))).await;
+
+        for (change_ip, change_port, expected) in [
+            (false, false, 0),
+            (false, true, 1),
+            (true, false, 2),
+            (true, true, 3),
+        ] {
+            let change_request = Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            };
+            let (from, mut bytes) = request(addresses[0], vec![change_request]).await;
+            let message = Message::decode(&mut bytes).unwrap();
+
+            assert_eq!(from, addresses[expected]);
+            assert_eq!(
+                message.attributes[1],
+                Attribute::ResponseOrigin(Address::parse_address(addresses[expected]))
+            );
+            assert_eq!(
+                message.attributes[2],
+                Attribute::OtherAddress(Address::parse_address(addresses[3]))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_advertises_the_external_addresses_in_responses() {
+        // the primary socket is handed over, so its IP isn't `server`'s
+        let server = Server::builder()
+            .config(Config {
+                server: "0.0.0.0:0".into(),
+                external_address: Some("203.0.113.7".into()),
+                alternate_external_address: Some("203.0.113.8".into()),
+                ..config(Some("127.0.0.2:0"))
+            })
+            .socket(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+            .start()
+            .await
+            .unwrap();
+        let addresses = server.local_addrs().to_vec();
+        let change_port = Attribute::ChangeRequest {
+            change_ip: false,
+            change_port: true,
+        };
+
+        let (from, mut bytes) = request(addresses[2], vec![change_port]).await;
+        let message = Message::decode(&mut bytes).unwrap();
+
+        assert_eq!(from, addresses[3]);
+        assert_eq!(
+            message.attributes[1],
+            Attribute::ResponseOrigin(Address::parse_address(SocketAddr::new(
+                "203.0.113.8".parse().unwrap(),
+                addresses[3].port()
+            )))
+        );
+        assert_eq!(
+            message.other_address(),
+            Some(SocketAddr::new(
+                "203.0.113.7".parse().unwrap(),
+                addresses[1].port()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reloads_the_advertised_addresses() {
+        let server = Server::builder()
+            .config(config(Some("127.0.0.2:0")))
+            .start()
+            .await
+            .unwrap();
+        let addresses = server.local_addrs().to_vec();
+        let reloaded = Config {
+            alternate_external_address: Some("203.0.113.8".into()),
+            ..config(Some("127.0.0.2:0"))
+        };
+
+        server.reload_handle().reload(&reloaded).unwrap();
+
+        let (from, mut bytes) = request(addresses[0], vec![]).await;
+        let message = Message::decode(&mut bytes).unwrap();
+
+        assert_eq!(from, addresses[0]);
+        assert_eq!(
+            message.other_address(),
+            Some(SocketAddr::new(
+                "203.0.113.8".parse().unwrap(),
+                addresses[3].port()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_demultiplexes_a_shared_port() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let server = Server::builder()
+            .config(config(None))
+            .sink(Kind::Rtp, move |datagram| sender.send(datagram).unwrap())
+            .start()
+            .await
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // invalid STUN, an RTP packet, and a DTLS record nobody sinks
+        for datagram in [&[0x00, 0x01][..], &[0x80, 0x60, 0x00, 0x01], &[22, 0xFE]] {
+            socket.send_to(datagram, server.local_addr()).await.unwrap();
+        }
+
+        let datagram = timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(datagram.contents, &[0x80, 0x60, 0x00, 0x01][..]);
+        assert_eq!(datagram.source, socket.local_addr().unwrap());
+
+        // the listener kept going, and can answer the sink's datagram
+        send(&socket, server.local_addr(), vec![]).await;
+        let (from, mut bytes) = receive(&socket).await;
+        assert_eq!(from, server.local_addr());
+        assert_eq!(
+            Message::decode(&mut bytes).unwrap().class,
+            Class::SuccessResponse
+        );
+
+        server
+            .send_to(datagram.listener, &[0x80, 0x61], datagram.source)
+            .await
+            .unwrap();
+        let (from, bytes) = receive(&socket).await;
+        assert_eq!((from, &bytes[..]), (server.local_addr(), &[0x80, 0x61][..]));
+    }
+
+    #[tokio::test]
+    async fn it_requires_a_different_alternate_ip() {
+        let config = config(Some("127.0.0.1:0"));
+
+        assert!(bind(&config, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_honours_a_response_port() {
+        let addresses = start(config(None)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response_port = response_socket.local_addr().unwrap().port();
+
+        send(
+            &socket,
+            addresses[0],
+            vec![Attribute::ResponsePort(response_port)],
+        )
+        .await;
+        let (_, mut bytes) = receive(&response_socket).await;
+        let message = Message::decode(&mut bytes).unwrap();
+
+        assert_eq!(message.class, Class::SuccessResponse);
+        assert_eq!(
+            message.attributes[0],
+            Attribute::XorMappedAddress(Address::parse_address(socket.local_addr().unwrap()))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_privileged_response_port() {
+        let addresses = start(config(None)).await;
+        let (_, mut bytes) = request(addresses[0], vec![Attribute::ResponsePort(53)]).await;
+        let message = Message::decode(&mut bytes).unwrap();
+
+        assert_eq!(message.class, Class::FailureResponse);
+        assert_eq!(
+            message.attributes[0],
+            Attribute::ErrorCode {
+                code: 400,
+                reason: "Bad Request"
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn it_drops_a_response_that_cannot_be_sent() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let listeners = [Listener {
+            advertised: socket.local_addr().unwrap(),
+            socket,
+        }];
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transmit = |destination| Transmit {
+            listener: 0,
+            destination,
+            contents: Bytes::from_static(b"response"),
+        };
+
+        // an IPv6 destination can't be reached from an IPv4 socket
+        super::send(
+            &listeners,
+            vec![
+                transmit("[2001:db8::1]:3478".parse().unwrap()),
+                transmit(client.local_addr().unwrap()),
+            ],
+        )
+        .await;
+
+        let (_, bytes) = receive(&client).await;
+        assert_eq!(bytes, &b"response"[..]);
+    }
+
+    #[tokio::test]
+    async fn it_pads_no_more_than_the_request_or_a_path_mtu() {
+        let addresses = start(config(None)).await;
+
+        // the 12 bytes of XOR-MAPPED-ADDRESS come out of the padding, and a
+        // response stays within 1500 bytes less the IPv6 and UDP headers
+        for (requested, padded) in [(100, 88), (2000, 1384)] {
+            let (_, mut bytes) = request(addresses[0], vec![Attribute::Padding(requested)]).await;
+            assert!(bytes.len() <= MESSAGE_HEADER_LENGTH + 4 + requested as usize);
+
+            let message = Message::decode(&mut bytes).unwrap();
+            assert_eq!(message.attributes[1], Attribute::Padding(padded));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_running_after_the_largest_padding_request() {
+        let addresses = start(config(None)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // the largest UDP payload is 65507 bytes, and PADDING is padded to 4
+        let padding = Attribute::Padding((65_507 - MESSAGE_HEADER_LENGTH as u16 - 4) & !3);
+        send(&socket, addresses[0], vec![padding]).await;
+        let (_, bytes) = receive(&socket).await;
+        assert!(bytes.len() <= 1452);
+
+        let (_, mut bytes) = request(addresses[0], vec![]).await;
+        let message = Message::decode(&mut bytes).unwrap();
+        assert_eq!(message.class, Class::SuccessResponse);
+    }
+}
@@ -0,0 +1,474 @@
+//! STUN over TLS-over-TCP, for `stuns:` URIs and networks that only let
+//! 443/TLS through.
+//!
+//! The TLS connection is opened to the `tls_server` address and STUN
+//! messages are framed on it exactly as on TCP, see `protocol::stream`.
+//! Clients identify the usage with ALPN (RFC 7443): "stun.turn" or
+//! "stun.nat-discovery".  A client that offers neither is rejected, one that
+//! offers none is accepted.  Handshakes that don't complete within the
+//! handshake timeout are dropped, as are connections that then receive
+//! nothing for the idle timeout, and past the connection limit new
+//! connections are closed as they're accepted, so idle or numerous
+//! connections can't pin the server.
+//!
+//! Each connection is answered by a single-listener `Protocol` shared by the
+//! TLS listener, so CHANGE-REQUEST is rejected as it is over TCP, and
+//! responses are always written back on the connection they arrived on.
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    protocol::{stream::Framer, Protocol},
+};
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+    time,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, ServerConfig},
+    TlsAcceptor,
+};
+
+/// The ALPN protocol IDs of STUN usages (RFC 7443).
+pub const ALPN: [&[u8]; 2] = [b"stun.turn", b"stun.nat-discovery"];
+
+/// How long a connection is kept without receiving anything.
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many connections a listener keeps open.
+pub(crate) const MAX_CONNECTIONS: usize = 1024;
+
+/// A bound TLS listener, with the protocol answering its connections.
+#[derive(Debug)]
+pub(crate) struct TlsListener {
+    pub(crate) listener: TcpListener,
+    pub(crate) acceptor: Acceptor,
+    pub(crate) protocol: Arc<Mutex<Protocol>>,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max_connections: usize,
+}
+
+/// The certificate of a running listener, which can be swapped on reload.
+/// New connections use the latest one.
+#[derive(Clone)]
+pub(crate) struct Acceptor(Arc<RwLock<TlsAcceptor>>);
+
+impl fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Acceptor").finish_non_exhaustive()
+    }
+}
+
+impl Acceptor {
+    fn current(&self) -> TlsAcceptor {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn replace(&self, acceptor: TlsAcceptor) {
+        *self.0.write().unwrap() = acceptor;
+    }
+}
+
+/// Bind the `tls_server` listener, if one is configured.
+pub(crate) async fn bind(
+    config: &Config,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    max_connections: usize,
+) -> Result<Option<TlsListener>> {
+    let address: SocketAddr = match &config.tls_server {
+        Some(tls_server) => tls_server.parse()?,
+        None => return Ok(None),
+    };
+    let acceptor = acceptor(config)?;
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| Error::Startup(e.to_string()))?;
+    let local_addr = listener
+        .local_addr()
+        .map_err(|e| Error::Startup(e.to_string()))?;
+    let advertised = config.advertised_addresses(&[local_addr])?[0];
+
+    log::info!(
+        "Started stun server on tls://{}, advertised as {}",
+        local_addr,
+        advertised
+    );
+
+    Ok(Some(TlsListener {
+        listener,
+        acceptor: Acceptor(Arc::new(RwLock::new(acceptor))),
+        protocol: Arc::new(Mutex::new(Protocol::new(vec![advertised])?)),
+        handshake_timeout,
+        idle_timeout,
+        max_connections,
+    }))
+}
+
+/// Load the PEM certificate chain and private key of `config`.
+pub(crate) fn acceptor(config: &Config) -> Result<TlsAcceptor> {
+    let (certificate, private_key) = match (&config.tls_certificate, &config.tls_private_key) {
+        (Some(certificate), Some(private_key)) => (certificate, private_key),
+        _ => {
+            return Err(Error::Config(
+                "tls_server needs both tls_certificate and tls_private_key".into(),
+            ))
+        }
+    };
+
+    let certificates = rustls_pemfile::certs(&mut super::pem(certificate)?)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| Error::Config(format!("{}: {}", certificate, e)))?;
+
+    if certificates.is_empty() {
+        return Err(Error::Config(format!(
+            "{}: no PEM certificates found",
+            certificate
+        )));
+    }
+
+    let key = rustls_pemfile::private_key(&mut super::pem(private_key)?)
+        .map_err(|e| Error::Config(format!("{}: {}", private_key, e)))?
+        .ok_or_else(|| Error::Config(format!("{}: no PEM private key found", private_key)))?;
+
+    let mut tls = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Config(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| Error::Config(format!("{}: {}", certificate, e)))?;
+    tls.alpn_protocols = ALPN.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+/// Accept connections until the server is shut down, which also closes the
+/// open connections.
+pub(crate) async fn run(listener: TlsListener, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let mut connections = JoinSet::new();
+
+    while !*shutdown.borrow() {
+        tokio::select! {
+            accepted = listener.listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    // e.g. out of file descriptors, which shouldn't stop the server
+                    Err(e) => {
+                        log::warn!("error accepting a TLS connection: {}", e);
+                        continue;
+                    }
+                };
+
+                // dropping the stream closes it
+                if connections.len() >= listener.max_connections {
+                    log::debug!("closing the TLS connection from {}: too many connections", peer);
+                    continue;
+                }
+
+                connections.spawn(connection(
+                    stream,
+                    peer,
+                    listener.acceptor.current(),
+                    listener.protocol.clone(),
+                    listener.handshake_timeout,
+                    listener.idle_timeout,
+                ));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            Ok(()) = shutdown.changed() => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: TlsAcceptor,
+    protocol: Arc<Mutex<Protocol>>,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+) {
+    match serve(
+        stream,
+        peer,
+        acceptor,
+        protocol,
+        handshake_timeout,
+        idle_timeout,
+    )
+    .await
+    {
+        Ok(()) => log::debug!("TLS connection from {} closed", peer),
+        Err(e) => log::debug!("closing the TLS connection from {}: {}", peer, e),
+    }
+}
+
+/// Answer each STUN message framed on the connection, until it's closed or
+/// receives nothing for `idle_timeout`.
+async fn serve(
+    stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: TlsAcceptor,
+    protocol: Arc<Mutex<Protocol>>,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let mut stream = time::timeout(handshake_timeout, acceptor.accept(stream))
+        .await
+        .map_err(|_| Error::Receive(format!("no TLS handshake within {:?}", handshake_timeout)))?
+        .map_err(|e| Error::Receive(e.to_string()))?;
+
+    log::debug!(
+        "TLS connection from {} with ALPN {:?}",
+        peer,
+        stream
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .map(String::from_utf8_lossy)
+    );
+
+    let mut framer = Framer::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let bytes_received = time::timeout(idle_timeout, stream.read(&mut buf))
+            .await
+            .map_err(|_| Error::Receive(format!("nothing received for {:?}", idle_timeout)))?
+            .map_err(|e| Error::Receive(e.to_string()))?;
+
+        if bytes_received == 0 {
+            return Ok(());
+        }
+
+        framer.extend(&buf[..bytes_received]);
+
+        while let Some(message) = framer.next_frame()? {
+            for transmit in super::respond(&protocol, &message, peer)? {
+                stream
+                    .write_all(&transmit.contents)
+                    .await
+                    .map_err(|e| Error::BindingResponse(e.to_string()))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{class::Class, Message},
+        server::{
+            tests::{identity, Identity},
+            ReloadHandle, Server, HANDSHAKE_TIMEOUT,
+        },
+    };
+    use bytes::BytesMut;
+    use tokio_rustls::{
+        client::TlsStream,
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    async fn start(identity: &Identity, handshake_timeout: Duration) -> Server {
+        start_with(identity, handshake_timeout, IDLE_TIMEOUT, MAX_CONNECTIONS).await
+    }
+
+    async fn start_with(
+        identity: &Identity,
+        handshake_timeout: Duration,
+        idle_timeout: Duration,
+        max_connections: usize,
+    ) -> Server {
+        Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .tls(
+                "127.0.0.1:0".parse().unwrap(),
+                &identity.certificate,
+                &identity.private_key,
+            )
+            .tls_handshake_timeout(handshake_timeout)
+            .tls_idle_timeout(idle_timeout)
+            .tls_max_connections(max_connections)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    async fn connect(
+        server: SocketAddr,
+        identity: &Identity,
+        alpn: &[u8],
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(identity.der.clone().into()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+
+        let stream = TcpStream::connect(server).await?;
+        let domain = ServerName::try_from("localhost").unwrap();
+
+        TlsConnector::from(Arc::new(config))
+            .connect(domain, stream)
+            .await
+    }
+
+    #[tokio::test]
+    async fn it_answers_binding_requests_over_tls() {
+        let identity = identity();
+        let server = start(&identity, HANDSHAKE_TIMEOUT).await;
+        let mut stream = connect(server.tls_local_addr().unwrap(), &identity, ALPN[1])
+            .await
+            .unwrap();
+        let local_addr = stream.get_ref().0.local_addr().unwrap();
+
+        // two requests in one write, to be framed apart
+        let mut buf = BytesMut::new();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+        Message::binding_request(vec![]).encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+
+        let mut framer = Framer::new();
+        let mut responses = vec![];
+        let mut read = [0u8; 1024];
+
+        while responses.len() < 2 {
+            let bytes_received = stream.read(&mut read).await.unwrap();
+            framer.extend(&read[..bytes_received]);
+
+            while let Some(response) = framer.next_frame().unwrap() {
+                responses.push(response);
+            }
+        }
+
+        for mut response in responses {
+            let message = Message::decode(&mut response).unwrap();
+
+            assert_eq!(message.class, Class::SuccessResponse);
+            assert_eq!(message.mapped_address(), Some(local_addr));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_rejects_an_unknown_alpn() {
+        let identity = identity();
+        let server = start(&identity, HANDSHAKE_TIMEOUT).await;
+
+        assert!(connect(server.tls_local_addr().unwrap(), &identity, b"h2")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_drops_a_stalled_handshake() {
+        let identity = identity();
+        let server = start(&identity, Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(server.tls_local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let closed = time::timeout(Duration::from_secs(1), stream.read(&mut [0u8; 1])).await;
+
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn it_drops_an_idle_connection() {
+        let identity = identity();
+        let server = start_with(
+            &identity,
+            HANDSHAKE_TIMEOUT,
+            Duration::from_millis(50),
+            MAX_CONNECTIONS,
+        )
+        .await;
+        let mut stream = connect(server.tls_local_addr().unwrap(), &identity, ALPN[0])
+            .await
+            .unwrap();
+
+        let closed = time::timeout(Duration::from_secs(1), stream.read(&mut [0u8; 1])).await;
+
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn it_closes_connections_past_the_limit() {
+        let identity = identity();
+        let server = start_with(&identity, HANDSHAKE_TIMEOUT, IDLE_TIMEOUT, 1).await;
+        let tls_local_addr = server.tls_local_addr().unwrap();
+        let _first = connect(tls_local_addr, &identity, ALPN[0]).await.unwrap();
+
+        assert!(connect(tls_local_addr, &identity, ALPN[0]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_reloads_the_certificate() {
+        let (old, new) = (identity(), identity());
+        let server = start(&old, HANDSHAKE_TIMEOUT).await;
+        let tls_local_addr = server.tls_local_addr().unwrap();
+        let config = Config {
+            server: "127.0.0.1:0".into(),
+            tls_server: Some(tls_local_addr.to_string()),
+            tls_certificate: Some(new.certificate.display().to_string()),
+            tls_private_key: Some(new.private_key.display().to_string()),
+            ..Config::default()
+        };
+
+        server.reload_handle().reload(&config).unwrap();
+
+        assert!(connect(tls_local_addr, &old, ALPN[0]).await.is_err());
+        assert!(connect(tls_local_addr, &new, ALPN[0]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_reloads_no_server_if_one_config_is_invalid() {
+        let (old, new) = (identity(), identity());
+        let servers = [
+            start(&old, HANDSHAKE_TIMEOUT).await,
+            start(&old, HANDSHAKE_TIMEOUT).await,
+        ];
+        let config = |server: &Server, identity: &Identity| Config {
+            server: "127.0.0.1:0".into(),
+            tls_server: Some(server.tls_local_addr().unwrap().to_string()),
+            tls_certificate: Some(identity.certificate.display().to_string()),
+            tls_private_key: Some(identity.private_key.display().to_string()),
+            ..Config::default()
+        };
+
+        // the second listener's certificate is a file, but not PEM
+        let invalid = identity();
+        std::fs::write(&invalid.certificate, "not a certificate").unwrap();
+
+        let handles = servers
+            .iter()
+            .map(Server::reload_handle)
+            .collect::<Vec<_>>();
+        let configs = [config(&servers[0], &new), config(&servers[1], &invalid)];
+
+        assert!(ReloadHandle::reload_all(&handles, &configs).is_err());
+
+        for server in &servers {
+            let tls_local_addr = server.tls_local_addr().unwrap();
+
+            assert!(connect(tls_local_addr, &old, ALPN[0]).await.is_ok());
+            assert!(connect(tls_local_addr, &new, ALPN[0]).await.is_err());
+        }
+    }
+}
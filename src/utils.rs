@@ -1,7 +1,13 @@
 use crate::error::{Error, Result};
-use std::{convert::TryFrom, net::SocketAddr};
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
-#[derive(Debug, Eq, PartialEq)]
+/// Large enough for any UDP datagram, so PADDING can probe fragmentation.
+pub const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Address {
     pub address: Vec<u8>,
     pub port: u16,
@@ -31,6 +37,23 @@ impl Address {
             SocketAddr::V6(address) => Address::ipv6(address.ip().octets(), address.port()),
         }
     }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        let ip = match self.ip_kind {
+            IPKind::IPv4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&self.address[..4]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            IPKind::IPv6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.address[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+
+        SocketAddr::new(ip, self.port)
+    }
 }
 
 impl TryFrom<&str> for Address {
@@ -42,7 +65,7 @@ impl TryFrom<&str> for Address {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum IPKind {
     IPv4,
     IPv6,
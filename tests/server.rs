@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+
+use stun_server::{client::StunClient, server::Server};
+use tokio::net::UdpSocket;
+
+async fn start() -> Server {
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    Server::builder().address(address).start().await.unwrap()
+}
+
+async fn binding(server: SocketAddr) -> SocketAddr {
+    let client = StunClient::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let mapped_address = client.binding(server).await.unwrap();
+
+    assert_eq!(mapped_address, client.local_addr().unwrap());
+
+    mapped_address
+}
+
+#[tokio::test]
+async fn it_binds_an_ephemeral_port() {
+    let server = start().await;
+
+    assert_ne!(server.local_addr().port(), 0);
+    binding(server.local_addr()).await;
+}
+
+#[tokio::test]
+async fn it_runs_servers_in_parallel() {
+    let (first, second) = tokio::join!(start(), start());
+
+    assert_ne!(first.local_addr(), second.local_addr());
+    tokio::join!(binding(first.local_addr()), binding(second.local_addr()));
+}
+
+#[tokio::test]
+async fn it_serves_an_existing_socket() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let server = Server::builder().socket(socket).start().await.unwrap();
+
+    assert_eq!(server.local_addr(), address);
+    binding(address).await;
+}
+
+#[tokio::test]
+async fn it_shuts_down_gracefully() {
+    let server = start().await;
+    binding(server.local_addr()).await;
+
+    server.shutdown_handle().shutdown();
+
+    assert!(server.join().await.is_ok());
+}
+
+#[tokio::test]
+async fn it_binds_the_behavior_discovery_listeners() {
+    let server = Server::builder()
+        .address("127.0.0.1:0".parse().unwrap())
+        .alternate_address("127.0.0.2:0".parse().unwrap())
+        .start()
+        .await
+        .unwrap();
+
+    assert_eq!(server.local_addrs().len(), 4);
+    binding(server.local_addrs()[0]).await;
+}